@@ -1,67 +1,422 @@
-//! contains custom message enum and implementation for it
+//! contains message envelope with metadata, typed message body and message stored in db
 
 use core::fmt;
 
 use anyhow::{bail, Context, Result};
-use chrono::prelude::*;
-use nanodb::nanodb::NanoDB;
+use chrono::Utc;
 use serde_derive::{Deserialize, Serialize};
-use std::path::Path;
-use tokio::fs::OpenOptions;
+use std::path::PathBuf;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::debug;
 
-/// custom message enum to hold message data
+use crate::frame::{FrameError, FrameKind, FrameLimits};
+use crate::history::{local_time, new_message_id, utc_timestamp, ChatRecord, HistoryEntry};
+use crate::logging::REDACTED;
+use crate::search::page_count;
+use crate::storage::Storage;
+use crate::transfer::{FileDownloads, FileKind, FileUpload};
+use crate::{deserialize_msg, save_msg_to_db, serialize_msg};
+
+/// password sent in login message, Debug and Display never show the value, so it cannot get to the log by accident
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Password(String);
+
+impl Password {
+    /// get the password itself, use only for validation of the password
+    pub fn expose(&self) -> &str {
+        return &self.0;
+    }
+}
+
+impl From<String> for Password {
+    fn from(password: String) -> Self {
+        return Password(password);
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Password({})", REDACTED)
+    }
+}
+
+impl fmt::Display for Password {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", REDACTED)
+    }
+}
+
+/// machine readable reason of the error reply sent by server
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// password doesn't match the one stored for the user
+    BadPassword,
+    /// user doesn't exist and has to be registered first
+    UnknownUser,
+    /// user with the same name is already connected
+    AlreadyLoggedIn,
+    /// user with the same name is already registered
+    UserExists,
+    /// server doesn't accept new registrations
+    RegistrationClosed,
+    /// invite code is missing or not valid
+    InvalidInvite,
+    /// too many failed attempts, client is disconnected
+    RateLimited,
+    /// file is bigger than the server accepts
+    FileTooLarge,
+    /// room doesn't exist
+    UnknownRoom,
+    /// user is not member of the room
+    NotRoomMember,
+    /// room name is not valid
+    InvalidRoomName,
+    /// recipient of the private message is not online
+    UserOffline,
+    /// file transfer with the id doesn't exist or is not finished
+    UnknownTransfer,
+    /// received file doesn't match the hash sent by the sender
+    ChecksumMismatch,
+    /// request is not allowed or not expected in current state
+    InvalidRequest,
+    /// request can be sent only by administrators of the server
+    NotAdmin,
+    /// server failed to process the request
+    Internal,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            ErrorCode::BadPassword => "Incorrect password",
+            ErrorCode::UnknownUser => "Unknown user",
+            ErrorCode::AlreadyLoggedIn => "User is already logged in",
+            ErrorCode::UserExists => "User already exists",
+            ErrorCode::RegistrationClosed => "Registration of new users is closed",
+            ErrorCode::InvalidInvite => "Invite code is missing or not valid",
+            ErrorCode::RateLimited => "Too many attempts",
+            ErrorCode::FileTooLarge => "File is too large",
+            ErrorCode::UnknownRoom => "Room doesn't exist",
+            ErrorCode::NotRoomMember => "Not member of the room",
+            ErrorCode::InvalidRoomName => "Invalid room name",
+            ErrorCode::UserOffline => "User is not online",
+            ErrorCode::UnknownTransfer => "Unknown file transfer",
+            ErrorCode::ChecksumMismatch => "File checksum doesn't match",
+            ErrorCode::InvalidRequest => "Invalid request",
+            ErrorCode::NotAdmin => "Only administrators can do this",
+            ErrorCode::Internal => "Internal server error",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// typed body of the message, sender, destination, id and time of the message are in its envelope
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum AsyncChatMsg {
-    /// simplest text message variant, contains username from who the message is and text of the message
-    Text(String, String), // from, message
-    /// message containing any file data, contains username from who the message is, name of the file and data of the file
-    File(String, String, Vec<u8>), // from, filename, file data
-    /// message containing image, contains username from who the message is, filename of the image and data of the image
-    Image(String, String, Vec<u8>), // from, filename, file data
+    /// text message, it is sent to the room or as private message to one user depending on destination of the envelope
+    Text {
+        /// text of the message
+        text: String,
+    },
+    /// start of the file or image transfer to the room of the envelope
+    FileStart {
+        /// id of the transfer, chunks and end of the transfer are sent with the same id
+        transfer: String,
+        /// name of the file without path
+        filename: String,
+        /// size of the file in bytes
+        size: u64,
+        /// kind of the file
+        kind: FileKind,
+        /// SHA-256 hash of the file as hex string
+        sha256: String,
+    },
+    /// part of the file data, chunks of one transfer are sent in order one after another
+    FileChunk {
+        /// id of the transfer
+        transfer: String,
+        /// offset of the data in the file
+        offset: u64,
+        /// data of the chunk
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    /// end of the file transfer, all chunks were sent
+    FileEnd {
+        /// id of the transfer
+        transfer: String,
+    },
+    /// request from client to continue interrupted upload, server replies with TransferOffset
+    ResumeUpload {
+        /// id of the transfer
+        transfer: String,
+    },
+    /// request from client to send the rest of stored file starting from the offset
+    ResumeDownload {
+        /// id of the transfer
+        transfer: String,
+        /// count of bytes the client already has
+        offset: u64,
+    },
+    /// reply from server with count of bytes received for the upload, transfer continues from this offset, 0 means start again
+    TransferOffset {
+        /// id of the transfer
+        transfer: String,
+        /// count of bytes received by the server
+        offset: u64,
+    },
+    /// transfer cannot continue, receiver removes partially received data
+    TransferCancelled {
+        /// id of the transfer
+        transfer: String,
+        /// machine readable reason
+        code: ErrorCode,
+        /// human readable reason
+        reason: String,
+    },
     /// special login message containing user name and password for user to login
-    Login(String, String), // login, password
+    Login {
+        /// name of the user
+        login: String,
+        /// password of the user
+        password: Password,
+    },
+    /// request from client to end the session, server disconnects the client and announces that user has left
+    Logout,
+    /// request to create new user, invite code is required only when server registration is invite only
+    Register {
+        /// name of the new user
+        login: String,
+        /// password of the new user
+        password: Password,
+        /// invite code
+        invite: Option<String>,
+    },
+    /// positive reply from server to the request, e.g. successful login or joined room
+    Success {
+        /// description of the result
+        text: String,
+    },
+    /// error reply from server with machine readable code and human readable description
+    Error {
+        /// machine readable reason
+        code: ErrorCode,
+        /// human readable description
+        text: String,
+    },
+    /// request from client to get list of online users
+    Who,
+    /// reply from server with names of online users
+    UserList {
+        /// names of online users
+        users: Vec<String>,
+    },
+    /// presence event pushed by server when user connects
+    UserJoined {
+        /// name of the user
+        user: String,
+    },
+    /// presence event pushed by server when user disconnects
+    UserLeft {
+        /// name of the user
+        user: String,
+    },
+    /// request from client to join the room, room is created if it doesn't exist
+    Join {
+        /// name of the room
+        room: String,
+    },
+    /// request from client to leave the room
+    Leave {
+        /// name of the room
+        room: String,
+    },
+    /// request from client to get list of existing rooms
+    ListRooms,
+    /// reply from server with list of existing rooms and count of their members
+    RoomList {
+        /// names of the rooms with count of their members
+        rooms: Vec<(String, usize)>,
+    },
+    /// request from client to get last messages of the room
+    GetHistory {
+        /// name of the room
+        room: String,
+        /// count of requested messages
+        count: usize,
+    },
+    /// messages of the room stored before, sent after login, on join of the room and on request, ordered from the oldest one
+    HistoryBatch {
+        /// name of the room
+        room: String,
+        /// stored messages with their timestamps
+        messages: Vec<HistoryEntry>,
+    },
+    /// request from client to search stored messages, query contains words and optional from:user, since:date and page:n filters
+    Search {
+        /// words and filters
+        query: String,
+    },
+    /// reply from server with one page of search results ordered from the newest message, query is sent without the page
+    SearchResults {
+        /// query without the page
+        query: String,
+        /// number of the page, starting from 1
+        page: usize,
+        /// count of all found messages
+        total: usize,
+        /// found messages of the page with their timestamps
+        results: Vec<HistoryEntry>,
+    },
+    /// admin request to remove messages over retention limits now, server replies with report of removed messages and attachments
+    Prune,
+    /// admin request to write snapshot of stored messages, users and attachments while server runs, server replies with folder of the snapshot
+    Snapshot,
 }
 
-use crate::AsyncChatMsg::{File as AsyncMsgFile, Image as AsyncMsgImage};
-use crate::{
-    deserialize_msg, ensure_folder, get_file_data, get_file_name, save_msg_to_db, serialize_msg,
-};
+/// where the message is delivered, messages without destination are requests for the server
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+pub enum Destination {
+    /// request handled by the server itself, e.g. login, join of the room or search
+    #[default]
+    Server,
+    /// all members of the room
+    Room(String),
+    /// one user, used for private messages and replies of the server
+    User(String),
+}
 
-impl AsyncChatMsg {
-    /// creates text variant of message from provided parameters
-    pub fn create_text(from: String, msg: String) -> Result<AsyncChatMsg> {
-        let m = AsyncChatMsg::Text(from, msg);
-        return Ok(m);
+/// message sent over the network, metadata of the message are in the envelope and its content in typed body
+/// id, timestamp and sender are set by the server when it receives the message, values sent by the client are replaced,
+/// so nobody can send message in the name of other user. Unknown fields are ignored and missing fields except the body get default value,
+/// so new fields can be added without breaking older clients
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Envelope {
+    /// unique increasing id of the message, stored message keeps it as its key, empty until the message gets to the server
+    #[serde(default)]
+    pub id: String,
+    /// UTC time when the server received or created the message, empty until the message gets to the server
+    #[serde(default)]
+    pub timestamp: String,
+    /// user who sent the message, empty for messages created by the server
+    #[serde(default)]
+    pub from: String,
+    /// where the message is delivered
+    #[serde(default)]
+    pub to: Destination,
+    /// content of the message
+    pub body: AsyncChatMsg,
+}
+
+impl Envelope {
+    /// create message for the destination, id, time and sender are set by the server
+    pub fn new(to: Destination, body: AsyncChatMsg) -> Envelope {
+        return Envelope {
+            id: String::new(),
+            timestamp: String::new(),
+            from: String::new(),
+            to,
+            body,
+        };
+    }
+
+    /// create request handled by the server
+    pub fn request(body: AsyncChatMsg) -> Envelope {
+        return Envelope::new(Destination::Server, body);
+    }
+
+    /// create message of the server for the user, e.g. reply to the request or presence event
+    pub fn from_server(user: &str, body: AsyncChatMsg) -> Envelope {
+        return Envelope::new(Destination::User(user.to_string()), body).stamped("");
+    }
+
+    /// set new id, current time and the sender of the message, used by the server for every received and created message
+    pub fn stamped(mut self, from: &str) -> Envelope {
+        self.id = new_message_id();
+        self.timestamp = utc_timestamp(Utc::now());
+        self.from = from.to_string();
+        return self;
     }
 
-    /// creates file variant of message from provided parameters
-    pub async fn create_file(from: String, path: String) -> Result<AsyncChatMsg> {
-        let file_name = get_file_name(&path);
-        let data: Vec<u8> = get_file_data(&path)
+    /// create next message of the same sender to the same destination, e.g. end of the file transfer, it gets its own id and time
+    pub fn follow_up(&self, body: AsyncChatMsg) -> Envelope {
+        return Envelope::new(self.to.clone(), body).stamped(&self.from);
+    }
+
+    /// creates text message for the room
+    pub fn create_text(room: String, text: String) -> Envelope {
+        return Envelope::new(Destination::Room(room), AsyncChatMsg::Text { text });
+    }
+
+    /// creates private text message for the user
+    pub fn create_direct(to: String, text: String) -> Envelope {
+        return Envelope::new(Destination::User(to), AsyncChatMsg::Text { text });
+    }
+
+    /// creates upload of the file to the room, which is sent as FileStart, FileChunk and FileEnd messages,
+    /// user is kept only in the journal of uploads, sender of the file is set by the server
+    pub async fn create_file(from: String, room: String, path: String) -> Result<FileUpload> {
+        return FileUpload::new(from, room, &path, FileKind::File)
             .await
-            .with_context(|| "Getting data for file failed")?;
-        let m = AsyncChatMsg::File(from, file_name, data);
-        return Ok(m);
+            .with_context(|| "Preparing file for upload failed");
     }
 
-    /// creates image variant of message from provided parameters
-    pub async fn create_image(from: String, path: String) -> Result<AsyncChatMsg> {
-        let file_name = get_file_name(&path);
-        let data: Vec<u8> = get_file_data(&path)
+    /// creates upload of the image to the room, which is sent as FileStart, FileChunk and FileEnd messages,
+    /// user is kept only in the journal of uploads, sender of the image is set by the server
+    pub async fn create_image(from: String, room: String, path: String) -> Result<FileUpload> {
+        return FileUpload::new(from, room, &path, FileKind::Image)
             .await
-            .with_context(|| "Getting data for image failed")?;
-        let m = AsyncChatMsg::Image(from, file_name, data);
-        return Ok(m);
+            .with_context(|| "Preparing image for upload failed");
     }
 
-    /// send message over tcp stream to server and return result
+    /// get room the message is sent to, None for requests and messages for one user
+    pub fn room(&self) -> Option<&str> {
+        return match &self.to {
+            Destination::Room(room) => Some(room),
+            _ => None,
+        };
+    }
+
+    /// send message over tcp stream to server and return result, only limit is size of the length prefix
     pub async fn send<T: AsyncWriteExt + Unpin>(&self, stream: &mut T) -> Result<()> {
-        let msg: Vec<u8> = serialize_msg(&self)?;
+        let limits = FrameLimits {
+            max_message_size: u32::MAX,
+            max_chunk_size: u32::MAX,
+        };
+        return self.send_with_limits(stream, &limits).await;
+    }
+
+    /// check that serialized message fits to the limit for its kind, server checks messages after stamping,
+    /// because id, time and sender make them bigger than they were sent by the client
+    pub fn check_limits(&self, limits: &FrameLimits) -> Result<()> {
+        self.framed(limits)?;
+        return Ok(());
+    }
+
+    /// serialize message and check it against the limit for its kind
+    fn framed(&self, limits: &FrameLimits) -> Result<(FrameKind, Vec<u8>)> {
+        let msg: Vec<u8> = serialize_msg(self)?;
+        let kind = FrameKind::of(&self.body);
+        limits.check(kind, msg.len() as u64)?;
+        return Ok((kind, msg));
+    }
+
+    /// send message over tcp stream, message bigger than the limit for its kind is not sent and FrameError::Oversized is returned
+    pub async fn send_with_limits<T: AsyncWriteExt + Unpin>(
+        &self,
+        stream: &mut T,
+        limits: &FrameLimits,
+    ) -> Result<()> {
+        let (kind, msg) = self.framed(limits)?;
         stream
             .write_all(&(msg.len() as u32).to_be_bytes())
             .await
             .with_context(|| "Sending message size failed")?;
+        stream
+            .write_all(&[kind as u8])
+            .await
+            .with_context(|| "Sending message kind failed")?;
         stream
             .write_all(&msg)
             .await
@@ -69,16 +424,26 @@ impl AsyncChatMsg {
         return Ok(());
     }
 
-    /// receive message from the provided stream
+    /// receive message from the provided stream using default frame limits
     pub async fn receive<T: AsyncReadExt + Unpin>(stream: &mut T) -> Result<Self> {
-        let mut length_bytes = [0; 4];
+        return Self::receive_with_limits(stream, &FrameLimits::default()).await;
+    }
+
+    /// receive message from the provided stream, length of the frame is checked before any memory for data is allocated
+    pub async fn receive_with_limits<T: AsyncReadExt + Unpin>(
+        stream: &mut T,
+        limits: &FrameLimits,
+    ) -> Result<Self> {
+        let mut header = [0; 5];
 
         stream
-            .read_exact(&mut length_bytes)
+            .read_exact(&mut header)
             .await
             .with_context(|| "Failed to read length")?;
 
-        let length = u32::from_be_bytes(length_bytes);
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let kind = FrameKind::from_byte(header[4])?;
+        limits.check(kind, length as u64)?;
 
         let mut msgdata = vec![0; length as usize];
         stream
@@ -86,7 +451,11 @@ impl AsyncChatMsg {
             .await
             .with_context(|| "Reading message failed")?;
 
-        let msg: AsyncChatMsg = deserialize_msg(msgdata)?;
+        let msg: Envelope = deserialize_msg(msgdata)?;
+        // small limit for control messages cannot be bypassed by sending them as file frame
+        if FrameKind::of(&msg.body) != kind {
+            return Err(FrameError::KindMismatch(kind).into());
+        }
 
         return Ok(msg);
     }
@@ -97,95 +466,414 @@ impl AsyncChatMsg {
         password: String,
         stream: &mut T,
     ) -> Result<()> {
-        let m = AsyncChatMsg::Login(login, password);
+        let m = Envelope::request(AsyncChatMsg::Login {
+            login,
+            password: Password::from(password),
+        });
         m.send(stream).await
     }
 
-    /// store file to the filesystem, depending on message type either store file in the ./files folder or image in ./images, folders are created if doesn't exists
-    pub async fn store_file(&self) -> Result<()> {
-        let (filename, data, path) = match self {
-            AsyncMsgImage(_u, filename, data) => {
-                ensure_folder("images").await?;
-                (filename, data, Path::new("images"))
-            }
-            AsyncMsgFile(_u, filename, data) => {
-                ensure_folder("files").await?;
-                (filename, data, Path::new("files"))
+    /// create registration message and send it to server
+    pub async fn register<T: AsyncWriteExt + Unpin>(
+        login: String,
+        password: String,
+        invite: Option<String>,
+        stream: &mut T,
+    ) -> Result<()> {
+        let m = Envelope::request(AsyncChatMsg::Register {
+            login,
+            password: Password::from(password),
+            invite,
+        });
+        m.send(stream).await
+    }
+
+    /// get message stored in db, None for messages which are not stored, e.g. login, control messages or file chunks
+    pub fn to_db_msg(&self) -> Option<AsyncChatMsgDB> {
+        let from = self.from.clone();
+        let db_msg = match (&self.body, &self.to) {
+            (AsyncChatMsg::Text { text }, Destination::Room(room)) => AsyncChatMsgDB::Text {
+                from,
+                room: room.clone(),
+                text: text.clone(),
+            },
+            (AsyncChatMsg::Text { text }, Destination::User(to)) => AsyncChatMsgDB::Direct {
+                from,
+                to: to.clone(),
+                text: text.clone(),
+            },
+            (
+                AsyncChatMsg::FileStart {
+                    transfer,
+                    filename,
+                    kind,
+                    sha256,
+                    ..
+                },
+                Destination::Room(room),
+            ) => {
+                let (room, filename, sha256, transfer) = (
+                    room.clone(),
+                    filename.clone(),
+                    sha256.clone(),
+                    transfer.clone(),
+                );
+                match kind {
+                    FileKind::File => AsyncChatMsgDB::File {
+                        from,
+                        room,
+                        filename,
+                        sha256,
+                        transfer,
+                    },
+                    FileKind::Image => AsyncChatMsgDB::Image {
+                        from,
+                        room,
+                        filename,
+                        sha256,
+                        transfer,
+                    },
+                }
             }
-            _ => bail!("This is wrong type"),
+            _ => return None,
         };
+        return Some(db_msg);
+    }
 
-        let mut f = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open(path.join(filename))
-            .await?;
-
-        f.write(data).await?;
-        println!("File {} was saved to {path:?}", filename);
-        return Ok(());
+    /// save message to db under its id and time, files are saved by their FileStart message, data of the files are not stored,
+    /// message which was not stamped by the server gets new id
+    pub async fn save_to_db(&self, storage: &dyn Storage) -> Result<()> {
+        let Some(db_msg) = self.to_db_msg() else {
+            return Ok(()); // do not save Login, control messages or file chunks to db
+        };
+        if self.id.is_empty() {
+            save_msg_to_db(db_msg, storage).await?;
+            return Ok(());
+        }
+        let record = ChatRecord {
+            timestamp: self.timestamp.clone(),
+            msg: db_msg,
+        };
+        storage.insert_message(&self.id, record).await?;
+        Ok(())
     }
+}
 
-    /// save message to db, data of the files are not stored
-    pub async fn save_to_db(&self, db: NanoDB) -> Result<()> {
-        let db_msg = match self {
-            AsyncChatMsg::Text(from, msg) => {
-                AsyncChatMsgDB::Text(from.to_string(), msg.to_string())
+impl AsyncChatMsg {
+    /// store received file to the filesystem chunk by chunk, depending on file kind either in the ./files folder or image in ./images, folders are created if doesn't exists
+    /// returns path to the file once the transfer is finished
+    pub async fn store_file(&self, downloads: &mut FileDownloads) -> Result<Option<PathBuf>> {
+        match self {
+            AsyncChatMsg::FileStart {
+                transfer,
+                filename,
+                size,
+                kind,
+                sha256,
+            } => {
+                downloads
+                    .start(transfer, filename, *size, *kind, sha256)
+                    .await?;
             }
-            AsyncChatMsg::Image(from, filename, _) => {
-                AsyncChatMsgDB::Image(from.to_string(), filename.to_string())
+            AsyncChatMsg::FileChunk {
+                transfer,
+                offset,
+                data,
+            } => {
+                downloads.write_chunk(transfer, *offset, data).await?;
             }
-            AsyncChatMsg::File(from, filename, _) => {
-                AsyncChatMsgDB::File(from.to_string(), filename.to_string())
+            AsyncChatMsg::FileEnd { transfer } => {
+                let path = downloads.finish(transfer).await?;
+                if let Some(path) = &path {
+                    debug!("File was saved to {path:?}");
+                }
+                return Ok(path);
             }
-            _ => return Ok(()), // do not save Login or other types to db
-        };
-        let timestamp: DateTime<Local> = Local::now();
-        save_msg_to_db(
-            timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
-            db_msg,
-            db,
-        )
-        .await?;
-        Ok(())
+            _ => bail!("This is wrong type"),
+        };
+        return Ok(None);
     }
 
-    ///get text from the message, in case of file and image, return filename, in case of login message return login
+    ///get text from the message, in case of file and image, return filename, in case of chunk or end of transfer return transfer id, in case of login message return login, in case of room requests return room
     pub fn get_text(&self) -> &str {
         let text = match self {
-            AsyncChatMsg::Text(_, msg) => msg,
-            AsyncChatMsg::Image(_, filename, _) => filename,
-            AsyncChatMsg::File(_, filename, _) => filename,
-            AsyncChatMsg::Login(login, _) => login,
+            AsyncChatMsg::Text { text } => text,
+            AsyncChatMsg::FileStart { filename, .. } => filename,
+            AsyncChatMsg::FileChunk { transfer, .. } => transfer,
+            AsyncChatMsg::FileEnd { transfer } => transfer,
+            AsyncChatMsg::ResumeUpload { transfer } => transfer,
+            AsyncChatMsg::ResumeDownload { transfer, .. } => transfer,
+            AsyncChatMsg::TransferOffset { transfer, .. } => transfer,
+            AsyncChatMsg::TransferCancelled { reason, .. } => reason,
+            AsyncChatMsg::Login { login, .. } => login,
+            AsyncChatMsg::Register { login, .. } => login,
+            AsyncChatMsg::Join { room } => room,
+            AsyncChatMsg::Leave { room } => room,
+            AsyncChatMsg::GetHistory { room, .. } => room,
+            AsyncChatMsg::HistoryBatch { room, .. } => room,
+            AsyncChatMsg::Search { query } => query,
+            AsyncChatMsg::SearchResults { query, .. } => query,
+            AsyncChatMsg::Success { text } => text,
+            AsyncChatMsg::Error { text, .. } => text,
+            AsyncChatMsg::UserJoined { user } => user,
+            AsyncChatMsg::UserLeft { user } => user,
+            AsyncChatMsg::Logout
+            | AsyncChatMsg::Who
+            | AsyncChatMsg::UserList { .. }
+            | AsyncChatMsg::ListRooms
+            | AsyncChatMsg::RoomList { .. }
+            | AsyncChatMsg::Prune
+            | AsyncChatMsg::Snapshot => "",
         };
         return text;
     }
 }
 
-/// lightweight version of AsyncChatMsg for storing in db, doesn't contain data of the files
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// lightweight version of AsyncChatMsg for storing in db, doesn't contain data of the files,
+/// fields have names, so new optional fields can be added without breaking older files
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase")]
 pub enum AsyncChatMsgDB {
-    /// simplest text message variant, contains username from who the message is and text of the message
-    Text(String, String), // from, message
-    /// file message variant, contains username from who the message is, file name and file data
-    File(String, String), // from, filename
-    /// image message variant, contains username from who the message is, image name and file data
-    Image(String, String), // from, filename
+    /// text sent to the room
+    Text {
+        /// author of the message
+        from: String,
+        /// room of the message
+        room: String,
+        /// text of the message
+        text: String,
+    },
+    /// file sent to the room, hash and transfer id are missing for files stored by older versions
+    File {
+        /// author of the message
+        from: String,
+        /// room of the message
+        room: String,
+        /// name of the file
+        filename: String,
+        /// SHA-256 hash of the file
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        sha256: String,
+        /// id of the transfer the file is stored under
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        transfer: String,
+    },
+    /// image sent to the room, hash and transfer id are missing for images stored by older versions
+    Image {
+        /// author of the message
+        from: String,
+        /// room of the message
+        room: String,
+        /// name of the image
+        filename: String,
+        /// SHA-256 hash of the image
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        sha256: String,
+        /// id of the transfer the image is stored under
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        transfer: String,
+    },
+    /// private message
+    Direct {
+        /// author of the message
+        from: String,
+        /// recipient of the message
+        to: String,
+        /// text of the message
+        text: String,
+    },
+}
+
+impl AsyncChatMsgDB {
+    /// get username from who the message is
+    pub fn get_from(&self) -> &str {
+        let from = match self {
+            AsyncChatMsgDB::Text { from, .. } => from,
+            AsyncChatMsgDB::File { from, .. } => from,
+            AsyncChatMsgDB::Image { from, .. } => from,
+            AsyncChatMsgDB::Direct { from, .. } => from,
+        };
+        return from;
+    }
+
+    /// get room of the message, None for private messages
+    pub fn get_room(&self) -> Option<&str> {
+        let room = match self {
+            AsyncChatMsgDB::Text { room, .. } => room,
+            AsyncChatMsgDB::File { room, .. } => room,
+            AsyncChatMsgDB::Image { room, .. } => room,
+            AsyncChatMsgDB::Direct { .. } => return None,
+        };
+        return Some(room);
+    }
+
+    /// get id of the transfer the attachment of the message is stored under, None for text messages and older records
+    pub fn get_transfer_id(&self) -> Option<&str> {
+        return match self {
+            AsyncChatMsgDB::File { transfer, .. } | AsyncChatMsgDB::Image { transfer, .. }
+                if !transfer.is_empty() =>
+            {
+                Some(transfer)
+            }
+            _ => None,
+        };
+    }
+
+    /// check if the message is private, private messages are visible only to the sender and the recipient
+    pub fn is_private(&self) -> bool {
+        return matches!(self, AsyncChatMsgDB::Direct { .. });
+    }
+
+    /// check if the message can be shown to the user, used when history is replayed
+    pub fn is_visible_to(&self, user: &str) -> bool {
+        return match self {
+            AsyncChatMsgDB::Direct { from, to, .. } => from == user || to == user,
+            _ => true,
+        };
+    }
+}
+
+/// implementation of Display trait, so stored messages are displayed on console same way as received ones
+impl fmt::Display for AsyncChatMsgDB {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let printable = match &self {
+            AsyncChatMsgDB::Text { from, room, text } => format!("[{room}] {from}: {text}"),
+            AsyncChatMsgDB::File {
+                from,
+                room,
+                filename,
+                ..
+            } => format!("[{room}] {from}: file {filename}"),
+            AsyncChatMsgDB::Image {
+                from,
+                room,
+                filename,
+                ..
+            } => format!("[{room}] {from}: image {filename}"),
+            AsyncChatMsgDB::Direct { from, to, text } => {
+                format!("(private) {from} -> {to}: {text}")
+            }
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+/// implementation of Display trait, so messages in rooms show the room and the sender and private messages both users
+impl fmt::Display for Envelope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let from = &self.from;
+        let body = &self.body;
+        let printable = match (&self.to, body) {
+            (
+                Destination::Room(room),
+                AsyncChatMsg::Text { .. } | AsyncChatMsg::FileStart { .. },
+            ) => {
+                format!("[{room}] {from}: {body}")
+            }
+            (Destination::User(to), AsyncChatMsg::Text { .. }) => {
+                format!("(private) {from} -> {to}: {body}")
+            }
+            _ => body.to_string(),
+        };
+        write!(f, "{}", printable)
+    }
 }
 
 /// implementation of Display trait, so AsyncChatMessage can be easily displayed on console
 impl fmt::Display for AsyncChatMsg {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let printable = match &self {
-            AsyncChatMsg::Text(from, text) => format!("{from}: {text}"),
-            AsyncChatMsg::File(from, text, data) => {
-                format!("{}: incomming file {} ({}B)", from, text, data.len())
+            AsyncChatMsg::Text { text } => text.to_string(),
+            AsyncChatMsg::FileStart {
+                filename,
+                size,
+                kind: FileKind::File,
+                ..
+            } => format!("incomming file {filename} ({size}B)"),
+            AsyncChatMsg::FileStart {
+                filename,
+                size,
+                kind: FileKind::Image,
+                ..
+            } => format!("incomming image {filename} ({size}B)"),
+            AsyncChatMsg::FileChunk {
+                transfer,
+                offset,
+                data,
+            } => format!("chunk of transfer {transfer} at {offset} ({}B)", data.len()),
+            AsyncChatMsg::FileEnd { transfer } => format!("end of transfer {transfer}"),
+            AsyncChatMsg::ResumeUpload { transfer } => format!("resuming upload {transfer}"),
+            AsyncChatMsg::ResumeDownload { transfer, offset } => {
+                format!("resuming download {transfer} from {offset}")
+            }
+            AsyncChatMsg::TransferOffset { transfer, offset } => {
+                format!("Server: received {offset}B of transfer {transfer}")
+            }
+            AsyncChatMsg::TransferCancelled {
+                transfer,
+                code,
+                reason,
+            } => format!("Server: transfer {transfer} was cancelled {code:?}: {reason}"),
+            AsyncChatMsg::Login { login, .. } => format!("{login}: ********* (you didn't really think that I would print password here, did you?"),
+            AsyncChatMsg::Register { login, .. } => format!("registering user {login}"),
+            AsyncChatMsg::Logout => "logging out".to_string(),
+            AsyncChatMsg::Success { text } => format!("Server: {text}"),
+            AsyncChatMsg::Error { code, text } => format!("Server: ERROR {code:?}: {text}"),
+            AsyncChatMsg::Who => "requesting list of online users".to_string(),
+            AsyncChatMsg::UserList { users } => format!("Online users: {}", users.join(", ")),
+            AsyncChatMsg::UserJoined { user } => format!("User {user} has connected"),
+            AsyncChatMsg::UserLeft { user } => format!("User {user} has disconnected"),
+            AsyncChatMsg::Join { room } => format!("joining room {room}"),
+            AsyncChatMsg::Leave { room } => format!("leaving room {room}"),
+            AsyncChatMsg::ListRooms => "requesting list of rooms".to_string(),
+            AsyncChatMsg::RoomList { rooms } => {
+                let rooms: Vec<String> = rooms
+                    .iter()
+                    .map(|(room, count)| format!("{room} ({count})"))
+                    .collect();
+                format!("Rooms: {}", rooms.join(", "))
+            }
+            AsyncChatMsg::GetHistory { room, count } => {
+                format!("requesting last {count} messages of room {room}")
+            }
+            AsyncChatMsg::HistoryBatch { room, messages } => {
+                let mut lines = vec![format!("--- history of {room} ---")];
+                lines.extend(
+                    messages
+                        .iter()
+                        .map(|(timestamp, msg)| format!("{} {msg}", local_time(timestamp))),
+                );
+                lines.push(format!("--- end of history of {room} ---"));
+                lines.join("\n")
             }
-            AsyncChatMsg::Image(from, text, data) => {
-                format!("{}: incomming image {} ({}B)", from, text, data.len())
+            AsyncChatMsg::Search { query } => format!("searching for {query}"),
+            AsyncChatMsg::Prune => "requesting prune of old messages".to_string(),
+            AsyncChatMsg::Snapshot => "requesting snapshot of stored data".to_string(),
+            AsyncChatMsg::SearchResults {
+                query,
+                page,
+                total,
+                results,
+            } => {
+                let pages = page_count(*total);
+                let mut lines = vec![format!(
+                    "--- search results for {query}: page {page} of {pages} ({total} messages) ---"
+                )];
+                lines.extend(
+                    results
+                        .iter()
+                        .map(|(timestamp, msg)| format!("{} {msg}", local_time(timestamp))),
+                );
+                if *page < pages {
+                    lines.push(format!(
+                        "--- use .search {query} page:{} for more ---",
+                        page + 1
+                    ));
+                } else {
+                    lines.push("--- end of search results ---".to_string());
+                }
+                lines.join("\n")
             }
-            AsyncChatMsg::Login(login, _password) => format!("{login}: ********* (you didn't really think that I would print password here, did you?"),
         };
         write!(f, "{}", printable)
     }
@@ -1,22 +1,45 @@
 //! Client binary for connecting to server part
 #![warn(missing_docs)]
+#![allow(clippy::needless_return)]
 use std::{
+    collections::BTreeSet,
     process::exit,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
 use anyhow::{Context, Result};
 use tokio::{
     io::{stdin, AsyncBufReadExt, BufReader},
     net::TcpStream,
+    sync::{mpsc, Mutex},
 };
+use tracing::error;
 
-use rust_15_async_chat::async_chat_msg::AsyncChatMsg;
+use rust_15_async_chat::async_chat_msg::{AsyncChatMsg, Envelope, ErrorCode};
+use rust_15_async_chat::config::{AcceptDecision, ClientConfig, CLIENT_CONFIG_FILE};
+use rust_15_async_chat::frame::FrameError;
+use rust_15_async_chat::history::DEFAULT_HISTORY_SIZE;
+use rust_15_async_chat::logging::init_logging;
+use rust_15_async_chat::rooms::{normalize_room_name, DEFAULT_ROOM};
+use rust_15_async_chat::transfer::{is_valid_transfer_id, FileDownloads, FileUpload, FileUploads};
 
 static END_INPUT: AtomicBool = AtomicBool::new(false);
+/// count of file messages waiting to be sent, upload task waits when the queue is full
+const UPLOAD_QUEUE_SIZE: usize = 4;
+/// journal of unfinished incoming transfers, they are resumed after next login
+const DOWNLOADS_JOURNAL: &str = "downloads.json";
+/// journal of unfinished outgoing transfers, they are resumed after next login
+const UPLOADS_JOURNAL: &str = "uploads.json";
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    init_logging("info");
+    let config = ClientConfig::load(CLIENT_CONFIG_FILE)?;
+    // frames over the limits are neither sent nor accepted, limits have to match the server
+    let limits = config.frame_limits;
     // create connection
     let stream = TcpStream::connect("127.0.0.1:11112")
         .await
@@ -28,39 +51,71 @@ async fn main() -> Result<()> {
     let mut lines = stdin.lines();
 
     println!("Client connected to AsyncChatServer");
-    println!("Enter your name and password:");
+    println!("Enter your name and password, new users register with: .register <name> <password> [invite code]");
 
     // get user name and password and validate against server
     let name = loop {
         let Ok(Some(name)) = lines.next_line().await else {
-            eprintln!("Getting username and password failed, quit");
+            error!("Getting username and password failed, quit");
             exit(0);
         };
 
+        // registration is the same as login, just with different message and optional invite code
+        let (name, register, invite) = match name.strip_prefix(".register ") {
+            Some(rest) => {
+                let mut parts = rest.split_whitespace();
+                let login = parts.next().unwrap_or_default();
+                let password = parts.next().unwrap_or_default();
+                let invite = parts.next().map(|i| i.to_string());
+                (format!("{login} {password}"), true, invite)
+            }
+            None => (name, false, None),
+        };
+
         match name.split_once(' ') {
             None => {
                 println!("Login and password cannot be empty");
                 continue;
             }
             Some((login, password)) => {
-                if login.trim().len() != 0 && password.len() != 0 {
-                    if AsyncChatMsg::login(login.trim().into(), password.into(), &mut writer)
+                if !login.trim().is_empty() && !password.is_empty() {
+                    let sent = if register {
+                        Envelope::register(
+                            login.trim().into(),
+                            password.into(),
+                            invite,
+                            &mut writer,
+                        )
                         .await
-                        .is_err()
-                    {
-                        eprintln!("Sending login failed");
+                    } else {
+                        Envelope::login(login.trim().into(), password.into(), &mut writer).await
+                    };
+                    if sent.is_err() {
+                        error!("Sending login failed");
                         exit(0);
                     }
 
-                    if let Ok(server_msg) = AsyncChatMsg::receive(&mut reader).await {
-                        println!("{server_msg}");
-                        if let AsyncChatMsg::Text(_from, msg) = server_msg {
-                            if msg.starts_with("ERROR") {
-                                println!("Login failed: {msg}");
-                                continue;
-                            }
+                    let reply = Envelope::receive_with_limits(&mut reader, &limits).await;
+                    match reply.map(|reply| reply.body) {
+                        Ok(AsyncChatMsg::Error {
+                            code: ErrorCode::RateLimited,
+                            text,
+                        }) => {
+                            println!("Login failed: {text}");
+                            exit(0);
+                        }
+                        Ok(AsyncChatMsg::Error { text, .. }) => {
+                            println!("Login failed: {text}");
+                            continue;
+                        }
+                        Ok(server_msg) => {
+                            println!("{server_msg}");
+                            break login.to_string();
+                        }
+                        Err(e) => {
+                            error!("Receiving login reply failed with error: {e}");
+                            exit(0);
                         }
-                        break login.to_string();
                     }
                 } else {
                     println!("Login and password cannot be empty! Try again");
@@ -69,81 +124,271 @@ async fn main() -> Result<()> {
         };
     };
 
+    // chat messages and file chunks are queued separately, so text doesn't wait until large upload is finished
+    let (chat_queue, mut chat_recv) = mpsc::unbounded_channel::<Envelope>();
+    let (upload_queue, mut upload_recv) = mpsc::channel::<Envelope>(UPLOAD_QUEUE_SIZE);
+
+    // transfers interrupted by previous disconnect continue from the offset where they stopped
+    let downloads = FileDownloads::load(
+        config.max_file_size,
+        &config.download_dir,
+        DOWNLOADS_JOURNAL,
+    )
+    .await
+    .unwrap_or_else(|e| {
+        error!("Loading unfinished downloads failed with error: {e}");
+        FileDownloads::new(config.max_file_size, &config.download_dir)
+    });
+    let uploads = FileUploads::load(UPLOADS_JOURNAL)
+        .await
+        .unwrap_or_else(|e| {
+            error!("Loading unfinished uploads failed with error: {e}");
+            FileUploads::new()
+        });
+    for (transfer, offset) in downloads.pending() {
+        _ = chat_queue.send(Envelope::request(AsyncChatMsg::ResumeDownload {
+            transfer,
+            offset,
+        }));
+    }
+    for upload in uploads.pending_for(&name) {
+        println!("Resuming upload of file {}", upload.filename);
+        let transfer = upload.id.clone();
+        _ = chat_queue.send(Envelope::request(AsyncChatMsg::ResumeUpload { transfer }));
+    }
+    let uploads = Arc::new(Mutex::new(uploads));
+    // offered files are accepted by write task and downloaded by read task
+    let downloads = Arc::new(Mutex::new(downloads));
+
+    let send_task = tokio::spawn(async move {
+        loop {
+            // chat messages have priority, file chunks are sent when there is no chat message waiting
+            let msg = tokio::select! {
+                biased;
+                Some(msg) = chat_recv.recv() => msg,
+                Some(msg) = upload_recv.recv() => msg,
+                else => break,
+            };
+            if let Err(e) = msg.send_with_limits(&mut writer, &limits).await {
+                match e.downcast_ref::<FrameError>() {
+                    Some(frame_error) => error!("Message was not sent: {frame_error}"),
+                    None => error!("Sending message to server failed"),
+                }
+                continue;
+            }
+            if matches!(msg.body, AsyncChatMsg::Logout) {
+                END_INPUT
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |_| Some(true))
+                    .unwrap();
+                break;
+            }
+        }
+    });
+
+    let write_uploads = uploads.clone();
+    let write_upload_queue = upload_queue.clone();
+    let max_upload_size = config.max_upload_size;
+    let write_downloads = downloads.clone();
+    let read_chat_queue = chat_queue.clone();
     let write_task = tokio::spawn(async move {
+        // room where text, files and images are sent, changed by .join command
+        let mut room = DEFAULT_ROOM.to_string();
         while let Ok(Some(line)) = lines.next_line().await {
-            match line.split_once(' ') {
-                None => {
-                    if AsyncChatMsg::create_text(name.clone(), line.clone())
-                        .unwrap()
-                        .send(&mut writer)
-                        .await
-                        .is_err()
-                    {
-                        eprintln!("Sending message to server failed");
+            let request = match line.split_once(' ') {
+                None if line == ".quit" => AsyncChatMsg::Logout,
+                None if line == ".rooms" => AsyncChatMsg::ListRooms,
+                None if line == ".who" => AsyncChatMsg::Who,
+                None if line == ".prune" => AsyncChatMsg::Prune,
+                None if line == ".snapshot" => AsyncChatMsg::Snapshot,
+                None if line == ".history" => AsyncChatMsg::GetHistory {
+                    room: room.clone(),
+                    count: DEFAULT_HISTORY_SIZE,
+                },
+                Some((".history", count)) => match count.trim().parse() {
+                    Ok(count) => AsyncChatMsg::GetHistory {
+                        room: room.clone(),
+                        count,
+                    },
+                    Err(_) => {
+                        eprintln!("Usage: .history [count]");
                         continue;
                     }
-                    if line == ".quit" {
-                        END_INPUT
-                            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |_| Some(true))
-                            .unwrap();
-                        break;
+                },
+                Some((".search", query)) if !query.trim().is_empty() => AsyncChatMsg::Search {
+                    query: query.trim().to_string(),
+                },
+                None | Some((".search", _)) if line.starts_with(".search") => {
+                    eprintln!("Usage: .search <terms> [from:user] [since:date] [page:n]");
+                    continue;
+                }
+                Some((".join", new_room)) => match normalize_room_name(new_room) {
+                    Ok(new_room) => {
+                        room = new_room.clone();
+                        AsyncChatMsg::Join { room: new_room }
+                    }
+                    Err(e) => {
+                        eprintln!("{e}");
+                        continue;
+                    }
+                },
+                Some((".leave", old_room)) => {
+                    let old_room = normalize_room_name(old_room).unwrap_or(old_room.into());
+                    if old_room == room {
+                        room = DEFAULT_ROOM.to_string();
                     }
+                    AsyncChatMsg::Leave { room: old_room }
                 }
-                Some((".image", path)) => {
-                    if AsyncChatMsg::create_image(name.clone(), path.into())
-                        .await
-                        .unwrap()
-                        .send(&mut writer)
-                        .await
-                        .is_err()
-                    {
-                        eprintln!("Sending message to server failed");
+                Some((".msg", rest)) => match rest.trim_start().split_once(' ') {
+                    Some((to, text)) if !text.trim().is_empty() => {
+                        let msg = Envelope::create_direct(to.to_string(), text.to_string());
+                        if chat_queue.send(msg).is_err() {
+                            break;
+                        }
                         continue;
                     }
+                    _ => {
+                        eprintln!("Usage: .msg <user> <message>");
+                        continue;
+                    }
+                },
+                Some((".image", path)) => {
+                    let upload =
+                        Envelope::create_image(name.clone(), room.clone(), path.into()).await;
+                    start_upload(upload, max_upload_size, &write_uploads, &write_upload_queue)
+                        .await;
+                    continue;
                 }
                 Some((".file", path)) => {
-                    if AsyncChatMsg::create_file(name.clone(), path.into())
-                        .await
-                        .unwrap()
-                        .send(&mut writer)
-                        .await
-                        .is_err()
-                    {
-                        eprintln!("Sending message to server failed");
-                        continue;
+                    let upload =
+                        Envelope::create_file(name.clone(), room.clone(), path.into()).await;
+                    start_upload(upload, max_upload_size, &write_uploads, &write_upload_queue)
+                        .await;
+                    continue;
+                }
+                Some((".accept", offer)) => {
+                    match write_downloads.lock().await.accept(offer).await {
+                        Ok(Some(request)) => request,
+                        Ok(None) => {
+                            println!("File will be downloaded when its upload is finished");
+                            continue;
+                        }
+                        Err(e) => {
+                            eprintln!("{e}");
+                            continue;
+                        }
                     }
                 }
+                Some((".reject", offer)) => {
+                    match write_downloads.lock().await.reject(offer) {
+                        Some(filename) => println!("File {filename} was rejected"),
+                        None => eprintln!("Unknown file offer {offer}"),
+                    }
+                    continue;
+                }
                 _ => {
-                    if AsyncChatMsg::create_text(name.clone(), line.clone())
-                        .unwrap()
-                        .send(&mut writer)
-                        .await
-                        .is_err()
-                    {
-                        eprintln!("Sending message to server failed");
-                        continue;
+                    let msg = Envelope::create_text(room.clone(), line.clone());
+                    if chat_queue.send(msg).is_err() {
+                        break;
                     }
+                    continue;
                 }
+            };
+            let logout = matches!(request, AsyncChatMsg::Logout);
+            if chat_queue.send(Envelope::request(request)).is_err() || logout {
+                break;
             }
         }
     });
 
     let read_task = tokio::spawn(async move {
+        // online users, initialized by user list sent after login and kept up to date by presence events
+        let mut roster: BTreeSet<String> = BTreeSet::new();
         loop {
-            let msg = match AsyncChatMsg::receive(&mut reader).await {
+            let msg = match Envelope::receive_with_limits(&mut reader, &limits).await {
                 Ok(msg) => msg,
+                // server closes the connection after logout
+                Err(_) if END_INPUT.load(Ordering::Relaxed) => break,
                 Err(e) => {
-                    eprintln!("Receiving message from server failed with error: {e}");
+                    error!("Receiving message from server failed with error: {e}");
                     break;
                 }
             };
-            println!("{}", msg);
-            if matches!(msg, AsyncChatMsg::File(_, _, _))
-                || matches!(msg, AsyncChatMsg::Image(_, _, _))
-            {
-                if let Err(e) = msg.store_file().await {
-                    eprintln!("Saving incomming file failed with error: {e}");
-                };
+            match &msg.body {
+                AsyncChatMsg::UserList { users } => {
+                    roster = users.iter().cloned().collect();
+                    println!("{}", msg);
+                }
+                AsyncChatMsg::UserJoined { user } => {
+                    roster.insert(user.clone());
+                    println!("{} ({} online)", msg, roster.len());
+                }
+                AsyncChatMsg::UserLeft { user } => {
+                    roster.remove(user);
+                    println!("{} ({} online)", msg, roster.len());
+                }
+                AsyncChatMsg::FileStart {
+                    transfer,
+                    filename,
+                    size,
+                    kind,
+                    sha256,
+                } => {
+                    println!("{}", msg);
+                    // transfer id becomes part of file name, so invalid id is never used for download
+                    if !is_valid_transfer_id(transfer) {
+                        error!(
+                            "File {filename} was ignored, transfer id {transfer:?} is not valid"
+                        );
+                        continue;
+                    }
+                    let mut downloads = downloads.lock().await;
+                    match config.accept.decide(filename, *size) {
+                        AcceptDecision::Accept => {
+                            if let Err(e) = msg.body.store_file(&mut downloads).await {
+                                error!("Saving incomming file failed with error: {e}");
+                            }
+                        }
+                        AcceptDecision::Ask => {
+                            let offer = downloads.offer(transfer, filename, *size, *kind, sha256);
+                            println!("Download it with .accept {offer} or ignore it with .reject {offer}");
+                        }
+                        AcceptDecision::Ignore => println!("File {filename} was ignored"),
+                    }
+                }
+                AsyncChatMsg::FileEnd { transfer }
+                    if downloads.lock().await.is_offered(transfer) =>
+                {
+                    match downloads.lock().await.offer_finished(transfer).await {
+                        Ok(Some(request)) => _ = read_chat_queue.send(Envelope::request(request)),
+                        Ok(None) => (),
+                        Err(e) => error!("Downloading file failed with error: {e}"),
+                    }
+                }
+                AsyncChatMsg::FileChunk { .. } | AsyncChatMsg::FileEnd { .. } => {
+                    match msg.body.store_file(&mut *downloads.lock().await).await {
+                        Ok(Some(path)) => println!("File was saved to {}", path.display()),
+                        Ok(None) => (),
+                        Err(e) => error!("Saving incomming file failed with error: {e}"),
+                    }
+                }
+                AsyncChatMsg::TransferOffset { transfer, offset } => {
+                    let mut uploads = uploads.lock().await;
+                    let Some(upload) = uploads.get(transfer).cloned() else {
+                        continue;
+                    };
+                    if *offset >= upload.size {
+                        uploads.finish(transfer).await;
+                        println!("File {} was sent", upload.filename);
+                    } else {
+                        uploads.start(upload, *offset, &upload_queue).await;
+                    }
+                }
+                AsyncChatMsg::TransferCancelled { transfer, .. } => {
+                    uploads.lock().await.finish(transfer).await;
+                    downloads.lock().await.cancel(transfer).await;
+                    println!("{}", msg);
+                }
+                _ => println!("{}", msg),
             }
 
             if END_INPUT.load(Ordering::Relaxed) {
@@ -152,6 +397,30 @@ async fn main() -> Result<()> {
         }
     });
 
-    _ = tokio::join!(write_task, read_task);
+    _ = tokio::join!(write_task, send_task, read_task);
     Ok(())
 }
+
+/// send the file in background task, chunks are queued to bounded upload queue, so only few of them are in memory
+async fn start_upload(
+    upload: Result<FileUpload>,
+    max_upload_size: u64,
+    uploads: &Mutex<FileUploads>,
+    upload_queue: &mpsc::Sender<Envelope>,
+) {
+    let upload = match upload {
+        Ok(upload) if upload.size > max_upload_size => {
+            error!(
+                "File {} has {}B, which exceeds limit {}B",
+                upload.filename, upload.size, max_upload_size
+            );
+            return;
+        }
+        Ok(upload) => upload,
+        Err(e) => {
+            error!("Creating message failed with error: {e}");
+            return;
+        }
+    };
+    uploads.lock().await.start(upload, 0, upload_queue).await;
+}
@@ -0,0 +1,114 @@
+//! Export binary to write stored chat history to a file
+#![warn(missing_docs)]
+#![allow(clippy::needless_return)]
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+use anyhow::{bail, Context, Result};
+
+use rust_15_async_chat::config::{ServerConfig, SERVER_CONFIG_FILE};
+use rust_15_async_chat::export::{export, ExportFilter, ExportFormat};
+use rust_15_async_chat::history::parse_time_filter;
+use rust_15_async_chat::logging::init_logging;
+use rust_15_async_chat::rooms::normalize_room_name;
+use rust_15_async_chat::storage::read_messages;
+use rust_15_async_chat::transfer::TRANSFERS_FOLDER;
+
+const USAGE: &str = "Usage: export <jsonl|csv|html|irc> [--room <room>] [--since <date>] [--until <date>] [--output <file>] [--config <file>] [--attachments <folder or url>]";
+
+/// options of the export given on command line
+struct ExportArgs {
+    format: ExportFormat,
+    filter: ExportFilter,
+    /// file to write to, standard output if not set
+    output: Option<String>,
+    /// server configuration with storage of messages
+    config: String,
+    /// folder or url prefix of links to stored files
+    attachments: String,
+    /// heading of html transcript
+    title: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    init_logging("warn");
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+    let config = ServerConfig::load(&args.config)?;
+    // storage is only read, so export never creates or converts its files
+    let records: Vec<_> = read_messages(&config.storage)
+        .await
+        .with_context(|| format!("Reading storage {:?} failed", config.storage))?
+        .into_iter()
+        .filter(|(_, record)| args.filter.matches(record))
+        .collect();
+
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).with_context(|| format!("Creating file {path} failed"))?,
+        )),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    export(
+        &mut out,
+        args.format,
+        &records,
+        &args.attachments,
+        &args.title,
+    )?;
+    if let Some(path) = &args.output {
+        eprintln!("Exported {} messages to {path}", records.len());
+    }
+    return Ok(());
+}
+
+/// parse command line arguments without the name of the program
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<ExportArgs> {
+    let Some(format) = args.next() else {
+        bail!("Export format is missing");
+    };
+    let mut export_args = ExportArgs {
+        format: format.parse()?,
+        filter: ExportFilter::default(),
+        output: None,
+        config: SERVER_CONFIG_FILE.to_string(),
+        attachments: TRANSFERS_FOLDER.to_string(),
+        title: "Chat history".to_string(),
+    };
+    while let Some(option) = args.next() {
+        let Some(value) = args.next() else {
+            bail!("Value of {option} is missing");
+        };
+        match option.as_str() {
+            "--room" => {
+                let room = normalize_room_name(&value)?;
+                export_args.title = format!("{} of {room}", export_args.title);
+                export_args.filter.room = Some(room);
+            }
+            "--since" | "--until" => {
+                let Some(time) = parse_time_filter(&value) else {
+                    bail!("Date {value} is not valid, use e.g. 2024-05-01");
+                };
+                let word = option.trim_start_matches('-');
+                export_args.title = format!("{} {word} {value}", export_args.title);
+                match option.as_str() {
+                    "--since" => export_args.filter.since = Some(time),
+                    _ => export_args.filter.until = Some(time),
+                }
+            }
+            "--output" => export_args.output = Some(value),
+            "--config" => export_args.config = value,
+            "--attachments" => export_args.attachments = value,
+            _ => bail!("Unknown option {option}"),
+        }
+    }
+    return Ok(export_args);
+}
@@ -0,0 +1,128 @@
+//! Import binary to bring chat history from other servers or IRC logs
+#![warn(missing_docs)]
+#![allow(clippy::needless_return)]
+use std::{path::Path, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use chrono::NaiveDate;
+
+use rust_15_async_chat::config::{ServerConfig, SERVER_CONFIG_FILE};
+use rust_15_async_chat::history::local_time;
+use rust_15_async_chat::import::{import_messages, parse_irc, parse_jsonl, ImportFormat};
+use rust_15_async_chat::logging::init_logging;
+use rust_15_async_chat::rooms::{normalize_room_name, DEFAULT_ROOM};
+use rust_15_async_chat::storage::{
+    open_backend, read_messages, MemoryStorage, Storage, WriteBehindStorage,
+};
+
+const USAGE: &str = "Usage: import <file> [--format jsonl|irc] [--room <room>] [--date <date>] [--config <file>] [--dry-run]";
+
+/// count of imported messages written to storage at once
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+/// options of the import given on command line
+struct ImportArgs {
+    file: String,
+    format: ImportFormat,
+    /// room of IRC log lines without room
+    room: String,
+    /// date of IRC log lines with time only
+    date: Option<NaiveDate>,
+    /// server configuration with storage of messages
+    config: String,
+    /// only report what would be imported
+    dry_run: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    init_logging("warn");
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+    let text = tokio::fs::read_to_string(&args.file)
+        .await
+        .with_context(|| format!("Reading file {} failed", args.file))?;
+    let parsed = match args.format {
+        ImportFormat::Jsonl => parse_jsonl(&text),
+        ImportFormat::Irc => parse_irc(&text, &args.room, args.date),
+    };
+
+    let config = ServerConfig::load(&args.config)?;
+    let storage: Arc<dyn Storage> = match args.dry_run {
+        // dry run only compares with stored messages, so storage files are neither created nor migrated
+        true => {
+            let existing = read_messages(&config.storage)
+                .await
+                .with_context(|| format!("Reading storage {:?} failed", config.storage))?;
+            let storage = MemoryStorage::new();
+            storage.insert_messages(existing).await?;
+            Arc::new(storage)
+        }
+        false => {
+            let backend = open_backend(&config.storage)
+                .await
+                .with_context(|| format!("Opening storage {:?} failed", config.storage))?;
+            // messages are written in batches, so NanoDB file is not rewritten for every message
+            WriteBehindStorage::new(backend, IMPORT_BATCH_SIZE, Duration::from_secs(1))
+        }
+    };
+    let report = import_messages(parsed, storage.as_ref(), args.dry_run).await?;
+    storage.flush().await?;
+
+    for (line, reason) in &report.skipped {
+        eprintln!("{}:{line}: {reason}", args.file);
+    }
+    if report.dry_run {
+        for (timestamp, msg) in &report.imported {
+            println!("{} {msg}", local_time(timestamp));
+        }
+    }
+    println!("{report}");
+    return Ok(());
+}
+
+/// parse command line arguments without the name of the program
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<ImportArgs> {
+    let Some(file) = args.next() else {
+        bail!("File to import is missing");
+    };
+    // format is guessed from extension, logs can have any extension
+    let extension = Path::new(&file)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut import_args = ImportArgs {
+        format: extension.parse().unwrap_or(ImportFormat::Irc),
+        file,
+        room: DEFAULT_ROOM.to_string(),
+        date: None,
+        config: SERVER_CONFIG_FILE.to_string(),
+        dry_run: false,
+    };
+    while let Some(option) = args.next() {
+        if option == "--dry-run" {
+            import_args.dry_run = true;
+            continue;
+        }
+        let Some(value) = args.next() else {
+            bail!("Value of {option} is missing");
+        };
+        match option.as_str() {
+            "--format" => import_args.format = value.parse()?,
+            "--room" => import_args.room = normalize_room_name(&value)?,
+            "--date" => {
+                let date = NaiveDate::parse_from_str(&value, "%Y-%m-%d")
+                    .with_context(|| format!("Date {value} is not valid, use e.g. 2024-05-01"))?;
+                import_args.date = Some(date);
+            }
+            "--config" => import_args.config = value,
+            _ => bail!("Unknown option {option}"),
+        }
+    }
+    return Ok(import_args);
+}
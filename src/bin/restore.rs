@@ -0,0 +1,81 @@
+//! Restore binary to replace stored data with snapshot taken by the server
+#![warn(missing_docs)]
+#![allow(clippy::needless_return)]
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+
+use rust_15_async_chat::config::{ServerConfig, SERVER_CONFIG_FILE};
+use rust_15_async_chat::logging::init_logging;
+use rust_15_async_chat::snapshot::{check_snapshot, restore_snapshot, SNAPSHOTS_FOLDER};
+use rust_15_async_chat::transfer::TRANSFERS_FOLDER;
+
+const USAGE: &str = "Usage: restore <snapshot folder> [--config <file>] [--check]";
+
+/// options of the restore given on command line
+struct RestoreArgs {
+    snapshot: PathBuf,
+    /// server configuration with storage to restore
+    config: String,
+    /// only check the snapshot without replacing anything
+    check: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    init_logging("warn");
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+    let config = ServerConfig::load(&args.config)?;
+    if args.check {
+        let check = check_snapshot(&args.snapshot, &config.storage).await?;
+        println!(
+            "Snapshot {} taken at {} is valid, it contains {} messages and {} attachments",
+            args.snapshot.display(),
+            check.manifest.created,
+            check.messages,
+            check.manifest.attachments.len()
+        );
+        return Ok(());
+    }
+    let report = restore_snapshot(
+        &args.snapshot,
+        &config.storage,
+        Path::new(TRANSFERS_FOLDER),
+        Path::new(SNAPSHOTS_FOLDER),
+    )
+    .await?;
+    println!("{report}");
+    return Ok(());
+}
+
+/// parse command line arguments without the name of the program
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<RestoreArgs> {
+    let Some(snapshot) = args.next() else {
+        bail!("Snapshot folder is missing");
+    };
+    let mut restore_args = RestoreArgs {
+        snapshot: PathBuf::from(snapshot),
+        config: SERVER_CONFIG_FILE.to_string(),
+        check: false,
+    };
+    while let Some(option) = args.next() {
+        if option == "--check" {
+            restore_args.check = true;
+            continue;
+        }
+        let Some(value) = args.next() else {
+            bail!("Value of {option} is missing");
+        };
+        match option.as_str() {
+            "--config" => restore_args.config = value,
+            _ => bail!("Unknown option {option}"),
+        }
+    }
+    return Ok(restore_args);
+}
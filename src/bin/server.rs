@@ -1,216 +1,1183 @@
 //! Server binary to host the clients
 #![warn(missing_docs)]
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+#![allow(clippy::needless_return)]
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::{Context, Result};
-use nanodb::nanodb::NanoDB;
 use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::{broadcast, RwLock},
+    fs,
+    net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+    sync::{broadcast, broadcast::error::RecvError, mpsc, RwLock},
+    task::JoinHandle,
+};
+use tracing::{debug, error, info, warn};
+
+use rust_15_async_chat::config::{ServerConfig, SERVER_CONFIG_FILE};
+use rust_15_async_chat::frame::{FrameError, FrameKind, FrameLimits};
+use rust_15_async_chat::history::{
+    history_batches, last_seen, save_last_seen, DEFAULT_HISTORY_SIZE, MAX_HISTORY_SIZE,
+};
+use rust_15_async_chat::logging::init_logging;
+use rust_15_async_chat::retention::prune;
+use rust_15_async_chat::rooms::{normalize_room_name, RoomMsg, RoomRegistry, DEFAULT_ROOM};
+use rust_15_async_chat::search::{search_results, SearchQuery};
+use rust_15_async_chat::snapshot::{take_snapshot, SNAPSHOTS_FOLDER};
+use rust_15_async_chat::storage::{open_storage, Storage};
+use rust_15_async_chat::transfer::{
+    is_valid_transfer_id, send_chunks, PartialFile, TransferError, TRANSFERS_FOLDER,
 };
+use rust_15_async_chat::{
+    async_chat_msg::{AsyncChatMsg, Destination, Envelope, ErrorCode},
+    register_user_in_db, validate_user_in_db, LoginStatus, RegisterStatus,
+};
+use rust_15_async_chat::{ensure_folder, sanitize_file_name, PORT};
+
+/// client is disconnected after this count of failed login or registration attempts
+const MAX_LOGIN_ATTEMPTS: u32 = 5;
+
+/// connected clients by user name, outbox of the client is used for direct messages
+type Clients = RwLock<HashMap<String, Outbox>>;
+type Rooms = RwLock<RoomRegistry>;
+
+/// count of download chunks waiting to be sent to one client, chunks are read from disk when there is space in the queue
+const DOWNLOAD_QUEUE_SIZE: usize = 4;
+/// count of chunks of files uploaded by other users waiting to be sent to one client
+const RELAY_QUEUE_SIZE: usize = 8;
+/// how long chunk waits for space in the relay queue, then the transfer is cancelled for the client
+const RELAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// messages for one connected client, replies of the server are addressed to the user of the client
+#[derive(Clone)]
+struct Outbox {
+    user: String,
+    queue: mpsc::UnboundedSender<Envelope>,
+    /// chunks of files sent to rooms, bounded, so slow client doesn't make the server keep whole files in memory
+    relay: mpsc::Sender<Envelope>,
+}
+
+impl Outbox {
+    /// send message of the server to the user
+    fn reply(&self, body: AsyncChatMsg) {
+        _ = self.queue.send(Envelope::from_server(&self.user, body));
+    }
+
+    /// send message of other user or message prepared by the server, returns false when the client is disconnected
+    fn forward(&self, msg: Envelope) -> bool {
+        return self.queue.send(msg).is_ok();
+    }
+}
 
-use rust_15_async_chat::PORT;
-use rust_15_async_chat::{async_chat_msg::AsyncChatMsg, validate_user_in_db};
+/// file transfer received from the client, data are stored to transfers folder and chunks are forwarded to the room the transfer was started in
+struct Upload {
+    start: Envelope,
+    sender: broadcast::Sender<RoomMsg>,
+    partial: PartialFile,
+}
+
+/// state shared by all client tasks
+struct ServerState {
+    clients: Clients,
+    rooms: Rooms,
+    /// messages, users and times when users were last online
+    storage: Arc<dyn Storage>,
+    config: ServerConfig,
+    /// FileStart messages of uploads interrupted by disconnect of the client, by transfer id
+    interrupted: RwLock<HashMap<String, Envelope>>,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    init_logging("info");
     let server = TcpListener::bind(format!("0.0.0.0:{PORT}"))
         .await
         .with_context(|| "Connecting to network address failed")?;
 
-    println!("AsyncChatServer is running");
-
-    let clients: Arc<RwLock<HashMap<String, SocketAddr>>> = Arc::new(RwLock::new(HashMap::new()));
+    info!("AsyncChatServer is running");
 
-    let (br_send, _br_recv) = broadcast::channel(1024);
     let mut waiting = true;
-    let chat_db = NanoDB::open("chatdb.json")
-        .unwrap_or_else(|e| panic!("Opening db file chatdb.json failed {}", e));
-    let users_db = NanoDB::open("userdb.json")
-        .unwrap_or_else(|e| panic!("Opening db file userdb.json failed {}", e));
+    let config = ServerConfig::load(SERVER_CONFIG_FILE)
+        .unwrap_or_else(|e| panic!("Loading server config failed {}", e));
+    info!("Registration policy is {:?}", config.registration);
+    let storage = open_storage(&config.storage, &config.durability)
+        .await
+        .unwrap_or_else(|e| panic!("Opening storage {:?} failed {}", config.storage, e));
+    info!("Storage is {:?}", config.storage);
+
+    let state = Arc::new(ServerState {
+        clients: RwLock::new(HashMap::new()),
+        rooms: RwLock::new(RoomRegistry::new()),
+        storage,
+        config,
+        interrupted: RwLock::new(HashMap::new()),
+    });
+    if let Err(e) = ensure_folder(TRANSFERS_FOLDER).await {
+        panic!("Creating folder {TRANSFERS_FOLDER} failed {e}");
+    }
+    let limits = state.config.frame_limits;
+    if state.config.retention.prune_interval_secs > 0 {
+        tokio::spawn(prune_periodically(state.clone()));
+    }
 
     // handle client
     'client: loop {
-        let Ok((stream, addr)) = server.accept().await else {
-            eprintln!("couldn't get client");
+        // stop accepting clients on ctrl+c, so waiting messages are written before exit
+        let accepted = tokio::select! {
+            accepted = server.accept() => accepted,
+            _ = tokio::signal::ctrl_c() => {
+                info!("Server interrupted, quit");
+                break;
+            }
+        };
+        let Ok((stream, addr)) = accepted else {
+            warn!("couldn't get client");
             continue;
         };
 
-        let client_count = clients.read().await.len();
+        let client_count = state.clients.read().await.len();
         if !waiting && client_count == 0 {
-            println!("No more clients, quit");
+            info!("No more clients, quit");
             break;
         }
         waiting = false;
 
-        let sender = br_send.clone();
-        let mut receiver = br_send.subscribe();
-
         let (mut stream_reader, mut stream_writer) = stream.into_split();
 
-        // validate user login, if failed, try again
+        // validate user login or registration, if failed, try again
+        let mut attempts = 0;
         let name = loop {
-            let Ok(AsyncChatMsg::Login(name, password)) =
-                AsyncChatMsg::receive(&mut stream_reader).await
-            else {
-                eprintln!("Login from the client not received");
-                continue 'client;
+            let auth_msg = match Envelope::receive_with_limits(&mut stream_reader, &limits).await {
+                Ok(
+                    msg @ Envelope {
+                        body: AsyncChatMsg::Login { .. } | AsyncChatMsg::Register { .. },
+                        ..
+                    },
+                ) => msg.body,
+                _ => {
+                    warn!("Login from the client not received");
+                    continue 'client;
+                }
             };
+            // replies before login are addressed to the name the client tried
+            let login = auth_msg.get_text().to_string();
 
-            // validate password against DB
-            match validate_user_in_db(&name, &password, users_db.clone()).await {
-                Ok(false) => {
-                    let wrong_pass_msg = AsyncChatMsg::create_text(
-                        "Server".into(),
-                        format!("ERROR: Incorrect password for login {name}"),
-                    )
-                    .unwrap();
-                    if let Err(e) = wrong_pass_msg.send(&mut stream_writer).await {
-                        eprintln!("Sending wrong password failed with error {e}");
+            // validate password or register user against DB
+            let name = match authenticate(auth_msg, state.storage.as_ref(), &state.config).await {
+                Ok(name) => name,
+                Err((code, reason)) => {
+                    attempts += 1;
+                    let (code, reason) = if attempts >= MAX_LOGIN_ATTEMPTS {
+                        (
+                            ErrorCode::RateLimited,
+                            format!("{reason}, too many failed attempts, disconnecting"),
+                        )
+                    } else {
+                        (code, reason)
+                    };
+                    let auth_failed_msg =
+                        Envelope::from_server(&login, AsyncChatMsg::Error { code, text: reason });
+                    if let Err(e) = auth_failed_msg
+                        .send_with_limits(&mut stream_writer, &limits)
+                        .await
+                    {
+                        error!("Sending login error failed with error {e}");
+                    }
+                    if code == ErrorCode::RateLimited {
+                        warn!(%addr, "Too many failed login attempts, client disconnected");
+                        continue 'client;
                     }
                     continue;
                 }
-                Ok(true) => (),
-                Err(error) => {
-                    eprintln!("Validation of user {name} failed with error: {error}");
-                    continue;
-                }
-            }
+            };
 
             // check for duplicity name of user
-            if clients.read().await.contains_key(&name) {
-                let name_used_msg = AsyncChatMsg::create_text(
-                    "Server".into(),
-                    format!("ERROR: User {name} is already logged in, please choose another or disconnect from existing session"),
-                )
-                .unwrap();
-                if let Err(e) = name_used_msg.send(&mut stream_writer).await {
-                    eprintln!("Sending existing name warning failed with error {e}");
+            if state.clients.read().await.contains_key(&name) {
+                let name_used_msg = Envelope::from_server(&name, AsyncChatMsg::Error {
+                    code: ErrorCode::AlreadyLoggedIn,
+                    text: format!("User {name} is already logged in, please choose another or disconnect from existing session"),
+                });
+                if let Err(e) = name_used_msg
+                    .send_with_limits(&mut stream_writer, &limits)
+                    .await
+                {
+                    error!("Sending existing name warning failed with error {e}");
                 }
                 continue;
             } else {
-                let welcome_msg = AsyncChatMsg::create_text(
-                    "Server".into(),
-                    format!("{name}, welcome on the AsyncChatServer!"),
-                )
-                .unwrap();
-                if let Err(e) = welcome_msg.send(&mut stream_writer).await {
-                    eprintln!("Sending welcome message failed with error {e}");
+                let welcome_msg = Envelope::from_server(
+                    &name,
+                    AsyncChatMsg::Success {
+                        text: format!("{name}, welcome on the AsyncChatServer!"),
+                    },
+                );
+                if let Err(e) = welcome_msg
+                    .send_with_limits(&mut stream_writer, &limits)
+                    .await
+                {
+                    error!("Sending welcome message failed with error {e}");
                 }
                 break name;
             }
         };
-        println!("User {name} has connected");
-
-        // _ = sender.send((
-        //     AsyncChatMsg::create_text("Server".to_string(), format!("User {name} has connected"))
-        //         .unwrap(),
-        //     SocketAddr::from_str(&format!("127.0.0.1:{PORT}")).unwrap(),
-        // ));
-
-        clients.write().await.insert(name.clone(), addr);
-        let clients_copy = clients.clone();
-
-        tokio::spawn({
-            let db = chat_db.clone();
-            async move {
-                loop {
-                    let message = AsyncChatMsg::receive(&mut stream_reader).await;
-                    match message {
-                        Ok(ref msg @ AsyncChatMsg::Text(ref _from, ref text)) => {
-                            println!("{msg}");
-                            if text == ".quit" {
-                                // if last client disconnected, then send quit ping to self to break the loops
-                                clients_copy.write().await.remove_entry(&name);
-                                if clients_copy.read().await.len() == 0 {
-                                    let _ = send_quit_ping() //sender, &addr, name
-                                        .await
-                                        .with_context(|| "Sending disconnect message failed (1)");
-                                }
-                            }
-                        }
-                        Ok(ref msg @ AsyncChatMsg::Image(ref _from, ref _text, ref _data)) => {
-                            println!("{msg}");
-                        }
-                        Ok(ref msg @ AsyncChatMsg::File(ref _from, ref _text, ref _data)) => {
-                            println!("{msg}");
-                        }
-                        Err(e) => {
-                            eprintln!("error receiving message from client: {e}");
-                            //let name = clients_copy.read().await.get(&addr).unwrap().clone();
-                            clients_copy.write().await.remove_entry(&name);
-                            if clients_copy.read().await.len() == 0 {
-                                let _ = send_quit_ping() //sender, &addr, name
-                                    .await
-                                    .with_context(|| "Sending disconnect message failed (1)");
-                            }
-                            break;
-                        }
-                        _ => (),
-                    };
-                    let message = message.unwrap();
-                    // send quit message with disconnect info for everyone
-                    if sender.send((message.clone(), addr)).is_err() {
-                        eprintln!("Sending message to broadcast failed");
-                    }
-                    if let Err(e) = message.save_to_db(db.clone()).await {
-                        eprintln!("Saving msg to db failed with error: {e}");
-                    }
-                    if message.get_text() == ".quit" {
-                        break;
+        info!(user = name, %addr, "User {name} has connected");
+
+        // every message for this client goes through outbox, so room forwarders and server replies don't fight for the stream
+        let (queue, mut outbox_recv) = mpsc::unbounded_channel::<Envelope>();
+        let (relay, mut relay_recv) = mpsc::channel::<Envelope>(RELAY_QUEUE_SIZE);
+        let outbox = Outbox {
+            user: name.clone(),
+            queue,
+            relay,
+        };
+        // stored files requested by the client are sent through bounded queue, so they are not read to memory at once
+        let (download_queue, mut download_recv) = mpsc::channel::<Envelope>(DOWNLOAD_QUEUE_SIZE);
+        state
+            .clients
+            .write()
+            .await
+            .insert(name.clone(), outbox.clone());
+
+        // let others know that user has connected and send current roster to the new user
+        let user = name.clone();
+        broadcast_presence(&state.clients, AsyncChatMsg::UserJoined { user }, &name).await;
+        let users = online_users(&state.clients).await;
+        outbox.reply(AsyncChatMsg::UserList { users });
+
+        tokio::spawn(handle_client(
+            name,
+            addr,
+            stream_reader,
+            outbox,
+            download_queue,
+            state.clone(),
+        ));
+
+        // handle sending messages to the client, task ends when client task, room forwarders and downloads are finished
+        tokio::spawn(async move {
+            loop {
+                // chat messages have priority, chunks of transfers are sent when there is no other message waiting
+                let msg = tokio::select! {
+                    biased;
+                    Some(msg) = outbox_recv.recv() => msg,
+                    Some(msg) = relay_recv.recv() => msg,
+                    Some(msg) = download_recv.recv() => msg,
+                    else => break,
+                };
+                // client accepts only frames within the limits, so bigger message is dropped instead of breaking the connection
+                if let Err(e) = msg.send_with_limits(&mut stream_writer, &limits).await {
+                    if let Some(frame_error) = e.downcast_ref::<FrameError>() {
+                        error!("Message {} was not sent: {frame_error}", msg.id);
+                        continue;
                     }
+                    error!("error sending message to client with error: {e}");
+                    break;
                 }
             }
         });
+    }
 
-        // handle sending broadcast messages
-        tokio::spawn(async move {
-            while let Ok((msg, other_addr)) = receiver.recv().await {
-                match msg.clone() {
-                    AsyncChatMsg::Text(from, text) => {
-                        if text == ".quit" {
-                            let bye_msg = AsyncChatMsg::Text(
-                                "Server".to_string(),
-                                format!("User {from} has disconnected"),
-                            );
-                            let _ = bye_msg
-                                .send(&mut stream_writer)
-                                .await
-                                .with_context(|| "Sending disconnect message failed (2)");
-                            // if current client sent quit message, break the while and exit the thread
-                            if other_addr == addr {
-                                break;
-                            }
-                        } else {
-                            if other_addr != addr {
-                                if let Err(e) = msg.send(&mut stream_writer).await {
-                                    eprintln!("error sending broadcast message with error: {e}");
-                                    break;
-                                }
-                            }
-                        }
-                        // if this is quit ping message from server, break the loop and close server
-                        if from == "Server" {
-                            break;
-                        }
+    //tokio::join!(_client_handle, _broadcast_handle); //(client_handle, broadcast_handle);
+
+    // messages still waiting in memory are written before server stops
+    if let Err(e) = state.storage.flush().await {
+        error!("Writing messages to storage failed with error: {e}");
+    }
+
+    return Ok(());
+}
+
+/// remove messages over retention limits when server starts and then every prune interval
+async fn prune_periodically(state: Arc<ServerState>) {
+    let interval = Duration::from_secs(state.config.retention.prune_interval_secs);
+    let mut timer = tokio::time::interval(interval);
+    loop {
+        timer.tick().await;
+        let retention = &state.config.retention;
+        match prune(
+            state.storage.as_ref(),
+            retention,
+            Path::new(TRANSFERS_FOLDER),
+        )
+        .await
+        {
+            Ok(report) if !report.is_empty() => info!("{report}"),
+            Ok(_) => {}
+            Err(e) => error!("Pruning messages failed with error: {e}"),
+        }
+    }
+}
+
+/// validate login or register new user, returns name of the user or error code with reason why it failed
+async fn authenticate(
+    msg: AsyncChatMsg,
+    storage: &dyn Storage,
+    config: &ServerConfig,
+) -> Result<String, (ErrorCode, String)> {
+    match msg {
+        AsyncChatMsg::Login {
+            login: name,
+            password,
+        } => match validate_user_in_db(&name, password.expose(), storage).await {
+            Ok(LoginStatus::Valid) => return Ok(name),
+            Ok(LoginStatus::WrongPassword) => {
+                return Err((
+                    ErrorCode::BadPassword,
+                    format!("Incorrect password for login {name}"),
+                ))
+            }
+            Ok(LoginStatus::UnknownUser) => {
+                return Err((
+                    ErrorCode::UnknownUser,
+                    format!("Unknown user {name}, register first with .register <name> <password>"),
+                ))
+            }
+            Err(error) => {
+                error!("Validation of user {name} failed with error: {error}");
+                return Err((
+                    ErrorCode::Internal,
+                    format!("Validation of user {name} failed"),
+                ));
+            }
+        },
+        AsyncChatMsg::Register {
+            login: name,
+            password,
+            invite,
+        } => {
+            if let Err(code) = config.registration_allowed(invite.as_deref()) {
+                return Err((code, code.to_string()));
+            }
+            match register_user_in_db(&name, password.expose(), storage).await {
+                Ok(RegisterStatus::Registered) => return Ok(name),
+                Ok(RegisterStatus::AlreadyExists) => {
+                    return Err((ErrorCode::UserExists, format!("User {name} already exists")))
+                }
+                Err(error) => {
+                    error!("Registration of user {name} failed with error: {error}");
+                    return Err((
+                        ErrorCode::Internal,
+                        format!("Registration of user {name} failed"),
+                    ));
+                }
+            }
+        }
+        _ => {
+            return Err((
+                ErrorCode::InvalidRequest,
+                "Login or registration expected".to_string(),
+            ))
+        }
+    }
+}
+
+/// receive messages from the client and route them to the rooms until client logs out or disconnects
+async fn handle_client(
+    name: String,
+    addr: SocketAddr,
+    mut stream_reader: OwnedReadHalf,
+    outbox: Outbox,
+    download_queue: mpsc::Sender<Envelope>,
+    state: Arc<ServerState>,
+) {
+    let ServerState {
+        clients,
+        rooms,
+        storage,
+        config,
+        ..
+    } = state.as_ref();
+    let db = storage.as_ref();
+    // forwarding tasks of the rooms this client is member of
+    let mut joined: HashMap<String, JoinHandle<()>> = HashMap::new();
+    // file transfers in progress by transfer id
+    let mut uploads: HashMap<String, Upload> = HashMap::new();
+    join_room(DEFAULT_ROOM, &name, addr, &outbox, rooms, &mut joined).await;
+    // user who was online before gets everything since then, others get only last messages
+    let since = last_seen(db, &name).await.unwrap_or_else(|e| {
+        error!("Loading last seen time of user {name} failed with error: {e}");
+        None
+    });
+    let count = match since {
+        Some(_) => MAX_HISTORY_SIZE,
+        None => DEFAULT_HISTORY_SIZE,
+    };
+    let limits = &config.frame_limits;
+    send_history(
+        db,
+        &name,
+        DEFAULT_ROOM,
+        since.as_deref(),
+        count,
+        limits,
+        &outbox,
+    )
+    .await;
+
+    loop {
+        let msg =
+            match Envelope::receive_with_limits(&mut stream_reader, &config.frame_limits).await {
+                Ok(msg) => msg,
+                Err(e) => {
+                    // stream is out of sync after frame error, so client is told why and disconnected
+                    if let Some(frame_error) = e.downcast_ref::<FrameError>() {
+                        reply_error(
+                            &outbox,
+                            frame_error_code(frame_error),
+                            frame_error.to_string(),
+                        );
+                    }
+                    warn!(user = name, "error receiving message from client: {e}");
+                    break;
+                }
+            };
+        // sender, id and time are set by the server, so nobody can send message in the name of other user
+        let msg = msg.stamped(&name);
+        // stamped message has to fit to the limits of receiving clients, chunks are checked when they are relayed
+        if !matches!(msg.body, AsyncChatMsg::FileChunk { .. }) {
+            if let Err(e) = msg.check_limits(limits) {
+                let code = e
+                    .downcast_ref::<FrameError>()
+                    .map_or(ErrorCode::InvalidRequest, frame_error_code);
+                reply_error(&outbox, code, e.to_string());
+                continue;
+            }
+        }
+        match &msg.body {
+            AsyncChatMsg::Join { room } => match normalize_room_name(room) {
+                Ok(room) => {
+                    join_room(&room, &name, addr, &outbox, rooms, &mut joined).await;
+                    reply_success(&outbox, format!("You have joined room {room}"));
+                    send_history(
+                        db,
+                        &name,
+                        &room,
+                        None,
+                        DEFAULT_HISTORY_SIZE,
+                        limits,
+                        &outbox,
+                    )
+                    .await;
+                }
+                Err(e) => reply_error(&outbox, ErrorCode::InvalidRoomName, e.to_string()),
+            },
+            AsyncChatMsg::Leave { room } => {
+                let room = normalize_room_name(room).unwrap_or(room.clone());
+                if room == DEFAULT_ROOM {
+                    reply_error(
+                        &outbox,
+                        ErrorCode::InvalidRequest,
+                        format!("Room {DEFAULT_ROOM} cannot be left"),
+                    );
+                } else if let Some(forwarder) = joined.remove(&room) {
+                    forwarder.abort();
+                    rooms.write().await.leave(&room, &name);
+                    reply_success(&outbox, format!("You have left room {room}"));
+                } else if rooms.read().await.sender(&room).is_none() {
+                    reply_error(
+                        &outbox,
+                        ErrorCode::UnknownRoom,
+                        format!("Room {room} doesn't exist"),
+                    );
+                } else {
+                    reply_error(
+                        &outbox,
+                        ErrorCode::NotRoomMember,
+                        format!("You are not member of room {room}"),
+                    );
+                }
+            }
+            AsyncChatMsg::Logout => {
+                reply_success(&outbox, format!("Bye {name}"));
+                break;
+            }
+            AsyncChatMsg::Who => {
+                let users = online_users(clients).await;
+                outbox.reply(AsyncChatMsg::UserList { users });
+            }
+            AsyncChatMsg::GetHistory { room, count } => {
+                let room = normalize_room_name(room).unwrap_or(room.clone());
+                if !joined.contains_key(&room) {
+                    reply_error(
+                        &outbox,
+                        ErrorCode::NotRoomMember,
+                        format!("You are not member of room {room}"),
+                    );
+                    continue;
+                }
+                let count = (*count).min(MAX_HISTORY_SIZE);
+                if !send_history(db, &name, &room, None, count, limits, &outbox).await {
+                    reply_success(&outbox, format!("There are no messages in room {room}"));
+                }
+            }
+            AsyncChatMsg::Search { query } => {
+                let query = match SearchQuery::parse(query) {
+                    Ok(query) => query,
+                    Err(e) => {
+                        reply_error(&outbox, ErrorCode::InvalidRequest, e.to_string());
+                        continue;
+                    }
+                };
+                match db.search(&query).await {
+                    Ok(hits) if hits.is_empty() => {
+                        reply_success(&outbox, format!("No messages found for {query}"));
+                    }
+                    Ok(hits) => outbox.reply(search_results(&query, hits)),
+                    Err(e) => {
+                        error!(user = name, "Searching messages failed with error: {e}");
+                        reply_error(&outbox, ErrorCode::Internal, "Search failed".to_string());
+                    }
+                }
+            }
+            AsyncChatMsg::Prune => {
+                if !state.config.is_admin(&name) {
+                    reply_error(
+                        &outbox,
+                        ErrorCode::NotAdmin,
+                        "Only administrators can prune messages".to_string(),
+                    );
+                    continue;
+                }
+                match prune(db, &state.config.retention, Path::new(TRANSFERS_FOLDER)).await {
+                    Ok(report) => {
+                        info!(user = name, "{report}");
+                        reply_success(&outbox, report.to_string());
+                    }
+                    Err(e) => {
+                        error!(user = name, "Pruning messages failed with error: {e}");
+                        reply_error(&outbox, ErrorCode::Internal, "Prune failed".to_string());
+                    }
+                }
+            }
+            AsyncChatMsg::Snapshot => {
+                if !state.config.is_admin(&name) {
+                    reply_error(
+                        &outbox,
+                        ErrorCode::NotAdmin,
+                        "Only administrators can take snapshots".to_string(),
+                    );
+                    continue;
+                }
+                let transfers = Path::new(TRANSFERS_FOLDER);
+                match take_snapshot(db, transfers, Path::new(SNAPSHOTS_FOLDER)).await {
+                    Ok(report) => {
+                        info!(user = name, "{report}");
+                        reply_success(&outbox, report.to_string());
+                    }
+                    Err(e) => {
+                        error!(user = name, "Taking snapshot failed with error: {e:#}");
+                        reply_error(&outbox, ErrorCode::Internal, "Snapshot failed".to_string());
                     }
-                    // broadcast other types of messages to everyone except my self
-                    _ => {
-                        if other_addr != addr {
-                            if let Err(e) = msg.send(&mut stream_writer).await {
-                                eprintln!("error sending broadcast message with error: {e}");
-                                break;
-                            }
-                        }
+                }
+            }
+            AsyncChatMsg::ListRooms => {
+                let rooms = rooms.read().await.list();
+                outbox.reply(AsyncChatMsg::RoomList { rooms });
+            }
+            AsyncChatMsg::FileStart { .. } => {
+                start_upload(msg, &name, addr, &outbox, &state, &mut uploads).await;
+            }
+            AsyncChatMsg::FileChunk {
+                transfer,
+                offset,
+                data,
+            } => {
+                let Some(upload) = uploads.get_mut(transfer) else {
+                    debug!(user = name, "Chunk of unknown transfer {transfer} ignored");
+                    continue;
+                };
+                if let Err(e) = upload.partial.write_chunk(*offset, data).await {
+                    if let Some(upload) = uploads.remove(transfer) {
+                        cancel_upload(upload, transfer, addr, &outbox, e).await;
                     }
+                    continue;
                 }
+                // chunks go to the room of the upload whatever destination the client has sent
+                let chunk = upload.start.follow_up(msg.body.clone());
+                if let Err(e) = chunk.check_limits(limits) {
+                    if let Some(upload) = uploads.remove(transfer) {
+                        cancel_upload(upload, transfer, addr, &outbox, e).await;
+                    }
+                    continue;
+                }
+                _ = upload.sender.send((chunk, addr));
+            }
+            AsyncChatMsg::FileEnd { transfer } => match uploads.remove(transfer) {
+                Some(upload) => finish_upload(upload, transfer, addr, &outbox, db).await,
+                None => debug!(user = name, "End of unknown transfer {transfer} ignored"),
+            },
+            AsyncChatMsg::ResumeUpload { transfer } => {
+                resume_upload(transfer, &name, addr, &outbox, &state, &mut uploads).await;
             }
+            AsyncChatMsg::ResumeDownload { transfer, offset } => {
+                resume_download(transfer, *offset, &name, &outbox, &download_queue).await;
+            }
+            AsyncChatMsg::Text { .. } => match &msg.to {
+                Destination::Room(room) => {
+                    let room = room.clone();
+                    send_to_room(msg, &room, &name, addr, &outbox, rooms, db).await;
+                }
+                Destination::User(to) => {
+                    let to = to.clone();
+                    send_direct(msg, &to, &outbox, clients, db).await;
+                }
+                Destination::Server => reply_error(
+                    &outbox,
+                    ErrorCode::InvalidRequest,
+                    "Text message has to be sent to a room or a user".to_string(),
+                ),
+            },
+            _ => {}
+        }
+    }
+
+    // disconnect the client, stop forwarding and let others know that user has left
+    for (_, forwarder) in joined.drain() {
+        forwarder.abort();
+    }
+    // unfinished uploads are kept, so the client can resume them after reconnect
+    for (id, upload) in uploads.drain() {
+        info!(
+            user = name,
+            "Upload {id} was interrupted after {}B",
+            upload.partial.written()
+        );
+        state.interrupted.write().await.insert(id, upload.start);
+    }
+    rooms.write().await.leave_all(&name);
+    clients.write().await.remove_entry(&name);
+    if let Err(e) = save_last_seen(db, &name).await {
+        error!("Saving last seen time of user {name} failed with error: {e}");
+    }
+    let user = name.clone();
+    broadcast_presence(clients, AsyncChatMsg::UserLeft { user }, &name).await;
+    info!(user = name, "User {name} has disconnected");
+
+    // if last client disconnected, then send quit ping to self to break the loops
+    if clients.read().await.is_empty() {
+        let _ = send_quit_ping()
+            .await
+            .with_context(|| "Sending disconnect message failed (1)");
+    }
+}
+
+/// send message to members of the room and save it to db, sender has to be member of the room
+async fn send_to_room(
+    msg: Envelope,
+    room: &str,
+    name: &str,
+    addr: SocketAddr,
+    outbox: &Outbox,
+    rooms: &Rooms,
+    db: &dyn Storage,
+) {
+    info!("{msg}");
+    let sender = match room_sender(rooms, room, name).await {
+        Ok(sender) => sender,
+        Err((code, reason)) => return reply_error(outbox, code, reason),
+    };
+    if let Err(e) = msg.save_to_db(db).await {
+        error!("Saving msg to db failed with error: {e}");
+    }
+    if sender.send((msg, addr)).is_err() {
+        debug!("Sending message to room {room} failed, no one is listening");
+    }
+}
+
+/// deliver private message only to the recipient and save it to db, recipient has to be online
+async fn send_direct(
+    msg: Envelope,
+    to: &str,
+    outbox: &Outbox,
+    clients: &Clients,
+    db: &dyn Storage,
+) {
+    let recipient = clients.read().await.get(to).cloned();
+    let Some(recipient) = recipient else {
+        return reply_error(
+            outbox,
+            ErrorCode::UserOffline,
+            format!("User {to} is not online, message was not delivered"),
+        );
+    };
+    recipient.forward(msg.clone());
+    if let Err(e) = msg.save_to_db(db).await {
+        error!("Saving msg to db failed with error: {e}");
+    }
+}
+
+/// start receiving of the file from the client, transfer is refused when the file is too big or user is not member of the room
+async fn start_upload(
+    start: Envelope,
+    name: &str,
+    addr: SocketAddr,
+    outbox: &Outbox,
+    state: &ServerState,
+    uploads: &mut HashMap<String, Upload>,
+) {
+    let AsyncChatMsg::FileStart {
+        transfer: id,
+        filename,
+        size,
+        kind,
+        sha256,
+    } = start.body.clone()
+    else {
+        return;
+    };
+    let refuse = |code: ErrorCode, reason: String| {
+        outbox.reply(AsyncChatMsg::TransferCancelled {
+            transfer: id.clone(),
+            code,
+            reason,
         });
+    };
+    if !is_valid_transfer_id(&id) {
+        return refuse(ErrorCode::InvalidRequest, "Invalid transfer id".to_string());
     }
+    let Some(room) = start.room() else {
+        return refuse(
+            ErrorCode::InvalidRequest,
+            "Files can be sent only to a room".to_string(),
+        );
+    };
+    if size > state.config.max_file_size {
+        return refuse(
+            ErrorCode::FileTooLarge,
+            format!(
+                "File {filename} has {size}B, which exceeds limit {}B",
+                state.config.max_file_size
+            ),
+        );
+    }
+    let path = transfer_path(&id);
+    if uploads.contains_key(&id)
+        || state.interrupted.read().await.contains_key(&id)
+        || path.exists()
+    {
+        return refuse(
+            ErrorCode::InvalidRequest,
+            format!("Transfer {id} already exists"),
+        );
+    }
+    let sender = match room_sender(&state.rooms, room, name).await {
+        Ok(sender) => sender,
+        Err((code, reason)) => return refuse(code, reason),
+    };
+    let partial = match PartialFile::create(path, &id, size, &sha256).await {
+        Ok(partial) => partial,
+        Err(e) => {
+            error!("Creating file for transfer {id} failed with error: {e}");
+            return refuse(ErrorCode::Internal, "Storing file failed".to_string());
+        }
+    };
 
-    //tokio::join!(_client_handle, _broadcast_handle); //(client_handle, broadcast_handle);
+    // file name comes from the client, so it is sanitized before it is sent to others
+    let start = Envelope {
+        body: AsyncChatMsg::FileStart {
+            transfer: id.clone(),
+            filename: sanitize_file_name(&filename),
+            size,
+            kind,
+            sha256,
+        },
+        ..start
+    };
+    info!("{start}");
+    if sender.send((start.clone(), addr)).is_err() {
+        debug!("Sending file to room failed, no one is listening");
+    }
+    uploads.insert(
+        id,
+        Upload {
+            start,
+            sender,
+            partial,
+        },
+    );
+}
 
-    return Ok(());
+/// move received file to transfers folder, save it to db and let the room and the sender know that transfer is complete
+async fn finish_upload(
+    upload: Upload,
+    id: &str,
+    addr: SocketAddr,
+    outbox: &Outbox,
+    db: &dyn Storage,
+) {
+    let Upload {
+        start,
+        sender,
+        partial,
+    } = upload;
+    let size = partial.size();
+    if let Err(e) = partial.finish().await {
+        warn!("Transfer {id} failed with error: {e}");
+        let cancelled = AsyncChatMsg::TransferCancelled {
+            transfer: id.to_string(),
+            code: transfer_error_code(&e),
+            reason: e.to_string(),
+        };
+        outbox.reply(cancelled.clone());
+        _ = sender.send((start.follow_up(cancelled), addr));
+        return;
+    }
+    if let Err(e) = start.save_to_db(db).await {
+        error!("Saving msg to db failed with error: {e}");
+    }
+    let transfer = id.to_string();
+    _ = sender.send((start.follow_up(AsyncChatMsg::FileEnd { transfer }), addr));
+    outbox.reply(AsyncChatMsg::TransferOffset {
+        transfer: id.to_string(),
+        offset: size,
+    });
+}
+
+/// stop the upload, received data are removed and room members are told to remove their partial files
+async fn cancel_upload(
+    upload: Upload,
+    id: &str,
+    addr: SocketAddr,
+    outbox: &Outbox,
+    error: anyhow::Error,
+) {
+    upload.partial.remove().await;
+    let cancelled = AsyncChatMsg::TransferCancelled {
+        transfer: id.to_string(),
+        code: transfer_error_code(&error),
+        reason: error.to_string(),
+    };
+    outbox.reply(cancelled.clone());
+    _ = upload
+        .sender
+        .send((upload.start.follow_up(cancelled), addr));
+}
+
+/// tell the client where interrupted upload stopped, finished uploads report whole size and unknown uploads start again from 0
+async fn resume_upload(
+    id: &str,
+    name: &str,
+    addr: SocketAddr,
+    outbox: &Outbox,
+    state: &ServerState,
+    uploads: &mut HashMap<String, Upload>,
+) {
+    let offset_reply = |offset| {
+        outbox.reply(AsyncChatMsg::TransferOffset {
+            transfer: id.to_string(),
+            offset,
+        });
+    };
+    let cancel = |code, reason| {
+        outbox.reply(AsyncChatMsg::TransferCancelled {
+            transfer: id.to_string(),
+            code,
+            reason,
+        });
+    };
+    if !is_valid_transfer_id(id) {
+        return cancel(ErrorCode::InvalidRequest, "Invalid transfer id".to_string());
+    }
+    let path = transfer_path(id);
+    if let Ok(metadata) = fs::metadata(&path).await {
+        return offset_reply(metadata.len());
+    }
+    let start = {
+        let mut interrupted = state.interrupted.write().await;
+        match interrupted.get(id) {
+            // only the user who started the upload can continue it
+            Some(start) if start.from == name => interrupted.remove(id),
+            _ => None,
+        }
+    };
+    let Some(start) = start else {
+        return offset_reply(0);
+    };
+    let (Some(room), AsyncChatMsg::FileStart { size, sha256, .. }) = (start.room(), &start.body)
+    else {
+        return;
+    };
+    let size = *size;
+    let resumed = match room_sender(&state.rooms, room, name).await {
+        Ok(sender) => match PartialFile::resume(path, id, size, sha256).await {
+            Ok(partial) => Ok((sender, partial)),
+            Err(e) => Err((ErrorCode::Internal, e.to_string())),
+        },
+        Err(error) => Err(error),
+    };
+    let (sender, partial) = match resumed {
+        Ok(resumed) => resumed,
+        Err((code, reason)) => {
+            _ = fs::remove_file(PartialFile::temp_path(&transfer_path(id), id)).await;
+            return cancel(code, reason);
+        }
+    };
+    let offset = partial.written();
+    info!(user = name, "Upload {id} resumed from {offset}B");
+    let upload = Upload {
+        start,
+        sender,
+        partial,
+    };
+    if offset == size {
+        // all data were received before the connection dropped, only end of the transfer is missing
+        finish_upload(upload, id, addr, outbox, state.storage.as_ref()).await;
+        return;
+    }
+    uploads.insert(id.to_string(), upload);
+    offset_reply(offset);
+}
+
+/// send the rest of stored file to the client, starting from the offset
+async fn resume_download(
+    id: &str,
+    offset: u64,
+    name: &str,
+    outbox: &Outbox,
+    download_queue: &mpsc::Sender<Envelope>,
+) {
+    let cancel = |code, reason| {
+        outbox.reply(AsyncChatMsg::TransferCancelled {
+            transfer: id.to_string(),
+            code,
+            reason,
+        });
+    };
+    let path = transfer_path(id);
+    let metadata = match fs::metadata(&path).await {
+        _ if !is_valid_transfer_id(id) => {
+            reply_error(
+                outbox,
+                ErrorCode::InvalidRequest,
+                "Invalid transfer id".to_string(),
+            );
+            return;
+        }
+        Ok(metadata) => metadata,
+        // upload is still in progress or was interrupted and can be resumed by its sender
+        Err(_) if PartialFile::temp_path(&path, id).exists() => {
+            reply_error(
+                outbox,
+                ErrorCode::UnknownTransfer,
+                format!("Transfer {id} is not finished yet, try again later"),
+            );
+            return;
+        }
+        _ => {
+            return cancel(
+                ErrorCode::UnknownTransfer,
+                format!("Transfer {id} is not available"),
+            );
+        }
+    };
+    if offset > metadata.len() {
+        return cancel(
+            ErrorCode::InvalidRequest,
+            format!("Offset {offset} is beyond end of transfer {id}"),
+        );
+    }
+    info!(user = name, "Sending transfer {id} from {offset}B");
+    let id = id.to_string();
+    let user = name.to_string();
+    let download_queue = download_queue.clone();
+    tokio::spawn(async move {
+        let envelope = |body| Envelope::from_server(&user, body);
+        let size = metadata.len();
+        if let Err(e) = send_chunks(&path, &id, offset, size, envelope, &download_queue).await {
+            warn!("Sending transfer {id} failed with error: {e}");
+        }
+    });
+}
+
+/// machine readable code for error of the transfer
+fn transfer_error_code(error: &anyhow::Error) -> ErrorCode {
+    if let Some(frame_error) = error.downcast_ref::<FrameError>() {
+        return frame_error_code(frame_error);
+    }
+    return match error.downcast_ref::<TransferError>() {
+        Some(TransferError::ChecksumMismatch { .. }) => ErrorCode::ChecksumMismatch,
+        Some(_) => ErrorCode::InvalidRequest,
+        None => ErrorCode::Internal,
+    };
+}
+
+/// error code sent to the client for message which doesn't fit to the frame limits
+fn frame_error_code(error: &FrameError) -> ErrorCode {
+    return match error {
+        FrameError::Oversized {
+            kind: FrameKind::File,
+            ..
+        } => ErrorCode::FileTooLarge,
+        _ => ErrorCode::InvalidRequest,
+    };
+}
+
+/// path of the file stored for the transfer
+fn transfer_path(id: &str) -> PathBuf {
+    return Path::new(TRANSFERS_FOLDER).join(id);
+}
+
+/// get broadcast sender of the room, user has to be member of the room to send messages to it
+async fn room_sender(
+    rooms: &Rooms,
+    room: &str,
+    name: &str,
+) -> Result<broadcast::Sender<RoomMsg>, (ErrorCode, String)> {
+    let rooms = rooms.read().await;
+    return match rooms.sender(room) {
+        None => Err((ErrorCode::UnknownRoom, format!("Room {room} doesn't exist"))),
+        Some(_) if !rooms.is_member(room, name) => Err((
+            ErrorCode::NotRoomMember,
+            format!("You are not member of room {room}, use .join {room} first"),
+        )),
+        Some(sender) => Ok(sender),
+    };
+}
+
+/// add user to the room and start forwarding of room messages to the client outbox
+async fn join_room(
+    room: &str,
+    name: &str,
+    addr: SocketAddr,
+    outbox: &Outbox,
+    rooms: &Rooms,
+    joined: &mut HashMap<String, JoinHandle<()>>,
+) {
+    if joined.contains_key(room) {
+        return;
+    }
+    let receiver = rooms.write().await.join(room, name);
+    let forwarder = tokio::spawn(forward_room(receiver, outbox.clone(), addr));
+    joined.insert(room.to_string(), forwarder);
+}
+
+/// send last messages of the room to the client in history batches, which fit to the frame limit
+/// returns false if there is no message to send
+async fn send_history(
+    db: &dyn Storage,
+    name: &str,
+    room: &str,
+    since: Option<&str>,
+    count: usize,
+    limits: &FrameLimits,
+    outbox: &Outbox,
+) -> bool {
+    let history = match db.history(name, room, since, count).await {
+        Ok(history) => history,
+        Err(e) => {
+            error!("Loading history of room {room} failed with error: {e}");
+            return false;
+        }
+    };
+    let batches = history_batches(name, room, history, limits.max_message_size);
+    let sent = !batches.is_empty();
+    for batch in batches {
+        outbox.forward(batch);
+    }
+    return sent;
+}
+
+/// forward messages of one room to the client, messages sent by the client itself are skipped
+async fn forward_room(
+    mut receiver: broadcast::Receiver<RoomMsg>,
+    outbox: Outbox,
+    addr: SocketAddr,
+) {
+    // transfers cancelled for this client, because it didn't receive their chunks fast enough
+    let mut dropped: HashSet<String> = HashSet::new();
+    loop {
+        let (msg, other_addr) = match receiver.recv().await {
+            Ok(room_msg) => room_msg,
+            Err(RecvError::Lagged(count)) => {
+                warn!("Client {addr} is too slow, {count} messages were skipped");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if other_addr == addr {
+            continue;
+        }
+        let delivered = match &msg.body {
+            AsyncChatMsg::FileChunk { transfer, .. }
+            | AsyncChatMsg::FileEnd { transfer }
+            | AsyncChatMsg::TransferCancelled { transfer, .. } => {
+                relay_transfer(msg.clone(), transfer, &outbox, &mut dropped).await
+            }
+            _ => outbox.forward(msg),
+        };
+        if !delivered {
+            break;
+        }
+    }
+}
+
+/// send message of file transfer through bounded relay queue, so it stays in order with the chunks,
+/// when the queue stays full the transfer is cancelled for the client instead of buffering its chunks,
+/// returns false when the client is disconnected
+async fn relay_transfer(
+    msg: Envelope,
+    transfer: &str,
+    outbox: &Outbox,
+    dropped: &mut HashSet<String>,
+) -> bool {
+    if dropped.contains(transfer) {
+        return true;
+    }
+    let AsyncChatMsg::FileChunk { .. } = msg.body else {
+        // end of the transfer is small and has to follow its chunks, so it waits for space in the queue
+        return outbox.relay.send(msg).await.is_ok();
+    };
+    // while the chunk waits, other messages of the room wait in the room channel, which skips them when the client lags too much
+    return match tokio::time::timeout(RELAY_TIMEOUT, outbox.relay.send(msg)).await {
+        Ok(sent) => sent.is_ok(),
+        Err(_) => {
+            warn!(
+                user = outbox.user,
+                "Client is too slow, transfer {transfer} was cancelled for it"
+            );
+            dropped.insert(transfer.to_string());
+            let cancelled = AsyncChatMsg::TransferCancelled {
+                transfer: transfer.to_string(),
+                code: ErrorCode::Internal,
+                reason: "Client didn't receive the file fast enough".to_string(),
+            };
+            outbox
+                .relay
+                .send(Envelope::from_server(&outbox.user, cancelled))
+                .await
+                .is_ok()
+        }
+    };
+}
+
+/// send presence event to all connected clients except the user the event is about
+async fn broadcast_presence(clients: &Clients, msg: AsyncChatMsg, except: &str) {
+    for (name, outbox) in clients.read().await.iter() {
+        if name != except {
+            outbox.reply(msg.clone());
+        }
+    }
+}
+
+/// names of currently connected users, sorted alphabetically
+async fn online_users(clients: &Clients) -> Vec<String> {
+    let mut users: Vec<String> = clients.read().await.keys().cloned().collect();
+    users.sort();
+    return users;
+}
+
+/// send positive reply from server to the client
+fn reply_success(outbox: &Outbox, text: String) {
+    outbox.reply(AsyncChatMsg::Success { text });
+}
+
+/// send error reply with machine readable code from server to the client
+fn reply_error(outbox: &Outbox, code: ErrorCode, text: String) {
+    outbox.reply(AsyncChatMsg::Error { code, text });
 }
 
 async fn send_quit_ping() -> Result<()> {
@@ -0,0 +1,266 @@
+//! server and client configuration loaded from json files, missing file or missing values use defaults
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::async_chat_msg::ErrorCode;
+use crate::frame::FrameLimits;
+use crate::transfer::DEFAULT_MAX_FILE_SIZE;
+
+/// default name of the server configuration file
+pub const SERVER_CONFIG_FILE: &str = "serverconfig.json";
+/// default name of the client configuration file
+pub const CLIENT_CONFIG_FILE: &str = "clientconfig.json";
+
+/// who can create new account using Register message
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RegistrationPolicy {
+    /// anyone can register
+    #[default]
+    Open,
+    /// only users with one of the invite codes from configuration can register
+    InviteOnly,
+    /// registration is disabled, only existing users can login
+    Closed,
+}
+
+/// where server stores messages and users
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+pub enum StorageConfig {
+    /// json files chatdb.json, userdb.json and seendb.json in working directory
+    #[default]
+    NanoDB,
+    /// SQLite database file
+    Sqlite(String),
+    /// memory only, everything is lost when server stops
+    Memory,
+}
+
+/// how quickly messages are written to storage
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum Durability {
+    /// every message is written before it is sent to others, nothing is lost, but every message waits for the write
+    Immediate,
+    /// messages wait in memory and are written together after max_pending messages or interval_ms milliseconds,
+    /// messages from the last interval can be lost if server crashes
+    Batched {
+        /// count of messages which triggers the write
+        max_pending: usize,
+        /// longest time the message waits in memory
+        interval_ms: u64,
+    },
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        return Durability::Batched {
+            max_pending: 100,
+            interval_ms: 1000,
+        };
+    }
+}
+
+/// how long messages are kept, missing limit means messages are kept forever
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// messages older than this count of days are removed
+    pub max_age_days: Option<u64>,
+    /// only this count of newest messages is kept
+    pub max_messages: Option<usize>,
+}
+
+/// retention of messages, policy of the room replaces the default one
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// policy for rooms without their own policy and for private messages
+    pub default: RetentionPolicy,
+    /// policies of rooms by room name, e.g. "#ops"
+    pub rooms: HashMap<String, RetentionPolicy>,
+    /// seconds between prunes done by the server, 0 disables background pruning
+    pub prune_interval_secs: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        return RetentionConfig {
+            default: RetentionPolicy::default(),
+            rooms: HashMap::new(),
+            prune_interval_secs: 3600,
+        };
+    }
+}
+
+impl RetentionConfig {
+    /// policy of the room, None room means private messages
+    pub fn policy(&self, room: Option<&str>) -> &RetentionPolicy {
+        return room
+            .and_then(|room| self.rooms.get(room))
+            .unwrap_or(&self.default);
+    }
+}
+
+/// configuration of the server
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// policy for registration of new users
+    pub registration: RegistrationPolicy,
+    /// invite codes accepted when registration is InviteOnly
+    pub invite_codes: Vec<String>,
+    /// maximum sizes of messages accepted from and sent to clients
+    pub frame_limits: FrameLimits,
+    /// maximum size of whole file or image in bytes, bigger transfers are refused before any data are sent
+    pub max_file_size: u64,
+    /// backend for messages and users
+    pub storage: StorageConfig,
+    /// choice between durability and throughput of storing messages
+    pub durability: Durability,
+    /// how long messages and their attachments are kept
+    pub retention: RetentionConfig,
+    /// users allowed to run admin commands
+    pub admins: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        return ServerConfig {
+            registration: RegistrationPolicy::default(),
+            invite_codes: Vec::new(),
+            frame_limits: FrameLimits::default(),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            storage: StorageConfig::default(),
+            durability: Durability::default(),
+            retention: RetentionConfig::default(),
+            admins: Vec::new(),
+        };
+    }
+}
+
+impl ServerConfig {
+    /// load configuration from json file, if file doesn't exist, default configuration is returned
+    pub fn load(path: &str) -> Result<ServerConfig> {
+        return load_config(path);
+    }
+
+    /// check if the user can run admin commands
+    pub fn is_admin(&self, user: &str) -> bool {
+        return self.admins.iter().any(|admin| admin == user);
+    }
+
+    /// check if registration is allowed by policy, invite code is required for InviteOnly policy
+    pub fn registration_allowed(&self, invite: Option<&str>) -> Result<(), ErrorCode> {
+        return match self.registration {
+            RegistrationPolicy::Open => Ok(()),
+            RegistrationPolicy::Closed => Err(ErrorCode::RegistrationClosed),
+            RegistrationPolicy::InviteOnly => match invite {
+                Some(code) if self.invite_codes.iter().any(|c| c == code) => Ok(()),
+                _ => Err(ErrorCode::InvalidInvite),
+            },
+        };
+    }
+}
+
+/// which files offered by other users are downloaded by the client
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+pub enum AcceptPolicy {
+    /// every file is downloaded
+    #[default]
+    Always,
+    /// user decides with .accept or .reject command
+    Ask,
+    /// no file is downloaded
+    Never,
+    /// files smaller than the size in bytes are downloaded, user is asked about bigger ones
+    SmallerThan(u64),
+    /// files with one of the extensions are downloaded, user is asked about others
+    Extensions(Vec<String>),
+}
+
+/// what client does with the offered file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptDecision {
+    /// download the file
+    Accept,
+    /// wait for .accept or .reject command
+    Ask,
+    /// ignore the file
+    Ignore,
+}
+
+impl AcceptPolicy {
+    /// decide about the file by its name and size
+    pub fn decide(&self, filename: &str, size: u64) -> AcceptDecision {
+        let accepted = match self {
+            AcceptPolicy::Always => true,
+            AcceptPolicy::Never => return AcceptDecision::Ignore,
+            AcceptPolicy::Ask => false,
+            AcceptPolicy::SmallerThan(limit) => size < *limit,
+            AcceptPolicy::Extensions(extensions) => {
+                let extension = Path::new(filename)
+                    .extension()
+                    .map(|e| e.to_string_lossy().to_lowercase());
+                extension.is_some_and(|extension| {
+                    extensions
+                        .iter()
+                        .any(|e| e.trim_start_matches('.').to_lowercase() == extension)
+                })
+            }
+        };
+        return match accepted {
+            true => AcceptDecision::Accept,
+            false => AcceptDecision::Ask,
+        };
+    }
+}
+
+/// configuration of the client
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ClientConfig {
+    /// folder where files and images folders with received files are created
+    pub download_dir: PathBuf,
+    /// which offered files are downloaded without asking
+    pub accept: AcceptPolicy,
+    /// maximum size of received file in bytes, bigger files are never downloaded
+    pub max_file_size: u64,
+    /// maximum size of sent file in bytes, bigger files are not uploaded, it shouldn't exceed max_file_size of the server
+    pub max_upload_size: u64,
+    /// maximum sizes of messages sent to and accepted from the server, they have to match limits of the server
+    pub frame_limits: FrameLimits,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        return ClientConfig {
+            download_dir: PathBuf::from("."),
+            accept: AcceptPolicy::default(),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_upload_size: DEFAULT_MAX_FILE_SIZE,
+            frame_limits: FrameLimits::default(),
+        };
+    }
+}
+
+impl ClientConfig {
+    /// load configuration from json file, if file doesn't exist, default configuration is returned
+    pub fn load(path: &str) -> Result<ClientConfig> {
+        return load_config(path);
+    }
+}
+
+/// read configuration from json file, default configuration is used if the file doesn't exist
+fn load_config<T: serde::de::DeserializeOwned + Default>(path: &str) -> Result<T> {
+    if !Path::new(path).exists() {
+        return Ok(T::default());
+    }
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("Reading config file {path} failed"))?;
+    let config = serde_json::from_str(&data)
+        .with_context(|| format!("Parsing config file {path} failed"))?;
+    return Ok(config);
+}
@@ -0,0 +1,317 @@
+//! export of stored messages to JSON Lines, CSV, self-contained HTML transcript or plain IRC-style log
+//! attachments are not inlined, exported messages link to the file stored in transfers folder of the server
+
+use core::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::async_chat_msg::AsyncChatMsgDB;
+use crate::history::{local_time, ChatRecord};
+
+/// format of exported history
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// one json object per line
+    Jsonl,
+    /// comma separated values with header
+    Csv,
+    /// html page with inline styles
+    Html,
+    /// plain text log, one message per line
+    Irc,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        return match format.to_lowercase().as_str() {
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            "csv" => Ok(ExportFormat::Csv),
+            "html" => Ok(ExportFormat::Html),
+            "irc" | "txt" => Ok(ExportFormat::Irc),
+            _ => bail!("Unknown export format {format}, use jsonl, csv, html or irc"),
+        };
+    }
+}
+
+/// type of exported message
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordKind {
+    /// text sent to the room
+    Text,
+    /// file sent to the room
+    File,
+    /// image sent to the room
+    Image,
+    /// private message
+    Direct,
+}
+
+impl fmt::Display for RecordKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self {
+            RecordKind::Text => "text",
+            RecordKind::File => "file",
+            RecordKind::Image => "image",
+            RecordKind::Direct => "direct",
+        };
+        write!(f, "{}", kind)
+    }
+}
+
+/// message in flat form used by JSON Lines and CSV exports, missing values are left out
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ExportRecord {
+    /// id of the message
+    pub id: String,
+    /// UTC time when the message was stored
+    pub timestamp: String,
+    /// type of the message
+    #[serde(rename = "type")]
+    pub kind: RecordKind,
+    /// author of the message
+    pub from: String,
+    /// room of the message, None for private messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// recipient of private message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    /// text of the message or name of the file
+    pub text: String,
+    /// SHA-256 hash of the file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// link to the stored file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<String>,
+}
+
+impl ExportRecord {
+    /// flatten stored message, attachments are linked as `<attachments>/<transfer id>`
+    pub fn new(id: &str, record: &ChatRecord, attachments: &str) -> ExportRecord {
+        let link =
+            record
+                .msg
+                .get_transfer_id()
+                .map(|transfer| match attachments.trim_end_matches('/') {
+                    "" => transfer.to_string(),
+                    folder => format!("{folder}/{transfer}"),
+                });
+        let hash = |sha256: &String| Some(sha256.clone()).filter(|sha256| !sha256.is_empty());
+        let (kind, to, text, sha256) = match &record.msg {
+            AsyncChatMsgDB::Text { text, .. } => (RecordKind::Text, None, text, None),
+            AsyncChatMsgDB::File {
+                filename, sha256, ..
+            } => (RecordKind::File, None, filename, hash(sha256)),
+            AsyncChatMsgDB::Image {
+                filename, sha256, ..
+            } => (RecordKind::Image, None, filename, hash(sha256)),
+            AsyncChatMsgDB::Direct { to, text, .. } => {
+                (RecordKind::Direct, Some(to.clone()), text, None)
+            }
+        };
+        return ExportRecord {
+            id: id.to_string(),
+            timestamp: record.timestamp.clone(),
+            kind,
+            from: record.msg.get_from().to_string(),
+            room: record.msg.get_room().map(|room| room.to_string()),
+            to,
+            text: text.clone(),
+            sha256,
+            attachment: link,
+        };
+    }
+}
+
+/// which messages are exported, times are UTC timestamps
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExportFilter {
+    /// only messages of the room, private messages are left out
+    pub room: Option<String>,
+    /// messages stored before this time are left out
+    pub since: Option<String>,
+    /// messages stored at this time or later are left out
+    pub until: Option<String>,
+}
+
+impl ExportFilter {
+    /// check if the message is exported
+    pub fn matches(&self, record: &ChatRecord) -> bool {
+        if let Some(room) = &self.room {
+            if record.msg.get_room() != Some(room.as_str()) {
+                return false;
+            }
+        }
+        if let Some(since) = &self.since {
+            if record.timestamp < *since {
+                return false;
+            }
+        }
+        if let Some(until) = &self.until {
+            if record.timestamp >= *until {
+                return false;
+            }
+        }
+        return true;
+    }
+}
+
+/// write messages in the format, title is used as heading of html transcript
+pub fn export<W: Write>(
+    out: &mut W,
+    format: ExportFormat,
+    records: &[(String, ChatRecord)],
+    attachments: &str,
+    title: &str,
+) -> Result<()> {
+    let records = records
+        .iter()
+        .map(|(id, record)| ExportRecord::new(id, record, attachments));
+    match format {
+        ExportFormat::Jsonl => {
+            for record in records {
+                serde_json::to_writer(&mut *out, &record)?;
+                writeln!(out)?;
+            }
+        }
+        ExportFormat::Csv => {
+            writeln!(out, "id,timestamp,type,from,to,room,text,sha256,attachment")?;
+            for record in records {
+                let fields = [
+                    record.id,
+                    record.timestamp,
+                    record.kind.to_string(),
+                    record.from,
+                    record.to.unwrap_or_default(),
+                    record.room.unwrap_or_default(),
+                    record.text,
+                    record.sha256.unwrap_or_default(),
+                    record.attachment.unwrap_or_default(),
+                ];
+                let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+                writeln!(out, "{}", fields.join(","))?;
+            }
+        }
+        ExportFormat::Html => {
+            writeln!(
+                out,
+                "{}",
+                HTML_HEADER.replace("{title}", &html_escape(title))
+            )?;
+            for record in records {
+                writeln!(out, "{}", html_line(&record))?;
+            }
+            writeln!(out, "{HTML_FOOTER}")?;
+        }
+        ExportFormat::Irc => {
+            for record in records {
+                writeln!(out, "{}", irc_line(&record))?;
+            }
+        }
+    }
+    out.flush()?;
+    return Ok(());
+}
+
+/// quote CSV field if it contains separator, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", field.replace('"', "\"\""));
+    }
+    return field.to_string();
+}
+
+/// escape text for html
+fn html_escape(text: &str) -> String {
+    return text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;");
+}
+
+/// start of html transcript, styles are inline, so the page doesn't need any other file
+const HTML_HEADER: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+.msg { margin: 0.2em 0; }
+.time { color: #888; font-family: monospace; }
+.room { color: #06c; }
+.from { font-weight: bold; }
+.direct { color: #a0a; }
+</style>
+</head>
+<body>
+<h1>{title}</h1>"#;
+
+/// end of html transcript
+const HTML_FOOTER: &str = "</body>\n</html>";
+
+/// one message of html transcript, attachment is link to the stored file
+fn html_line(record: &ExportRecord) -> String {
+    let time = html_escape(&local_time(&record.timestamp));
+    let from = html_escape(&record.from);
+    let text = html_escape(&record.text);
+    let body = match (record.kind, &record.attachment) {
+        (RecordKind::File | RecordKind::Image, Some(link)) => {
+            format!(
+                "{} <a href=\"{}\">{text}</a>",
+                record.kind,
+                html_escape(link)
+            )
+        }
+        (RecordKind::File | RecordKind::Image, None) => format!("{} {text}", record.kind),
+        _ => text,
+    };
+    let target = match (&record.room, &record.to) {
+        (Some(room), _) => format!("<span class=\"room\">[{}]</span>", html_escape(room)),
+        (None, to) => format!(
+            "<span class=\"direct\">(private to {})</span>",
+            html_escape(to.as_deref().unwrap_or_default())
+        ),
+    };
+    return format!(
+        "<div class=\"msg\"><span class=\"time\">{time}</span> {target} <span class=\"from\">{from}</span>: {body}</div>"
+    );
+}
+
+/// one line of IRC-style log, target is the room or recipient of private message, files are logged as actions
+/// e.g. `[2024-05-01 10:00:00] #general <martin> hello` or `[2024-05-01 10:00:00] #general * martin sends file a.txt (transfers/id)`
+fn irc_line(record: &ExportRecord) -> String {
+    let time = local_time(&record.timestamp);
+    let target = record
+        .room
+        .as_deref()
+        .or(record.to.as_deref())
+        .unwrap_or_default();
+    // line breaks would start new log line
+    let text = record.text.replace(['\r', '\n'], " ");
+    return match record.kind {
+        RecordKind::Text | RecordKind::Direct => {
+            format!("[{time}] {target} <{}> {text}", record.from)
+        }
+        RecordKind::File | RecordKind::Image => {
+            let link = record
+                .attachment
+                .as_ref()
+                .map(|link| format!(" ({link})"))
+                .unwrap_or_default();
+            format!(
+                "[{time}] {target} * {} sends {} {text}{link}",
+                record.from, record.kind
+            )
+        }
+    };
+}
@@ -0,0 +1,98 @@
+//! framing of messages sent over the network, every frame is 4 bytes length, 1 byte kind and serialized message
+
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::async_chat_msg::AsyncChatMsg;
+
+/// default maximum size of control and text message frame
+pub const DEFAULT_MAX_MESSAGE_SIZE: u32 = 64 * 1024;
+/// default maximum size of frame carrying chunk of file or image data
+pub const DEFAULT_MAX_CHUNK_SIZE: u32 = 64 * 1024;
+
+/// kind of the frame, sent before the message data, so receiver knows which limit to check before reading the data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// control, text and other small messages
+    Message = 0,
+    /// chunks of file or image data
+    File = 1,
+}
+
+impl FrameKind {
+    /// get kind of the frame for the message
+    pub fn of(msg: &AsyncChatMsg) -> FrameKind {
+        return match msg {
+            AsyncChatMsg::FileChunk { .. } => FrameKind::File,
+            _ => FrameKind::Message,
+        };
+    }
+
+    /// get frame kind from byte received from the network
+    pub fn from_byte(byte: u8) -> Result<FrameKind, FrameError> {
+        return match byte {
+            0 => Ok(FrameKind::Message),
+            1 => Ok(FrameKind::File),
+            other => Err(FrameError::UnknownKind(other)),
+        };
+    }
+}
+
+/// maximum sizes of frames, checked before the data are read or sent
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FrameLimits {
+    /// maximum size of control and text message frame in bytes
+    pub max_message_size: u32,
+    /// maximum size of frame with chunk of file or image in bytes
+    pub max_chunk_size: u32,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        return FrameLimits {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_chunk_size: DEFAULT_MAX_CHUNK_SIZE,
+        };
+    }
+}
+
+impl FrameLimits {
+    /// get limit for the kind of frame
+    pub fn limit(&self, kind: FrameKind) -> u32 {
+        return match kind {
+            FrameKind::Message => self.max_message_size,
+            FrameKind::File => self.max_chunk_size,
+        };
+    }
+
+    /// check size of the frame against the limit for its kind
+    pub fn check(&self, kind: FrameKind, size: u64) -> Result<(), FrameError> {
+        let limit = self.limit(kind);
+        if size > limit as u64 {
+            return Err(FrameError::Oversized { kind, size, limit });
+        }
+        return Ok(());
+    }
+}
+
+/// errors of the framing, stream is not usable after any of them, because rest of the frame was not read
+#[derive(Debug, Error)]
+pub enum FrameError {
+    /// frame is bigger than the limit for its kind
+    #[error("Frame of kind {kind:?} has {size}B, which exceeds limit {limit}B")]
+    Oversized {
+        /// kind of the frame
+        kind: FrameKind,
+        /// size of the frame
+        size: u64,
+        /// limit for the kind of frame
+        limit: u32,
+    },
+    /// kind byte is not known
+    #[error("Unknown frame kind {0}")]
+    UnknownKind(u8),
+    /// message in the frame doesn't belong to the kind of frame it was sent in
+    #[error("Message doesn't match frame kind {0:?}")]
+    KindMismatch(FrameKind),
+}
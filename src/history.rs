@@ -0,0 +1,211 @@
+//! history of chat messages stored in db, it is replayed to users after login, on join of the room and on request
+//! messages are stored by unique increasing id, so messages sent in the same moment don't overwrite each other
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Result;
+use chrono::prelude::*;
+use serde_derive::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::async_chat_msg::{AsyncChatMsg, AsyncChatMsgDB, Envelope};
+use crate::rooms::DEFAULT_ROOM;
+use crate::storage::Storage;
+
+/// count of messages replayed to the user, who was never seen before, or who joins the room
+pub const DEFAULT_HISTORY_SIZE: usize = 20;
+/// maximum count of messages replayed at once, also for users who were offline for a long time
+pub const MAX_HISTORY_SIZE: usize = 500;
+
+/// format of timestamps stored in db, UTC with microseconds, timestamps in this format can be compared as strings
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6fZ";
+/// format of local time used in keys of older db files and for display
+const LOCAL_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// last message id created by this process, new id is always bigger
+static LAST_MESSAGE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// message from history together with the time when it was stored
+pub type HistoryEntry = (String, AsyncChatMsgDB); // timestamp, message
+
+/// message stored in chat db, key of the record is message id, fields of the message are next to the timestamp
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChatRecord {
+    /// UTC time when the message was stored, with microseconds
+    pub timestamp: String,
+    /// the message itself
+    #[serde(flatten)]
+    pub msg: AsyncChatMsgDB,
+}
+
+impl ChatRecord {
+    /// create record of the message stored now
+    pub fn new(msg: AsyncChatMsgDB) -> ChatRecord {
+        return ChatRecord {
+            timestamp: utc_timestamp(Utc::now()),
+            msg,
+        };
+    }
+}
+
+/// create new message id, ids are microseconds since epoch increased when needed, so they are unique and increasing
+/// ids have fixed length, so their order as strings is the same as order of messages
+pub fn new_message_id() -> String {
+    let now = Utc::now().timestamp_micros().max(0) as u64;
+    let last = LAST_MESSAGE_ID
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+            Some(now.max(last + 1))
+        })
+        .expect("update always returns new value");
+    return format_message_id(now.max(last + 1));
+}
+
+/// remember id of stored message, so new ids are bigger, ids which are not numbers are ignored
+pub(crate) fn remember_message_id(id: &str) {
+    if let Ok(id) = id.parse::<u64>() {
+        LAST_MESSAGE_ID.fetch_max(id, Ordering::SeqCst);
+    }
+}
+
+/// create id for message stored at the time, used for messages with original time like imported ones,
+/// id is increased until it is not one of the taken ids and new id is added to them
+pub fn message_id_at(time: DateTime<Utc>, taken: &mut HashSet<String>) -> String {
+    let mut id = time.timestamp_micros().max(0) as u64;
+    while taken.contains(&format_message_id(id)) {
+        id += 1;
+    }
+    LAST_MESSAGE_ID.fetch_max(id, Ordering::SeqCst);
+    let id = format_message_id(id);
+    taken.insert(id.clone());
+    return id;
+}
+
+/// format numeric message id as key of the record
+fn format_message_id(id: u64) -> String {
+    return format!("{id:020}");
+}
+
+/// format time as timestamp stored in db
+pub fn utc_timestamp(time: DateTime<Utc>) -> String {
+    return time.format(TIMESTAMP_FORMAT).to_string();
+}
+
+/// format timestamp from db as local time for display, invalid timestamps are returned as they are
+pub fn local_time(timestamp: &str) -> String {
+    return match DateTime::parse_from_rfc3339(timestamp) {
+        Ok(time) => time
+            .with_timezone(&Local)
+            .format(LOCAL_TIME_FORMAT)
+            .to_string(),
+        Err(_) => timestamp.to_string(),
+    };
+}
+
+/// parse local time used by older versions, returns UTC time
+pub fn parse_local_time(time: &str) -> Option<DateTime<Utc>> {
+    let time = NaiveDateTime::parse_from_str(time, LOCAL_TIME_FORMAT).ok()?;
+    return Local
+        .from_local_datetime(&time)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc));
+}
+
+/// parse date or time written by the user to UTC timestamp, local date (2024-05-01) means start of the day,
+/// local time can be written as 2024-05-01T10:00:00, RFC 3339 time with offset is also accepted
+pub fn parse_time_filter(time: &str) -> Option<String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Some(utc_timestamp(time.with_timezone(&Utc)));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        return parse_local_time(&format!("{date} 00:00:00")).map(utc_timestamp);
+    }
+    return parse_local_time(&time.replace('T', " ")).map(utc_timestamp);
+}
+
+/// select last `count` messages of the room visible to the user from records ordered by id, messages stored before `since` are skipped
+/// private messages of the user belong to history of the default room
+pub fn select_history(
+    records: Vec<(String, ChatRecord)>,
+    user: &str,
+    room: &str,
+    since: Option<&str>,
+    count: usize,
+) -> Vec<HistoryEntry> {
+    let mut history: Vec<HistoryEntry> = records
+        .into_iter()
+        .map(|(_, record)| record)
+        .filter(|record| since.is_none_or(|since| record.timestamp.as_str() >= since))
+        .filter(|record| match record.msg.get_room() {
+            Some(msg_room) => msg_room == room,
+            None => room == DEFAULT_ROOM,
+        })
+        .filter(|record| record.msg.is_visible_to(user))
+        .map(|record| (record.timestamp, record.msg))
+        .collect();
+    let skipped = history.len().saturating_sub(count);
+    return history.split_off(skipped);
+}
+
+/// split history to HistoryBatch messages of the server for the user, so none of them is bigger than max_size together with its envelope,
+/// entry which doesn't fit to the message alone is skipped
+pub fn history_batches(
+    user: &str,
+    room: &str,
+    history: Vec<HistoryEntry>,
+    max_size: u32,
+) -> Vec<Envelope> {
+    let max_size = max_size as usize;
+    let batch_msg = |messages| {
+        let room = room.to_string();
+        return Envelope::from_server(user, AsyncChatMsg::HistoryBatch { room, messages });
+    };
+    // size of empty batch with the envelope, array header can grow by 8 bytes with count of entries,
+    // id and timestamp of every envelope have the same length
+    let empty = batch_msg(Vec::new());
+    let empty_size = serde_cbor::to_vec(&empty).map_or(max_size, |data| data.len()) + 8;
+    let mut batches = Vec::new();
+    let mut batch: Vec<HistoryEntry> = Vec::new();
+    let mut size = empty_size;
+    for entry in history {
+        let entry_size = serde_cbor::to_vec(&entry).map_or(usize::MAX, |data| data.len());
+        if empty_size.saturating_add(entry_size) > max_size {
+            warn!(
+                "Message from {} at {} is too big for history",
+                entry.1.get_from(),
+                entry.0
+            );
+            continue;
+        }
+        if size + entry_size > max_size {
+            batches.push(batch_msg(batch));
+            batch = Vec::new();
+            size = empty_size;
+        }
+        size += entry_size;
+        batch.push(entry);
+    }
+    if !batch.is_empty() {
+        batches.push(batch_msg(batch));
+    }
+    return batches;
+}
+
+/// get time when the user was last seen online in format of timestamps in db, None for users who were never seen
+/// local time saved by older versions is converted
+pub async fn last_seen(storage: &dyn Storage, user: &str) -> Result<Option<String>> {
+    let Some(seen) = storage.last_seen(user).await? else {
+        return Ok(None);
+    };
+    return match parse_local_time(&seen) {
+        Some(time) => Ok(Some(utc_timestamp(time))),
+        None => Ok(Some(seen)),
+    };
+}
+
+/// remember the time when the user disconnected, so only newer messages are replayed after next login
+pub async fn save_last_seen(storage: &dyn Storage, user: &str) -> Result<()> {
+    return storage
+        .set_last_seen(user, &utc_timestamp(Utc::now()))
+        .await;
+}
@@ -0,0 +1,333 @@
+//! import of chat history from JSON Lines exports and simple IRC-style logs, messages keep their original time and author
+//! messages which are already stored are skipped as duplicates, so the same file can be imported again
+
+use core::fmt;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use chrono::prelude::*;
+
+use crate::async_chat_msg::AsyncChatMsgDB;
+use crate::export::{ExportRecord, RecordKind};
+use crate::history::{utc_timestamp, HistoryEntry};
+use crate::rooms::{normalize_room_name, DEFAULT_ROOM};
+use crate::save_msg_to_db_at;
+use crate::storage::Storage;
+use crate::transfer::FileKind;
+
+/// format of imported file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    /// one json object per line as written by export
+    Jsonl,
+    /// plain text log with lines like `[2024-05-01 10:00:00] #general <martin> hello`
+    Irc,
+}
+
+impl FromStr for ImportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        return match format.to_lowercase().as_str() {
+            "jsonl" | "json" => Ok(ImportFormat::Jsonl),
+            "irc" | "txt" | "log" => Ok(ImportFormat::Irc),
+            _ => bail!("Unknown import format {format}, use jsonl or irc"),
+        };
+    }
+}
+
+/// message read from imported file with its original time
+pub type ImportedMessage = (DateTime<Utc>, AsyncChatMsgDB);
+
+/// messages read from the file and lines which are not messages
+#[derive(Debug, Default)]
+pub struct ParsedImport {
+    /// messages in order of the file
+    pub messages: Vec<ImportedMessage>,
+    /// line number and reason why the line was skipped
+    pub skipped: Vec<(usize, String)>,
+}
+
+impl ParsedImport {
+    /// parse every non-empty line by the function, lines which cannot be parsed are remembered with the reason
+    fn parse_lines<F>(text: &str, parse: F) -> ParsedImport
+    where
+        F: Fn(&str) -> Result<ImportedMessage>,
+    {
+        let mut parsed = ParsedImport::default();
+        for (index, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match parse(line) {
+                Ok(message) => parsed.messages.push(message),
+                Err(e) => parsed.skipped.push((index + 1, e.to_string())),
+            }
+        }
+        return parsed;
+    }
+}
+
+/// read JSON Lines written by export, files are imported without link to the stored file, because the file is not on this server
+pub fn parse_jsonl(text: &str) -> ParsedImport {
+    return ParsedImport::parse_lines(text, |line| {
+        let record: ExportRecord = serde_json::from_str(line)?;
+        return message_from_export(record);
+    });
+}
+
+/// read IRC-style log, messages without room are imported to the room, date is used for logs with time only
+/// target before the nick is the room or recipient of private message, actions `* nick sends file name` are imported as files
+pub fn parse_irc(text: &str, room: &str, date: Option<NaiveDate>) -> ParsedImport {
+    return ParsedImport::parse_lines(text, |line| parse_irc_line(line, room, date));
+}
+
+/// convert exported record to message
+fn message_from_export(record: ExportRecord) -> Result<ImportedMessage> {
+    let time = DateTime::parse_from_rfc3339(&record.timestamp)
+        .with_context(|| format!("Timestamp {} is not valid", record.timestamp))?
+        .with_timezone(&Utc);
+    if record.from.trim().is_empty() {
+        bail!("Author of the message is missing");
+    }
+    let room = normalize_room_name(record.room.as_deref().unwrap_or(DEFAULT_ROOM));
+    let sha256 = record.sha256.unwrap_or_default();
+    let from = record.from;
+    let msg = match record.kind {
+        RecordKind::Text => AsyncChatMsgDB::Text {
+            from,
+            room: room?,
+            text: record.text,
+        },
+        RecordKind::File => AsyncChatMsgDB::File {
+            from,
+            room: room?,
+            filename: record.text,
+            sha256,
+            transfer: String::new(),
+        },
+        RecordKind::Image => AsyncChatMsgDB::Image {
+            from,
+            room: room?,
+            filename: record.text,
+            sha256,
+            transfer: String::new(),
+        },
+        RecordKind::Direct => match record.to {
+            Some(to) => AsyncChatMsgDB::Direct {
+                from,
+                to,
+                text: record.text,
+            },
+            None => bail!("Recipient of private message is missing"),
+        },
+    };
+    return Ok((time, msg));
+}
+
+/// parse one line of IRC-style log
+fn parse_irc_line(line: &str, room: &str, date: Option<NaiveDate>) -> Result<ImportedMessage> {
+    let Some((stamp, rest)) = line.strip_prefix('[').and_then(|line| line.split_once(']')) else {
+        bail!("Line doesn't start with [time]");
+    };
+    let time = parse_irc_time(stamp.trim(), date)?;
+    let rest = rest.trim_start();
+    let (target, rest) = match rest.split_once(' ') {
+        Some((target, rest)) if !target.starts_with('<') && target != "*" => {
+            (Some(target), rest.trim_start())
+        }
+        _ => (None, rest),
+    };
+    // target which is not a room is recipient of private message
+    let (room, recipient) = match target {
+        Some(target) if !target.starts_with('#') => (normalize_room_name(room)?, Some(target)),
+        target => (normalize_room_name(target.unwrap_or(room))?, None),
+    };
+    if let Some(rest) = rest.strip_prefix('<') {
+        let Some((nick, text)) = rest.split_once('>') else {
+            bail!("Nick is not closed by >");
+        };
+        let nick = clean_nick(nick)?;
+        let text = text.strip_prefix(' ').unwrap_or(text).to_string();
+        let msg = match recipient {
+            Some(to) => AsyncChatMsgDB::Direct {
+                from: nick,
+                to: to.to_string(),
+                text,
+            },
+            None => AsyncChatMsgDB::Text {
+                from: nick,
+                room,
+                text,
+            },
+        };
+        return Ok((time, msg));
+    }
+    if let Some(action) = rest.strip_prefix("* ") {
+        let Some((nick, action)) = action.split_once(' ') else {
+            bail!("Action without text");
+        };
+        let nick = clean_nick(nick)?;
+        let file = match action.strip_prefix("sends file ") {
+            Some(name) => Some((FileKind::File, name)),
+            None => action
+                .strip_prefix("sends image ")
+                .map(|name| (FileKind::Image, name)),
+        };
+        let text = format!("* {nick} {action}");
+        let msg = match (recipient, file) {
+            (None, Some((FileKind::File, name))) => AsyncChatMsgDB::File {
+                from: nick,
+                room,
+                filename: without_link(name),
+                sha256: String::new(),
+                transfer: String::new(),
+            },
+            (None, Some((FileKind::Image, name))) => AsyncChatMsgDB::Image {
+                from: nick,
+                room,
+                filename: without_link(name),
+                sha256: String::new(),
+                transfer: String::new(),
+            },
+            (Some(to), _) => AsyncChatMsgDB::Direct {
+                from: nick,
+                to: to.to_string(),
+                text,
+            },
+            (None, None) => AsyncChatMsgDB::Text {
+                from: nick,
+                room,
+                text,
+            },
+        };
+        return Ok((time, msg));
+    }
+    bail!("Line is not a message");
+}
+
+/// parse local time of the log line, logs with time only need date of the log
+fn parse_irc_time(stamp: &str, date: Option<NaiveDate>) -> Result<DateTime<Utc>> {
+    let full = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(stamp, format).ok());
+    let time = match full {
+        Some(time) => time,
+        None => {
+            let Some(clock) = ["%H:%M:%S", "%H:%M"]
+                .iter()
+                .find_map(|format| NaiveTime::parse_from_str(stamp, format).ok())
+            else {
+                bail!("Time {stamp} is not valid");
+            };
+            let Some(date) = date else {
+                bail!("Time {stamp} has no date and date of the log is not known");
+            };
+            date.and_time(clock)
+        }
+    };
+    return Local
+        .from_local_datetime(&time)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .with_context(|| format!("Time {stamp} doesn't exist in local time zone"));
+}
+
+/// remove IRC mode prefix like @ or + from the nick
+fn clean_nick(nick: &str) -> Result<String> {
+    let nick = nick.trim().trim_start_matches(['@', '+', '%', '&', '~']);
+    if nick.is_empty() || nick.contains(char::is_whitespace) {
+        bail!("Nick {nick} is not valid");
+    }
+    return Ok(nick.to_string());
+}
+
+/// remove link to the stored file written by export after the file name
+fn without_link(name: &str) -> String {
+    return match name.rsplit_once(" (") {
+        Some((name, link)) if link.ends_with(')') => name.to_string(),
+        _ => name.to_string(),
+    };
+}
+
+/// key of the message used to find duplicates, logs have time only in seconds, so messages are compared by second
+/// and whitespace of the text is normalized, because line breaks are lost in logs
+fn duplicate_key(timestamp: &str, msg: &AsyncChatMsgDB) -> String {
+    let second = timestamp.get(..19).unwrap_or(timestamp);
+    let (kind, target, text) = match msg {
+        AsyncChatMsgDB::Text { room, text, .. } => ("text", room, text),
+        AsyncChatMsgDB::File { room, filename, .. } => ("file", room, filename),
+        AsyncChatMsgDB::Image { room, filename, .. } => ("image", room, filename),
+        AsyncChatMsgDB::Direct { to, text, .. } => ("direct", to, text),
+    };
+    let text: Vec<&str> = text.split_whitespace().collect();
+    return format!(
+        "{second}|{kind}|{target}|{}|{}",
+        msg.get_from(),
+        text.join(" ")
+    );
+}
+
+/// result of the import
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// imported messages, in dry run messages which would be imported
+    pub imported: Vec<HistoryEntry>,
+    /// count of messages which were already stored or were in the file more times
+    pub duplicates: usize,
+    /// line number and reason of lines which are not messages
+    pub skipped: Vec<(usize, String)>,
+    /// nothing was written to storage
+    pub dry_run: bool,
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = match self.dry_run {
+            true => "Would import",
+            false => "Imported",
+        };
+        write!(
+            f,
+            "{action} {} messages, {} duplicates and {} other lines skipped",
+            self.imported.len(),
+            self.duplicates,
+            self.skipped.len()
+        )
+    }
+}
+
+/// save parsed messages through save_msg_to_db_at ordered by their time, messages which are already stored are skipped,
+/// in dry run nothing is written and report contains messages which would be imported
+pub async fn import_messages(
+    parsed: ParsedImport,
+    storage: &dyn Storage,
+    dry_run: bool,
+) -> Result<ImportReport> {
+    let existing = storage.messages().await?;
+    let mut taken: HashSet<String> = existing.iter().map(|(id, _)| id.clone()).collect();
+    let mut seen: HashSet<String> = existing
+        .iter()
+        .map(|(_, record)| duplicate_key(&record.timestamp, &record.msg))
+        .collect();
+    let mut report = ImportReport {
+        skipped: parsed.skipped,
+        dry_run,
+        ..ImportReport::default()
+    };
+    let mut messages = parsed.messages;
+    messages.sort_by_key(|(time, _)| *time);
+    for (time, msg) in messages {
+        let timestamp = utc_timestamp(time);
+        if !seen.insert(duplicate_key(&timestamp, &msg)) {
+            report.duplicates += 1;
+            continue;
+        }
+        if !dry_run {
+            save_msg_to_db_at(msg.clone(), time, &mut taken, storage).await?;
+        }
+        report.imported.push((timestamp, msg));
+    }
+    return Ok(report);
+}
@@ -1,27 +1,63 @@
 //! Library with common functions for client and server
 #![warn(missing_docs)]
-use anyhow::{Context, Result};
-use async_chat_msg::{AsyncChatMsg, AsyncChatMsgDB};
-use nanodb::{error::NanoDBError, nanodb::NanoDB};
-use std::{io::Error, path::Path};
-use tokio::{
-    fs::{self, File},
-    io::AsyncReadExt,
+#![allow(clippy::needless_return)]
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
 };
+use async_chat_msg::{AsyncChatMsgDB, Envelope};
+use chrono::{DateTime, Utc};
+use history::{message_id_at, new_message_id, utc_timestamp, ChatRecord};
+use std::{
+    collections::HashSet,
+    io::Error,
+    path::{Path, PathBuf},
+};
+use storage::Storage;
+use subtle::ConstantTimeEq;
+use tokio::{fs, io::AsyncWriteExt};
+use tracing::{error, info};
 
 /// reference async_chat_msg file
 pub mod async_chat_msg;
+/// reference config file
+pub mod config;
+/// reference export file
+pub mod export;
+/// reference frame file
+pub mod frame;
+/// reference history file
+pub mod history;
+/// reference import file
+pub mod import;
+/// reference logging file
+pub mod logging;
+/// reference retention file
+pub mod retention;
+/// reference rooms file
+pub mod rooms;
+/// reference schema file
+pub mod schema;
+/// reference search file
+pub mod search;
+/// reference snapshot file
+pub mod snapshot;
+/// reference storage folder
+pub mod storage;
+/// reference transfer file
+pub mod transfer;
 /// define port to which client and server are connected
 pub const PORT: &str = "11112";
 
 /// serialize message to binary vec for sending via network
-pub fn serialize_msg(msg: &AsyncChatMsg) -> Result<Vec<u8>> {
-    return Ok(serde_cbor::to_vec(&msg).with_context(|| "Serialization of message failed")?);
+pub fn serialize_msg(msg: &Envelope) -> Result<Vec<u8>> {
+    return serde_cbor::to_vec(msg).with_context(|| "Serialization of message failed");
 }
 
 /// deserialize message from vector to object
-pub fn deserialize_msg(data: Vec<u8>) -> Result<AsyncChatMsg> {
-    return Ok(serde_cbor::from_slice(&data).with_context(|| "Deserialization of message failed")?);
+pub fn deserialize_msg(data: Vec<u8>) -> Result<Envelope> {
+    return serde_cbor::from_slice(&data).with_context(|| "Deserialization of message failed");
 }
 
 /// get file name from path provided
@@ -30,15 +66,68 @@ pub fn get_file_name(path: &str) -> String {
     return path.file_name().unwrap().to_str().unwrap().to_string();
 }
 
-/// load data from filesystem from path provided
-pub async fn get_file_data(path: &str) -> Result<Vec<u8>> {
-    let path = Path::new(path.trim());
-    let mut f = File::open(path).await?;
-    let metadata = fs::metadata(path).await?;
-    let mut buffer = vec![0; metadata.len() as usize];
-    f.read(&mut buffer).await?;
+/// names reserved by Windows, files with these names cannot be created even with extension
+const RESERVED_FILE_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+/// maximum length of sanitized file name in characters
+const MAX_FILE_NAME_LENGTH: usize = 200;
 
-    return Ok(buffer);
+/// make file name received from the network safe to be joined to the download folder
+/// directory components, control and reserved characters, leading dots and reserved names are removed, so the file cannot be written outside of the folder
+pub fn sanitize_file_name(name: &str) -> String {
+    // both separators are handled, sender can run on other platform
+    let name = name
+        .rsplit(['/', '\\'])
+        .find(|part| !part.trim().is_empty())
+        .unwrap_or_default();
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .take(MAX_FILE_NAME_LENGTH)
+        .collect();
+    let name = name
+        .trim_start_matches(['.', ' '])
+        .trim_end_matches(['.', ' ']);
+    if name.is_empty() {
+        return "file".to_string();
+    }
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_FILE_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        return format!("_{name}");
+    }
+    return name.to_string();
+}
+
+/// get path which doesn't exist yet, if file with the same name exists, numbered suffix is added, e.g. photo (1).jpg
+pub fn unique_file_path(path: &Path) -> PathBuf {
+    if !path.exists() {
+        return path.to_path_buf();
+    }
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let mut number = 1;
+    loop {
+        let candidate = path.with_file_name(format!("{stem} ({number}){extension}"));
+        if !candidate.exists() {
+            return candidate;
+        }
+        number += 1;
+    }
 }
 
 /// make sure that the folder provided as path parameter exists and is a folder
@@ -46,7 +135,6 @@ pub async fn ensure_folder(path: &str) -> Result<()> {
     let path = Path::new(path.trim());
     if !path.exists() {
         fs::create_dir_all(path).await?;
-        ()
     }
 
     let meta = fs::metadata(path).await?;
@@ -60,50 +148,166 @@ pub async fn ensure_folder(path: &str) -> Result<()> {
     Ok(())
 }
 
-/// saves message to databaze
-pub async fn save_msg_to_db(timestamp: String, msg: AsyncChatMsgDB, mut db: NanoDB) -> Result<()> {
-    let from = match msg.clone() {
-        AsyncChatMsgDB::Text(from, _) => from,
-        AsyncChatMsgDB::Image(from, _) => from,
-        AsyncChatMsgDB::File(from, _) => from,
+/// write file through temporary file in the same folder, which is renamed to the path only when all data are on disk,
+/// so crash during the write leaves either old or new content
+pub async fn write_file_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let Some(name) = path.file_name() else {
+        bail!("Path {} is not a file", path.display());
     };
-    db.insert(&(timestamp + "|" + &from), msg).await?;
-    if let Err(e) = db.write().await {
-        eprintln!("Saving db to file failed with error {e}");
+    let temp = path.with_file_name(format!("{}.tmp", name.to_string_lossy()));
+    let written = async {
+        let mut file = fs::File::create(&temp).await?;
+        file.write_all(contents).await?;
+        file.sync_all().await?;
+        return fs::rename(&temp, path).await;
     }
-    Ok(())
+    .await;
+    if let Err(e) = written {
+        _ = fs::remove_file(&temp).await;
+        return Err(
+            anyhow::Error::new(e).context(format!("Writing file {} failed", path.display()))
+        );
+    }
+    return Ok(());
+}
+
+/// saves message to databaze under new unique message id together with current time, returns id of the message
+pub async fn save_msg_to_db(msg: AsyncChatMsgDB, storage: &dyn Storage) -> Result<String> {
+    let id = new_message_id();
+    storage.insert_message(&id, ChatRecord::new(msg)).await?;
+    Ok(id)
+}
+
+/// saves message to databaze with its original time under id made from the time, ids in `taken` are not used and new id is added to them,
+/// returns id of the message
+pub async fn save_msg_to_db_at(
+    msg: AsyncChatMsgDB,
+    time: DateTime<Utc>,
+    taken: &mut HashSet<String>,
+    storage: &dyn Storage,
+) -> Result<String> {
+    let id = message_id_at(time, taken);
+    let record = ChatRecord {
+        timestamp: utc_timestamp(time),
+        msg,
+    };
+    storage.insert_message(&id, record).await?;
+    Ok(id)
 }
 
-/// validate user name and password against data stored in db, if user doesn't exist yet, create it
-pub async fn validate_user_in_db(login: &str, password: &str, mut db: NanoDB) -> Result<bool> {
-    let pass = get_password_for_user(login, &db).await;
+/// result of validation of user name and password against db
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginStatus {
+    /// user exists and password is correct
+    Valid,
+    /// user exists, but password doesn't match
+    WrongPassword,
+    /// user doesn't exist, it has to be registered first
+    UnknownUser,
+}
+
+/// result of registration of new user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterStatus {
+    /// user was created
+    Registered,
+    /// user with the same name already exists
+    AlreadyExists,
+}
+
+/// validate user name and password against data stored in db, unknown users are not created, they have to be registered
+/// passwords are stored as Argon2 hashes, plain text passwords from older db files are re-hashed on successful login
+pub async fn validate_user_in_db(
+    login: &str,
+    password: &str,
+    storage: &dyn Storage,
+) -> Result<LoginStatus> {
+    let pass = get_password_for_user(login, storage).await;
     match pass {
-        Ok(pass) => {
-            println!("Password for user {login} in db is '{password}'");
-            if pass != password {
-                // TODO md5
-                return Ok(false);
+        Ok(Some(pass)) => {
+            if is_password_hash(&pass) {
+                if verify_password(password, &pass).await? {
+                    return Ok(LoginStatus::Valid);
+                }
+                return Ok(LoginStatus::WrongPassword);
             }
-            return Ok(true);
-        }
-        Err(NanoDBError::KeyNotFound(error)) => {
-            eprintln!("Error getting password for user {login} from db, key not found: {error}");
-            // this is new user, so save him and return true
-            db.insert(login, password).await?;
-            if let Err(e) = db.write().await {
-                eprintln!("Saving db to file failed with error {e}");
+            // legacy plain text password, compare in constant time and upgrade it to hash
+            if !bool::from(pass.as_bytes().ct_eq(password.as_bytes())) {
+                return Ok(LoginStatus::WrongPassword);
             }
-            return Ok(true);
+            save_password_for_user(login, password, storage).await?;
+            return Ok(LoginStatus::Valid);
+        }
+        Ok(None) => {
+            info!("User {login} not found in db");
+            return Ok(LoginStatus::UnknownUser);
         }
         Err(error) => {
-            eprintln!("Error getting password for user {login} from db: {error}");
-            return Ok(false);
+            error!("Error getting password for user {login} from db: {error}");
+            return Ok(LoginStatus::WrongPassword);
         }
     }
 }
 
-/// get password for user name provided as parameter
-pub async fn get_password_for_user(login: &str, db: &NanoDB) -> Result<String, NanoDBError> {
-    let pass = db.data().await.get(&login)?.into()?;
-    return Ok(pass);
+/// create new user in db, existing user is never overwritten
+pub async fn register_user_in_db(
+    login: &str,
+    password: &str,
+    storage: &dyn Storage,
+) -> Result<RegisterStatus> {
+    match get_password_for_user(login, storage).await {
+        Ok(Some(_)) => return Ok(RegisterStatus::AlreadyExists),
+        Ok(None) => (),
+        Err(error) => bail!("Error getting user {login} from db: {error}"),
+    }
+    save_password_for_user(login, password, storage).await?;
+    info!("User {login} was registered");
+    return Ok(RegisterStatus::Registered);
+}
+
+/// get password for user name provided as parameter, it is Argon2 hash or plain text password from older db files, None for unknown user
+pub async fn get_password_for_user(login: &str, storage: &dyn Storage) -> Result<Option<String>> {
+    return storage.get_password(login).await;
+}
+
+/// hash the password and save it to db for user name provided as parameter
+async fn save_password_for_user(login: &str, password: &str, storage: &dyn Storage) -> Result<()> {
+    let hash = hash_password(password).await?;
+    storage.set_password(login, &hash).await
+}
+
+/// hash password with Argon2 and random salt, result is PHC string containing algorithm, parameters, salt and hash
+pub async fn hash_password(password: &str) -> Result<String> {
+    let password = password.to_string();
+    // hashing is intentionally slow, so it is not done on async worker thread
+    let hash = tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+    })
+    .await?
+    .map_err(|e| anyhow!("Hashing password failed: {e}"))?;
+    return Ok(hash);
+}
+
+/// verify password against Argon2 hash stored in db, hashes are compared in constant time
+pub async fn verify_password(password: &str, hash: &str) -> Result<bool> {
+    let password = password.to_string();
+    let hash = hash.to_string();
+    let valid = tokio::task::spawn_blocking(move || {
+        let hash = PasswordHash::new(&hash).map_err(|e| anyhow!("Invalid password hash: {e}"))?;
+        return Ok::<bool, anyhow::Error>(
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok(),
+        );
+    })
+    .await??;
+    return Ok(valid);
+}
+
+/// check if value stored in db is Argon2 hash, other values are plain text passwords saved by older versions
+fn is_password_hash(stored: &str) -> bool {
+    return stored.starts_with("$argon2");
 }
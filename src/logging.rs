@@ -0,0 +1,52 @@
+//! structured logging for client and server, values of sensitive fields are never written to the log
+
+use tracing::field::Field;
+use tracing::Subscriber;
+use tracing_subscriber::field::MakeExt;
+use tracing_subscriber::fmt::{format, MakeWriter};
+use tracing_subscriber::EnvFilter;
+
+/// names of the log fields which values are replaced by REDACTED
+pub const SENSITIVE_FIELDS: [&str; 4] = ["password", "pass", "secret", "token"];
+/// text written to the log instead of sensitive value
+pub const REDACTED: &str = "********";
+
+/// initialize logging to stderr, level can be overridden by RUST_LOG environment variable
+pub fn init_logging(default_level: &str) {
+    let subscriber = create_subscriber(default_level, std::io::stderr);
+    if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
+        eprintln!("Initializing logging failed with error: {e}");
+    }
+}
+
+/// create subscriber which writes log with timestamps, levels and targets to the provided writer, sensitive fields are redacted
+pub fn create_subscriber<W>(default_level: &str, writer: W) -> impl Subscriber + Send + Sync
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_level));
+    return tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(true)
+        .with_writer(writer)
+        .fmt_fields(
+            format::debug_fn(|writer, field: &Field, value| {
+                if is_sensitive(field.name()) {
+                    return write!(writer, "{field}={REDACTED}");
+                }
+                if field.name() == "message" {
+                    return write!(writer, "{value:?}");
+                }
+                return write!(writer, "{field}={value:?}");
+            })
+            .delimited(" "),
+        )
+        .finish();
+}
+
+/// check if the field with provided name contains sensitive value
+fn is_sensitive(name: &str) -> bool {
+    let name = name.to_lowercase();
+    return SENSITIVE_FIELDS.iter().any(|s| name.contains(s));
+}
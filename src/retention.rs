@@ -0,0 +1,135 @@
+//! retention of stored messages, messages older than max age or over max count of the room are pruned together with their stored attachments
+
+use core::fmt;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::prelude::*;
+use tracing::warn;
+
+use crate::config::RetentionConfig;
+use crate::history::{utc_timestamp, ChatRecord};
+use crate::storage::Storage;
+use crate::transfer::is_valid_transfer_id;
+
+/// name of the group of private messages in prune report
+pub const PRIVATE_MESSAGES: &str = "private";
+
+/// what was removed by pruning
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PruneReport {
+    /// count of removed messages
+    pub messages: usize,
+    /// count of removed messages by room, private messages are counted together
+    pub rooms: BTreeMap<String, usize>,
+    /// count of removed attachment files
+    pub attachments: usize,
+    /// size of removed attachment files in bytes
+    pub attachment_bytes: u64,
+}
+
+impl PruneReport {
+    /// check if anything was removed
+    pub fn is_empty(&self) -> bool {
+        return self.messages == 0 && self.attachments == 0;
+    }
+}
+
+impl fmt::Display for PruneReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "Nothing to prune");
+        }
+        let rooms: Vec<String> = self
+            .rooms
+            .iter()
+            .map(|(room, count)| format!("{room}: {count}"))
+            .collect();
+        write!(
+            f,
+            "Pruned {} messages ({}) and {} attachments ({}B)",
+            self.messages,
+            rooms.join(", "),
+            self.attachments,
+            self.attachment_bytes
+        )
+    }
+}
+
+/// select messages which are over the retention limits, messages are grouped by room and private messages use the default policy
+pub fn expired_messages(
+    records: Vec<(String, ChatRecord)>,
+    config: &RetentionConfig,
+    now: DateTime<Utc>,
+) -> Vec<(String, ChatRecord)> {
+    let mut groups: HashMap<Option<String>, Vec<(String, ChatRecord)>> = HashMap::new();
+    for (id, record) in records {
+        let room = record.msg.get_room().map(|room| room.to_string());
+        groups.entry(room).or_default().push((id, record));
+    }
+    let mut expired = Vec::new();
+    for (room, mut records) in groups {
+        let policy = config.policy(room.as_deref());
+        records.sort_by(|(a, _), (b, _)| a.cmp(b));
+        // count of oldest messages over the limit
+        let over_count = match policy.max_messages {
+            Some(max) => records.len().saturating_sub(max),
+            None => 0,
+        };
+        let cutoff = policy
+            .max_age_days
+            .map(|days| utc_timestamp(now - chrono::Duration::days(days as i64)));
+        for (position, (id, record)) in records.into_iter().enumerate() {
+            let too_old = cutoff
+                .as_ref()
+                .is_some_and(|cutoff| record.timestamp < *cutoff);
+            if position < over_count || too_old {
+                expired.push((id, record));
+            }
+        }
+    }
+    expired.sort_by(|(a, _), (b, _)| a.cmp(b));
+    return expired;
+}
+
+/// remove expired messages from storage and their attachments from transfers folder, files which cannot be removed are only logged
+pub async fn prune(
+    storage: &dyn Storage,
+    config: &RetentionConfig,
+    transfers: &Path,
+) -> Result<PruneReport> {
+    let expired = expired_messages(storage.messages().await?, config, Utc::now());
+    let ids: Vec<String> = expired.iter().map(|(id, _)| id.clone()).collect();
+    let mut report = PruneReport {
+        messages: storage.delete_messages(&ids).await?,
+        ..PruneReport::default()
+    };
+    for (_, record) in &expired {
+        let room = record.msg.get_room().unwrap_or(PRIVATE_MESSAGES);
+        *report.rooms.entry(room.to_string()).or_default() += 1;
+        // transfer id comes from db, so it is checked before it is used as file name
+        let Some(id) = record
+            .msg
+            .get_transfer_id()
+            .filter(|id| is_valid_transfer_id(id))
+        else {
+            continue;
+        };
+        let path = transfers.join(id);
+        let Ok(metadata) = tokio::fs::metadata(&path).await else {
+            continue;
+        };
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {
+                report.attachments += 1;
+                report.attachment_bytes += metadata.len();
+            }
+            Err(e) => warn!(
+                "Removing attachment {} failed with error: {e}",
+                path.display()
+            ),
+        }
+    }
+    return Ok(report);
+}
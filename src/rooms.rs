@@ -0,0 +1,123 @@
+//! registry of chat rooms kept by the server, every room has its own broadcast channel and list of members
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+use anyhow::{bail, Result};
+use tokio::sync::broadcast;
+
+use crate::async_chat_msg::Envelope;
+
+/// name of the room every user joins after login, this room is never removed from registry
+pub const DEFAULT_ROOM: &str = "#general";
+/// capacity of broadcast channel created for every room
+const ROOM_CAPACITY: usize = 1024;
+
+/// message distributed through room broadcast channel together with address of the client who sent it
+pub type RoomMsg = (Envelope, SocketAddr);
+
+/// single chat room, contains broadcast sender for the room and names of the users in the room
+struct Room {
+    sender: broadcast::Sender<RoomMsg>,
+    members: HashSet<String>,
+}
+
+impl Room {
+    fn new() -> Self {
+        let (sender, _) = broadcast::channel(ROOM_CAPACITY);
+        return Room {
+            sender,
+            members: HashSet::new(),
+        };
+    }
+}
+
+/// registry of all existing rooms, rooms are created on first join and removed when last member leaves
+pub struct RoomRegistry {
+    rooms: HashMap<String, Room>,
+}
+
+impl Default for RoomRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RoomRegistry {
+    /// create new registry containing only default room
+    pub fn new() -> Self {
+        let mut rooms = HashMap::new();
+        rooms.insert(DEFAULT_ROOM.to_string(), Room::new());
+        return RoomRegistry { rooms };
+    }
+
+    /// add user to the room, room is created if it doesn't exist yet, returns receiver for messages of the room
+    pub fn join(&mut self, room: &str, user: &str) -> broadcast::Receiver<RoomMsg> {
+        let room = self.rooms.entry(room.to_string()).or_insert_with(Room::new);
+        room.members.insert(user.to_string());
+        return room.sender.subscribe();
+    }
+
+    /// remove user from the room, returns false if user was not member of the room
+    pub fn leave(&mut self, room: &str, user: &str) -> bool {
+        let Some(r) = self.rooms.get_mut(room) else {
+            return false;
+        };
+        let removed = r.members.remove(user);
+        if r.members.is_empty() && room != DEFAULT_ROOM {
+            self.rooms.remove(room);
+        }
+        return removed;
+    }
+
+    /// remove user from all rooms, returns names of the rooms user was member of
+    pub fn leave_all(&mut self, user: &str) -> Vec<String> {
+        let rooms: Vec<String> = self
+            .rooms
+            .iter()
+            .filter(|(_, r)| r.members.contains(user))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for room in &rooms {
+            self.leave(room, user);
+        }
+        return rooms;
+    }
+
+    /// check if user is member of the room
+    pub fn is_member(&self, room: &str, user: &str) -> bool {
+        return self
+            .rooms
+            .get(room)
+            .is_some_and(|r| r.members.contains(user));
+    }
+
+    /// get broadcast sender of the room, None if room doesn't exist
+    pub fn sender(&self, room: &str) -> Option<broadcast::Sender<RoomMsg>> {
+        return self.rooms.get(room).map(|r| r.sender.clone());
+    }
+
+    /// list of existing rooms with count of members, sorted by room name
+    pub fn list(&self) -> Vec<(String, usize)> {
+        let mut rooms: Vec<(String, usize)> = self
+            .rooms
+            .iter()
+            .map(|(name, r)| (name.clone(), r.members.len()))
+            .collect();
+        rooms.sort();
+        return rooms;
+    }
+}
+
+/// validate room name entered by user, leading '#' is added when missing, whitespace is not allowed
+pub fn normalize_room_name(room: &str) -> Result<String> {
+    let room = room.trim();
+    let name = room.strip_prefix('#').unwrap_or(room);
+    if name.is_empty() {
+        bail!("Room name cannot be empty");
+    }
+    if name.chars().any(|c| c.is_whitespace() || c == '#') {
+        bail!("Room name {room} contains invalid characters");
+    }
+    return Ok(format!("#{name}"));
+}
@@ -0,0 +1,291 @@
+//! versions of stored message records and migrations between them, chat db contains version of its records under schema key,
+//! older files are upgraded step by step before they are opened
+//! - version 1: records keyed by "local time|from" with the message only
+//! - version 2: records keyed by message id with UTC timestamp and message as tuple enum, e.g. {"timestamp": ..., "msg": {"Text": ["john", "hi", "#general"]}}
+//! - version 3: records with named fields, e.g. {"timestamp": ..., "type": "text", "from": "john", "room": "#general", "text": "hi"}
+
+use core::fmt;
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::async_chat_msg::AsyncChatMsgDB;
+use crate::history::{
+    message_id_at, parse_local_time, remember_message_id, utc_timestamp, ChatRecord,
+};
+use crate::rooms::DEFAULT_ROOM;
+use crate::{unique_file_path, write_file_atomic};
+
+/// version of records written by this version of the server
+pub const SCHEMA_VERSION: u64 = 3;
+/// key of chat db with version of its records, it is never message id
+pub const SCHEMA_KEY: &str = "schema_version";
+
+/// message of records of version 1 and 2 stored as tuple enum, room is the last field, so records saved before rooms existed
+/// are read as messages of the default room
+#[derive(Serialize, Deserialize)]
+enum MsgV2 {
+    Text(String, String, #[serde(default = "default_room")] String), // from, message, room
+    File(
+        String,
+        String,
+        #[serde(default = "default_room")] String,
+        #[serde(default)] String,
+        #[serde(default)] String,
+    ), // from, filename, room, sha256, transfer id
+    Image(
+        String,
+        String,
+        #[serde(default = "default_room")] String,
+        #[serde(default)] String,
+        #[serde(default)] String,
+    ), // from, filename, room, sha256, transfer id
+    Direct(String, String, String),                                  // from, to, message
+}
+
+fn default_room() -> String {
+    return DEFAULT_ROOM.to_string();
+}
+
+impl From<MsgV2> for AsyncChatMsgDB {
+    fn from(msg: MsgV2) -> Self {
+        return match msg {
+            MsgV2::Text(from, text, room) => AsyncChatMsgDB::Text { from, room, text },
+            MsgV2::File(from, filename, room, sha256, transfer) => AsyncChatMsgDB::File {
+                from,
+                room,
+                filename,
+                sha256,
+                transfer,
+            },
+            MsgV2::Image(from, filename, room, sha256, transfer) => AsyncChatMsgDB::Image {
+                from,
+                room,
+                filename,
+                sha256,
+                transfer,
+            },
+            MsgV2::Direct(from, to, text) => AsyncChatMsgDB::Direct { from, to, text },
+        };
+    }
+}
+
+/// read message stored as tuple enum of version 2, used to upgrade messages of other storages than chat db
+pub(crate) fn parse_msg_v2(msg: &str) -> serde_json::Result<AsyncChatMsgDB> {
+    return serde_json::from_str::<MsgV2>(msg).map(AsyncChatMsgDB::from);
+}
+
+/// record of chat db in version 2
+#[derive(Deserialize)]
+struct RecordV2 {
+    timestamp: String,
+    msg: MsgV2,
+}
+
+/// one step of the migration, converts records of the previous version to this version and returns count of converted records
+struct Migration {
+    version: u64,
+    description: &'static str,
+    run: fn(&mut Map<String, Value>) -> Result<usize>,
+}
+
+/// all migrations ordered by version
+const MIGRATIONS: [Migration; 2] = [
+    Migration {
+        version: 2,
+        description: "records keyed by message id with UTC timestamp",
+        run: key_by_message_id,
+    },
+    Migration {
+        version: 3,
+        description: "records with named fields",
+        run: name_record_fields,
+    },
+];
+
+/// result of the migration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// version of records before the migration
+    pub from: u64,
+    /// version of records after the migration
+    pub to: u64,
+    /// version and count of converted records of every step
+    pub steps: Vec<(u64, usize)>,
+}
+
+impl MigrationReport {
+    /// check if nothing had to be converted
+    pub fn is_current(&self) -> bool {
+        return self.from == self.to;
+    }
+}
+
+impl fmt::Display for MigrationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_current() {
+            return write!(f, "Chat db is in version {}", self.to);
+        }
+        let steps: Vec<String> = self
+            .steps
+            .iter()
+            .map(|(version, records)| format!("version {version}: {records} records"))
+            .collect();
+        write!(
+            f,
+            "Chat db migrated from version {} to {} ({})",
+            self.from,
+            self.to,
+            steps.join(", ")
+        )
+    }
+}
+
+/// version of records in chat db, files written before the version was stored are recognized by their keys and records,
+/// new empty db is in current version
+pub fn schema_version(records: &Map<String, Value>) -> Result<u64> {
+    if let Some(version) = records.get(SCHEMA_KEY) {
+        return version
+            .as_u64()
+            .with_context(|| format!("Schema version {version} of chat db is not valid"));
+    }
+    if records.keys().any(|key| key.parse::<u64>().is_err()) {
+        return Ok(1);
+    }
+    if records.values().any(|record| record.get("msg").is_some()) {
+        return Ok(2);
+    }
+    return Ok(SCHEMA_VERSION);
+}
+
+/// upgrade records to current version step by step and store the version, records which cannot be converted are only logged
+pub fn migrate_records(records: &mut Map<String, Value>) -> Result<MigrationReport> {
+    let from = schema_version(records)?;
+    if from > SCHEMA_VERSION {
+        bail!("Chat db has schema version {from}, this version of the server supports up to {SCHEMA_VERSION}");
+    }
+    let mut report = MigrationReport {
+        from,
+        to: SCHEMA_VERSION,
+        steps: Vec::new(),
+    };
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > from)
+    {
+        let converted = (migration.run)(records).with_context(|| {
+            format!(
+                "Migration of chat db to version {} failed",
+                migration.version
+            )
+        })?;
+        info!(
+            "Chat db upgraded to version {}, {}: {converted} records",
+            migration.version, migration.description
+        );
+        report.steps.push((migration.version, converted));
+    }
+    records.insert(SCHEMA_KEY.to_string(), Value::from(SCHEMA_VERSION));
+    return Ok(report);
+}
+
+/// upgrade chat db file to current version before it is opened, original file is kept as <file>.v<version>,
+/// ids of stored messages are remembered, so new ids are always bigger
+pub async fn migrate_chat_file(path: &Path) -> Result<MigrationReport> {
+    let contents = match fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(MigrationReport {
+                from: SCHEMA_VERSION,
+                to: SCHEMA_VERSION,
+                steps: Vec::new(),
+            });
+        }
+        Err(e) => {
+            return Err(
+                anyhow::Error::new(e).context(format!("Reading chat db {} failed", path.display()))
+            )
+        }
+    };
+    let Value::Object(mut records) = serde_json::from_str(&contents)
+        .with_context(|| format!("Chat db {} is not valid json", path.display()))?
+    else {
+        bail!("Chat db {} doesn't contain map of messages", path.display());
+    };
+    let report = migrate_records(&mut records)?;
+    for id in records.keys() {
+        remember_message_id(id);
+    }
+    if report.is_current() {
+        return Ok(report);
+    }
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let backup = unique_file_path(&path.with_file_name(format!("{name}.v{}", report.from)));
+    fs::copy(path, &backup)
+        .await
+        .with_context(|| format!("Keeping original chat db as {} failed", backup.display()))?;
+    let contents = serde_json::to_string_pretty(&Value::Object(records))?;
+    write_file_atomic(path, contents.as_bytes()).await?;
+    info!("{report}, original file was kept as {}", backup.display());
+    return Ok(report);
+}
+
+/// version 2: records keyed by "local time|from" get message id made from their time and UTC timestamp,
+/// keys of these records contain time without subseconds, so records with the same time keep order of their keys
+fn key_by_message_id(records: &mut Map<String, Value>) -> Result<usize> {
+    let mut taken: HashSet<String> = records
+        .keys()
+        .filter(|key| key.parse::<u64>().is_ok())
+        .cloned()
+        .collect();
+    let mut old = Vec::new();
+    for (key, value) in records.iter() {
+        if key.parse::<u64>().is_ok() || key == SCHEMA_KEY {
+            continue;
+        }
+        let time = key
+            .split_once('|')
+            .and_then(|(time, _)| parse_local_time(time));
+        let msg = serde_json::from_value::<MsgV2>(value.clone());
+        match (time, msg) {
+            (Some(time), Ok(msg)) => old.push((key.clone(), time, msg)),
+            _ => warn!("Record {key} in chat db cannot be converted"),
+        }
+    }
+    old.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+    let count = old.len();
+    for (key, time, msg) in old {
+        let id = message_id_at(time, &mut taken);
+        let record = serde_json::json!({ "timestamp": utc_timestamp(time), "msg": msg });
+        records.remove(&key);
+        records.insert(id, record);
+    }
+    return Ok(count);
+}
+
+/// version 3: message as tuple enum is replaced by fields with names next to the timestamp
+fn name_record_fields(records: &mut Map<String, Value>) -> Result<usize> {
+    let mut count = 0;
+    for (key, value) in records.iter_mut() {
+        if key == SCHEMA_KEY || value.get("msg").is_none() {
+            continue;
+        }
+        match serde_json::from_value::<RecordV2>(value.clone()) {
+            Ok(record) => {
+                let record = ChatRecord {
+                    timestamp: record.timestamp,
+                    msg: record.msg.into(),
+                };
+                *value = serde_json::to_value(record)?;
+                count += 1;
+            }
+            Err(e) => warn!("Record {key} in chat db cannot be converted: {e}"),
+        }
+    }
+    return Ok(count);
+}
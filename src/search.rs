@@ -0,0 +1,236 @@
+//! full-text search over stored messages, text of room messages and names of attachments are indexed by lowercase words
+//! query contains words which all have to be found and optional filters `from:user`, `since:date` and `page:n`
+
+use core::fmt;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use anyhow::{bail, Result};
+
+use crate::async_chat_msg::{AsyncChatMsg, AsyncChatMsgDB};
+use crate::history::{parse_time_filter, ChatRecord, HistoryEntry};
+
+/// count of results sent in one page
+pub const SEARCH_PAGE_SIZE: usize = 10;
+/// longest text of the message shown in results in characters, longer texts are shortened, so the page fits to one frame
+const SNIPPET_LENGTH: usize = 200;
+
+/// parsed search query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchQuery {
+    /// lowercase words which all have to be in the message
+    pub terms: Vec<String>,
+    /// author of the messages
+    pub from: Option<String>,
+    /// date or time as it was written by the user
+    pub since: Option<String>,
+    /// UTC timestamp of the since filter, messages stored before it are skipped
+    pub since_timestamp: Option<String>,
+    /// requested page of results, first page is 1
+    pub page: usize,
+}
+
+impl SearchQuery {
+    /// parse query like `deploy failed from:martin since:2024-05-01 page:2`, since is local date or local time, or RFC 3339 time
+    pub fn parse(query: &str) -> Result<SearchQuery> {
+        let mut search = SearchQuery {
+            terms: Vec::new(),
+            from: None,
+            since: None,
+            since_timestamp: None,
+            page: 1,
+        };
+        for word in query.split_whitespace() {
+            if let Some(from) = word.strip_prefix("from:") {
+                search.from = Some(from.to_string());
+            } else if let Some(since) = word.strip_prefix("since:") {
+                let Some(timestamp) = parse_time_filter(since) else {
+                    bail!("Date {since} is not valid, use e.g. since:2024-05-01");
+                };
+                search.since = Some(since.to_string());
+                search.since_timestamp = Some(timestamp);
+            } else if let Some(page) = word.strip_prefix("page:") {
+                match page.parse() {
+                    Ok(page) if page > 0 => search.page = page,
+                    _ => bail!("Page {page} is not valid, pages are numbered from 1"),
+                }
+            } else {
+                search.terms.extend(words(word));
+            }
+        }
+        if search.terms.is_empty() {
+            bail!(
+                "Nothing to search for, usage: .search <terms> [from:user] [since:date] [page:n]"
+            );
+        }
+        return Ok(search);
+    }
+
+    /// check if the message matches filters of the query, words are not checked
+    fn matches_filters(&self, record: &ChatRecord) -> bool {
+        if let Some(from) = &self.from {
+            if record.msg.get_from() != from {
+                return false;
+            }
+        }
+        if let Some(since) = &self.since_timestamp {
+            if record.timestamp < *since {
+                return false;
+            }
+        }
+        return true;
+    }
+}
+
+/// query is displayed without the page, so it can be used to ask for another page
+impl fmt::Display for SearchQuery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = self.terms.clone();
+        if let Some(from) = &self.from {
+            parts.push(format!("from:{from}"));
+        }
+        if let Some(since) = &self.since {
+            parts.push(format!("since:{since}"));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+/// split text to lowercase words, everything except letters and digits separates words
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    return text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase());
+}
+
+/// searchable text of the message, None for messages which are not indexed
+fn searchable_text(msg: &AsyncChatMsgDB) -> Option<&str> {
+    return match msg {
+        AsyncChatMsgDB::Text { text, .. } => Some(text),
+        AsyncChatMsgDB::File { filename, .. } => Some(filename),
+        AsyncChatMsgDB::Image { filename, .. } => Some(filename),
+        // private messages are not searchable, so they are never shown to other users
+        AsyncChatMsgDB::Direct { .. } => None,
+    };
+}
+
+/// inverted index from words to ids of messages containing them
+#[derive(Default)]
+pub struct SearchIndex {
+    words: HashMap<String, BTreeSet<String>>,
+    records: BTreeMap<String, ChatRecord>,
+}
+
+impl SearchIndex {
+    /// create empty index
+    pub fn new() -> SearchIndex {
+        return SearchIndex::default();
+    }
+
+    /// count of indexed messages
+    pub fn len(&self) -> usize {
+        return self.records.len();
+    }
+
+    /// check if no message is indexed
+    pub fn is_empty(&self) -> bool {
+        return self.records.is_empty();
+    }
+
+    /// add message stored under the id, messages which are not searchable are ignored, message with the same id is replaced
+    pub fn add(&mut self, id: &str, record: &ChatRecord) {
+        self.remove(id);
+        let Some(text) = searchable_text(&record.msg) else {
+            return;
+        };
+        for word in words(text) {
+            self.words.entry(word).or_default().insert(id.to_string());
+        }
+        self.records.insert(id.to_string(), record.clone());
+    }
+
+    /// remove message from index, nothing happens if message isn't indexed
+    pub fn remove(&mut self, id: &str) {
+        let Some(record) = self.records.remove(id) else {
+            return;
+        };
+        for word in searchable_text(&record.msg)
+            .map(words)
+            .into_iter()
+            .flatten()
+        {
+            if let Some(ids) = self.words.get_mut(&word) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.words.remove(&word);
+                }
+            }
+        }
+    }
+
+    /// messages containing all words of the query and matching its filters, ordered from the newest one
+    pub fn search(&self, query: &SearchQuery) -> Vec<HistoryEntry> {
+        let mut sets = Vec::new();
+        for term in &query.terms {
+            match self.words.get(term) {
+                Some(ids) => sets.push(ids),
+                None => return Vec::new(),
+            }
+        }
+        // the smallest set is checked against the others
+        sets.sort_by_key(|ids| ids.len());
+        let Some((smallest, others)) = sets.split_first() else {
+            return Vec::new();
+        };
+        return smallest
+            .iter()
+            .rev()
+            .filter(|id| others.iter().all(|ids| ids.contains(*id)))
+            .filter_map(|id| self.records.get(id))
+            .filter(|record| query.matches_filters(record))
+            .map(|record| (record.timestamp.clone(), record.msg.clone()))
+            .collect();
+    }
+}
+
+/// create reply with the requested page of results, long texts are shortened, empty page is sent when page is after the last one
+pub fn search_results(query: &SearchQuery, hits: Vec<HistoryEntry>) -> AsyncChatMsg {
+    let total = hits.len();
+    let page = hits
+        .into_iter()
+        .skip(
+            query
+                .page
+                .saturating_sub(1)
+                .saturating_mul(SEARCH_PAGE_SIZE),
+        )
+        .take(SEARCH_PAGE_SIZE)
+        .map(|(timestamp, msg)| (timestamp, snippet(msg)))
+        .collect();
+    return AsyncChatMsg::SearchResults {
+        query: query.to_string(),
+        page: query.page,
+        total,
+        results: page,
+    };
+}
+
+/// shorten text of the message to SNIPPET_LENGTH characters
+fn snippet(msg: AsyncChatMsgDB) -> AsyncChatMsgDB {
+    return match msg {
+        AsyncChatMsgDB::Text { from, room, text } if text.chars().count() > SNIPPET_LENGTH => {
+            let text: String = text.chars().take(SNIPPET_LENGTH).collect();
+            AsyncChatMsgDB::Text {
+                from,
+                room,
+                text: format!("{text}..."),
+            }
+        }
+        msg => msg,
+    };
+}
+
+/// count of pages needed for all results
+pub fn page_count(total: usize) -> usize {
+    return total.div_ceil(SEARCH_PAGE_SIZE);
+}
@@ -0,0 +1,359 @@
+//! snapshots of stored data taken while server runs, snapshot is folder with copies of database files, stored attachments
+//! and manifest with size and SHA-256 hash of every file, restore checks the whole snapshot before live data are replaced
+
+use core::fmt;
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use chrono::prelude::*;
+use serde_derive::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::config::StorageConfig;
+use crate::history::{utc_timestamp, ChatRecord};
+use crate::storage::{database_files, NanoDbStorage, SqliteStorage, Storage};
+use crate::transfer::{file_sha256, is_valid_transfer_id, TRANSFERS_FOLDER};
+use crate::{ensure_folder, unique_file_path, write_file_atomic};
+
+/// default folder where snapshots are created
+pub const SNAPSHOTS_FOLDER: &str = "snapshots";
+/// file with list of files in the snapshot, it is written last, so folder without it is not complete snapshot
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// file in the snapshot
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SnapshotFile {
+    /// name of the file
+    pub name: String,
+    /// size in bytes
+    pub size: u64,
+    /// SHA-256 hash of the content
+    pub sha256: String,
+}
+
+impl SnapshotFile {
+    /// describe the file in the folder
+    async fn read(folder: &Path, name: &str) -> Result<SnapshotFile> {
+        let path = folder.join(name);
+        return Ok(SnapshotFile {
+            name: name.to_string(),
+            size: fs::metadata(&path).await?.len(),
+            sha256: file_sha256(&path).await?,
+        });
+    }
+
+    /// check that the file in the folder has the same size and hash
+    async fn check(&self, folder: &Path) -> Result<()> {
+        let actual = SnapshotFile::read(folder, &self.name)
+            .await
+            .with_context(|| format!("File {} of snapshot cannot be read", self.name))?;
+        if actual != *self {
+            bail!("File {} of snapshot was changed or damaged", self.name);
+        }
+        return Ok(());
+    }
+}
+
+/// list of files in the snapshot
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// UTC time when the snapshot was taken
+    pub created: String,
+    /// database files in the snapshot folder
+    pub databases: Vec<SnapshotFile>,
+    /// stored attachments in attachments folder of the snapshot, named by transfer id
+    pub attachments: Vec<SnapshotFile>,
+}
+
+impl Manifest {
+    /// read manifest of the snapshot, names of files are checked, so they cannot point outside of the snapshot
+    pub async fn read(dir: &Path) -> Result<Manifest> {
+        let path = dir.join(MANIFEST_FILE);
+        let contents = fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("Folder {} is not a complete snapshot", dir.display()))?;
+        let manifest: Manifest = serde_json::from_str(&contents)
+            .with_context(|| format!("Manifest {} cannot be read", path.display()))?;
+        for file in &manifest.databases {
+            let mut components = Path::new(&file.name).components();
+            if !matches!(
+                (components.next(), components.next()),
+                (Some(Component::Normal(_)), None)
+            ) {
+                bail!("Database file {} of snapshot is not valid", file.name);
+            }
+        }
+        if let Some(file) = manifest
+            .attachments
+            .iter()
+            .find(|file| !is_valid_transfer_id(&file.name))
+        {
+            bail!("Attachment {} of snapshot is not valid", file.name);
+        }
+        return Ok(manifest);
+    }
+
+    /// size of all files in bytes
+    pub fn size(&self) -> u64 {
+        return self
+            .databases
+            .iter()
+            .chain(&self.attachments)
+            .map(|file| file.size)
+            .sum();
+    }
+}
+
+/// created snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotReport {
+    /// folder of the snapshot
+    pub dir: PathBuf,
+    /// files in the snapshot
+    pub manifest: Manifest,
+}
+
+impl fmt::Display for SnapshotReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Snapshot {} with {} database files and {} attachments ({}B)",
+            self.dir.display(),
+            self.manifest.databases.len(),
+            self.manifest.attachments.len(),
+            self.manifest.size()
+        )
+    }
+}
+
+/// write snapshot of the storage and attachments in transfers folder to new folder in root folder named by local time,
+/// attachments are copied after databases, so every attachment of stored messages is in the snapshot
+pub async fn take_snapshot(
+    storage: &dyn Storage,
+    transfers: &Path,
+    root: &Path,
+) -> Result<SnapshotReport> {
+    let now = Utc::now();
+    let name = now
+        .with_timezone(&Local)
+        .format("%Y%m%d-%H%M%S")
+        .to_string();
+    ensure_folder(&root.to_string_lossy()).await?;
+    let dir = unique_file_path(&root.join(name));
+    fs::create_dir(&dir).await?;
+    match write_snapshot(storage, transfers, &dir, now).await {
+        Ok(manifest) => return Ok(SnapshotReport { dir, manifest }),
+        Err(e) => {
+            // incomplete snapshot is removed, so it cannot be restored by mistake
+            _ = fs::remove_dir_all(&dir).await;
+            return Err(e.context(format!("Writing snapshot {} failed", dir.display())));
+        }
+    }
+}
+
+/// write databases, attachments and manifest to the folder
+async fn write_snapshot(
+    storage: &dyn Storage,
+    transfers: &Path,
+    dir: &Path,
+    now: DateTime<Utc>,
+) -> Result<Manifest> {
+    storage.snapshot(dir).await?;
+    let mut manifest = Manifest {
+        created: utc_timestamp(now),
+        databases: Vec::new(),
+        attachments: Vec::new(),
+    };
+    for name in file_names(dir).await? {
+        manifest
+            .databases
+            .push(SnapshotFile::read(dir, &name).await?);
+    }
+
+    let attachments = dir.join(TRANSFERS_FOLDER);
+    fs::create_dir(&attachments).await?;
+    if fs::metadata(transfers).await.is_ok() {
+        // partial uploads have other names than transfer id, they are not part of stored data
+        for name in file_names(transfers).await? {
+            if !is_valid_transfer_id(&name) {
+                continue;
+            }
+            match fs::copy(transfers.join(&name), attachments.join(&name)).await {
+                Ok(_) => {}
+                // attachment was pruned in the meantime
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+            manifest
+                .attachments
+                .push(SnapshotFile::read(&attachments, &name).await?);
+        }
+    }
+    write_file_atomic(
+        &dir.join(MANIFEST_FILE),
+        serde_json::to_string_pretty(&manifest)?.as_bytes(),
+    )
+    .await?;
+    return Ok(manifest);
+}
+
+/// sorted names of files in the folder, folders are skipped
+async fn file_names(folder: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    let mut entries = fs::read_dir(folder)
+        .await
+        .with_context(|| format!("Reading folder {} failed", folder.display()))?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_file() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    names.sort();
+    return Ok(names);
+}
+
+/// checked snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotCheck {
+    /// files in the snapshot
+    pub manifest: Manifest,
+    /// count of messages in snapshot databases
+    pub messages: usize,
+}
+
+/// check snapshot before it is restored to storage selected in configuration, every file has to match the manifest,
+/// databases have to be readable and every attachment of stored messages has to be in the snapshot
+pub async fn check_snapshot(dir: &Path, config: &StorageConfig) -> Result<SnapshotCheck> {
+    let manifest = Manifest::read(dir).await?;
+    for file in &manifest.databases {
+        file.check(dir).await?;
+    }
+    let attachments = dir.join(TRANSFERS_FOLDER);
+    for file in &manifest.attachments {
+        file.check(&attachments).await?;
+    }
+
+    let names: HashSet<&str> = manifest
+        .databases
+        .iter()
+        .map(|file| file.name.as_str())
+        .collect();
+    let mut files = Vec::new();
+    for live in database_files(config) {
+        let name = live.file_name().unwrap_or_default().to_string_lossy();
+        if !names.contains(name.as_ref()) {
+            bail!("Snapshot doesn't contain database file {name} of {config:?} storage");
+        }
+        files.push(dir.join(name.as_ref()));
+    }
+    let messages: Vec<(String, ChatRecord)> = match config {
+        StorageConfig::NanoDB => NanoDbStorage::check(&files[0], &files[1], &files[2]).await?,
+        StorageConfig::Sqlite(_) => SqliteStorage::check(&files[0]).await?,
+        StorageConfig::Memory => bail!("Memory storage cannot be restored"),
+    };
+
+    let stored: HashSet<&str> = manifest
+        .attachments
+        .iter()
+        .map(|file| file.name.as_str())
+        .collect();
+    let missing: Vec<&str> = messages
+        .iter()
+        .filter_map(|(_, record)| record.msg.get_transfer_id())
+        .filter(|id| !stored.contains(id))
+        .collect();
+    if let Some(id) = missing.first() {
+        bail!(
+            "Snapshot is missing {} attachments of stored messages, e.g. {id}",
+            missing.len()
+        );
+    }
+    return Ok(SnapshotCheck {
+        messages: messages.len(),
+        manifest,
+    });
+}
+
+/// restored snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestoreReport {
+    /// checked snapshot which replaced live data
+    pub check: SnapshotCheck,
+    /// folder where replaced live data were moved
+    pub backup: PathBuf,
+}
+
+impl fmt::Display for RestoreReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Restored {} messages and {} attachments, replaced data were moved to {}",
+            self.check.messages,
+            self.check.manifest.attachments.len(),
+            self.backup.display()
+        )
+    }
+}
+
+/// check the snapshot and replace live database files and transfers folder with it, server has to be stopped,
+/// replaced data are moved to new folder in backup root, so restore can be undone
+pub async fn restore_snapshot(
+    dir: &Path,
+    config: &StorageConfig,
+    transfers: &Path,
+    backup_root: &Path,
+) -> Result<RestoreReport> {
+    let check = check_snapshot(dir, config).await?;
+    let name = Local::now()
+        .format("before-restore-%Y%m%d-%H%M%S")
+        .to_string();
+    ensure_folder(&backup_root.to_string_lossy()).await?;
+    let backup = unique_file_path(&backup_root.join(name));
+    fs::create_dir(&backup).await?;
+    replace_live_data(dir, config, transfers, &backup)
+        .await
+        .with_context(|| format!("Restore failed, replaced data are in {}", backup.display()))?;
+    return Ok(RestoreReport { check, backup });
+}
+
+/// move live data to backup folder and copy the snapshot in their place
+async fn replace_live_data(
+    dir: &Path,
+    config: &StorageConfig,
+    transfers: &Path,
+    backup: &Path,
+) -> Result<()> {
+    for live in database_files(config) {
+        let name = live.file_name().unwrap_or_default().to_string_lossy();
+        // journal files of SQLite belong to the replaced database
+        for suffix in ["", "-wal", "-shm"] {
+            let file = live.with_file_name(format!("{name}{suffix}"));
+            if fs::metadata(&file).await.is_ok() {
+                fs::rename(&file, backup.join(format!("{name}{suffix}"))).await?;
+            }
+        }
+        copy_file_atomic(&dir.join(name.as_ref()), &live).await?;
+    }
+    if fs::metadata(transfers).await.is_ok() {
+        fs::rename(transfers, backup.join(TRANSFERS_FOLDER)).await?;
+    }
+    fs::create_dir_all(transfers).await?;
+    let attachments = dir.join(TRANSFERS_FOLDER);
+    for name in file_names(&attachments).await? {
+        copy_file_atomic(&attachments.join(&name), &transfers.join(&name)).await?;
+    }
+    return Ok(());
+}
+
+/// copy file through temporary file, so the target is never left half written
+async fn copy_file_atomic(from: &Path, to: &Path) -> Result<()> {
+    let name = to.file_name().unwrap_or_default().to_string_lossy();
+    let temp = to.with_file_name(format!("{name}.tmp"));
+    fs::copy(from, &temp)
+        .await
+        .with_context(|| format!("Copying {} to {} failed", from.display(), to.display()))?;
+    fs::File::open(&temp).await?.sync_all().await?;
+    fs::rename(&temp, to).await?;
+    return Ok(());
+}
@@ -0,0 +1,108 @@
+//! search index layer over other storage, index is built from stored messages when storage is opened and updated with every new message
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::RwLock;
+
+use super::Storage;
+use crate::history::{ChatRecord, HistoryEntry};
+use crate::search::{SearchIndex, SearchQuery};
+
+/// storage which answers searches from index kept in memory, everything else is passed to inner storage
+pub struct IndexedStorage {
+    inner: Arc<dyn Storage>,
+    index: RwLock<SearchIndex>,
+}
+
+impl IndexedStorage {
+    /// wrap the storage and index all its messages
+    pub async fn open(inner: Arc<dyn Storage>) -> Result<IndexedStorage> {
+        let mut index = SearchIndex::new();
+        for (id, record) in inner.messages().await? {
+            index.add(&id, &record);
+        }
+        return Ok(IndexedStorage {
+            inner,
+            index: RwLock::new(index),
+        });
+    }
+
+    /// count of indexed messages
+    pub async fn indexed(&self) -> usize {
+        return self.index.read().await.len();
+    }
+}
+
+#[async_trait]
+impl Storage for IndexedStorage {
+    async fn insert_message(&self, id: &str, record: ChatRecord) -> Result<()> {
+        // index is changed only when message is really stored, otherwise it would be found but not stored
+        self.inner.insert_message(id, record.clone()).await?;
+        self.index.write().await.add(id, &record);
+        return Ok(());
+    }
+
+    async fn insert_messages(&self, records: Vec<(String, ChatRecord)>) -> Result<()> {
+        self.inner.insert_messages(records.clone()).await?;
+        let mut index = self.index.write().await;
+        for (id, record) in &records {
+            index.add(id, record);
+        }
+        return Ok(());
+    }
+
+    async fn flush(&self) -> Result<()> {
+        return self.inner.flush().await;
+    }
+
+    async fn delete_messages(&self, ids: &[String]) -> Result<usize> {
+        // index is changed only when messages are really deleted, otherwise they would stay stored but not searchable
+        let deleted = self.inner.delete_messages(ids).await?;
+        let mut index = self.index.write().await;
+        for id in ids {
+            index.remove(id);
+        }
+        return Ok(deleted);
+    }
+
+    async fn snapshot(&self, dir: &Path) -> Result<()> {
+        return self.inner.snapshot(dir).await;
+    }
+
+    async fn messages(&self) -> Result<Vec<(String, ChatRecord)>> {
+        return self.inner.messages().await;
+    }
+
+    async fn history(
+        &self,
+        user: &str,
+        room: &str,
+        since: Option<&str>,
+        count: usize,
+    ) -> Result<Vec<HistoryEntry>> {
+        return self.inner.history(user, room, since, count).await;
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<Vec<HistoryEntry>> {
+        return Ok(self.index.read().await.search(query));
+    }
+
+    async fn get_password(&self, user: &str) -> Result<Option<String>> {
+        return self.inner.get_password(user).await;
+    }
+
+    async fn set_password(&self, user: &str, password: &str) -> Result<()> {
+        return self.inner.set_password(user, password).await;
+    }
+
+    async fn last_seen(&self, user: &str) -> Result<Option<String>> {
+        return self.inner.last_seen(user).await;
+    }
+
+    async fn set_last_seen(&self, user: &str, timestamp: &str) -> Result<()> {
+        return self.inner.set_last_seen(user, timestamp).await;
+    }
+}
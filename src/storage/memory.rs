@@ -0,0 +1,73 @@
+//! storage keeping everything in memory, data are lost when the server stops, used for tests
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::RwLock;
+
+use super::Storage;
+use crate::history::ChatRecord;
+
+/// messages ordered by id and users in memory
+#[derive(Default)]
+pub struct MemoryStorage {
+    messages: RwLock<BTreeMap<String, ChatRecord>>,
+    passwords: RwLock<HashMap<String, String>>,
+    seen: RwLock<HashMap<String, String>>,
+}
+
+impl MemoryStorage {
+    /// create empty storage
+    pub fn new() -> MemoryStorage {
+        return MemoryStorage::default();
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn insert_message(&self, id: &str, record: ChatRecord) -> Result<()> {
+        self.messages.write().await.insert(id.to_string(), record);
+        return Ok(());
+    }
+
+    async fn delete_messages(&self, ids: &[String]) -> Result<usize> {
+        let mut messages = self.messages.write().await;
+        return Ok(ids
+            .iter()
+            .filter(|id| messages.remove(*id).is_some())
+            .count());
+    }
+
+    async fn messages(&self) -> Result<Vec<(String, ChatRecord)>> {
+        let messages = self.messages.read().await;
+        return Ok(messages
+            .iter()
+            .map(|(id, record)| (id.clone(), record.clone()))
+            .collect());
+    }
+
+    async fn get_password(&self, user: &str) -> Result<Option<String>> {
+        return Ok(self.passwords.read().await.get(user).cloned());
+    }
+
+    async fn set_password(&self, user: &str, password: &str) -> Result<()> {
+        self.passwords
+            .write()
+            .await
+            .insert(user.to_string(), password.to_string());
+        return Ok(());
+    }
+
+    async fn last_seen(&self, user: &str) -> Result<Option<String>> {
+        return Ok(self.seen.read().await.get(user).cloned());
+    }
+
+    async fn set_last_seen(&self, user: &str, timestamp: &str) -> Result<()> {
+        self.seen
+            .write()
+            .await
+            .insert(user.to_string(), timestamp.to_string());
+        return Ok(());
+    }
+}
@@ -0,0 +1,145 @@
+//! storage of chat messages and users, server uses it through Storage trait, so backend can be chosen in configuration
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+
+use crate::config::{Durability, StorageConfig};
+use crate::history::{select_history, ChatRecord, HistoryEntry};
+use crate::search::{SearchIndex, SearchQuery};
+
+/// reference indexed file
+pub mod indexed;
+/// reference memory file
+pub mod memory;
+/// reference nano file
+pub mod nano;
+/// reference sqlite file
+pub mod sqlite;
+/// reference write_behind file
+pub mod write_behind;
+
+pub use indexed::IndexedStorage;
+pub use memory::MemoryStorage;
+pub use nano::NanoDbStorage;
+pub use sqlite::SqliteStorage;
+pub use write_behind::WriteBehindStorage;
+
+/// storage of messages, password hashes and times when users were last online
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// store the message under the id, message with the same id is replaced
+    async fn insert_message(&self, id: &str, record: ChatRecord) -> Result<()>;
+
+    /// store all messages at once, backends override it to write them in one write or transaction
+    async fn insert_messages(&self, records: Vec<(String, ChatRecord)>) -> Result<()> {
+        for (id, record) in records {
+            self.insert_message(&id, record).await?;
+        }
+        return Ok(());
+    }
+
+    /// write all changes waiting in memory, called before server stops
+    async fn flush(&self) -> Result<()> {
+        return Ok(());
+    }
+
+    /// remove messages with the ids, returns count of removed messages, unknown ids are ignored
+    async fn delete_messages(&self, ids: &[String]) -> Result<usize>;
+
+    /// write consistent copy of stored data to the folder while storage is used, files keep names of live files
+    async fn snapshot(&self, dir: &Path) -> Result<()> {
+        bail!("Storage cannot write snapshot to {}", dir.display());
+    }
+
+    /// all stored messages with their ids ordered by id
+    async fn messages(&self) -> Result<Vec<(String, ChatRecord)>>;
+
+    /// last `count` messages of the room visible to the user ordered from the oldest one, messages stored before `since` are skipped
+    async fn history(
+        &self,
+        user: &str,
+        room: &str,
+        since: Option<&str>,
+        count: usize,
+    ) -> Result<Vec<HistoryEntry>> {
+        let records = self.messages().await?;
+        return Ok(select_history(records, user, room, since, count));
+    }
+
+    /// messages matching the query ordered from the newest one, default implementation indexes all messages for every search
+    async fn search(&self, query: &SearchQuery) -> Result<Vec<HistoryEntry>> {
+        let mut index = SearchIndex::new();
+        for (id, record) in self.messages().await? {
+            index.add(&id, &record);
+        }
+        return Ok(index.search(query));
+    }
+
+    /// get password hash of the user, None if user doesn't exist
+    async fn get_password(&self, user: &str) -> Result<Option<String>>;
+
+    /// store password hash of the user, user is created if doesn't exist
+    async fn set_password(&self, user: &str, password: &str) -> Result<()>;
+
+    /// get time when the user was last online, None for users who were never seen
+    async fn last_seen(&self, user: &str) -> Result<Option<String>>;
+
+    /// store time when the user was last online
+    async fn set_last_seen(&self, user: &str, timestamp: &str) -> Result<()>;
+}
+
+/// open backend selected in configuration without any other layer, used by tools working with stored messages
+pub async fn open_backend(config: &StorageConfig) -> Result<Arc<dyn Storage>> {
+    return Ok(match config {
+        StorageConfig::NanoDB => Arc::new(
+            NanoDbStorage::open(nano::CHAT_DB_FILE, nano::USERS_DB_FILE, nano::SEEN_DB_FILE)
+                .await?,
+        ),
+        StorageConfig::Sqlite(path) => Arc::new(SqliteStorage::open(path).await?),
+        StorageConfig::Memory => Arc::new(MemoryStorage::new()),
+    });
+}
+
+/// read stored messages of backend selected in configuration without creating or migrating its files,
+/// used by tools which must not change the storage
+pub async fn read_messages(config: &StorageConfig) -> Result<Vec<(String, ChatRecord)>> {
+    return match config {
+        StorageConfig::NanoDB => NanoDbStorage::read_messages(Path::new(nano::CHAT_DB_FILE)).await,
+        StorageConfig::Sqlite(path) => SqliteStorage::read_messages(Path::new(path)).await,
+        StorageConfig::Memory => Ok(Vec::new()),
+    };
+}
+
+/// files with data of backend selected in configuration, snapshot of the backend contains files with the same names
+pub fn database_files(config: &StorageConfig) -> Vec<PathBuf> {
+    return match config {
+        StorageConfig::NanoDB => [nano::CHAT_DB_FILE, nano::USERS_DB_FILE, nano::SEEN_DB_FILE]
+            .iter()
+            .map(PathBuf::from)
+            .collect(),
+        StorageConfig::Sqlite(path) => vec![PathBuf::from(path)],
+        StorageConfig::Memory => Vec::new(),
+    };
+}
+
+/// open storage selected in configuration, messages are written in batches unless immediate durability is required,
+/// stored messages are indexed for search
+pub async fn open_storage(
+    config: &StorageConfig,
+    durability: &Durability,
+) -> Result<Arc<dyn Storage>> {
+    let storage = open_backend(config).await?;
+    let storage: Arc<dyn Storage> = match durability {
+        Durability::Immediate => storage,
+        Durability::Batched {
+            max_pending,
+            interval_ms,
+        } => WriteBehindStorage::new(storage, *max_pending, Duration::from_millis(*interval_ms)),
+    };
+    // index wraps the write-behind layer, so messages waiting to be written can be found too
+    return Ok(Arc::new(IndexedStorage::open(storage).await?));
+}
@@ -0,0 +1,251 @@
+//! storage in json files using NanoDB, messages, users and last seen times are in separate files
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use nanodb::{error::NanoDBError, nanodb::NanoDB};
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+
+use super::Storage;
+use crate::history::ChatRecord;
+use crate::schema::{migrate_chat_file, migrate_records, SCHEMA_KEY, SCHEMA_VERSION};
+use crate::write_file_atomic;
+
+/// default file with chat messages
+pub const CHAT_DB_FILE: &str = "chatdb.json";
+/// default file with password hashes of users
+pub const USERS_DB_FILE: &str = "userdb.json";
+/// default file with times when users were last online
+pub const SEEN_DB_FILE: &str = "seendb.json";
+
+/// NanoDB database with its file, whole file is written atomically through temporary file, so crash during write cannot corrupt it
+#[derive(Clone)]
+pub struct JsonDb {
+    db: NanoDB,
+    path: PathBuf,
+    /// held while file is written, so older content never replaces newer one
+    writing: Arc<Mutex<()>>,
+}
+
+impl JsonDb {
+    /// open database file, empty database is used if file doesn't exist
+    pub fn open(path: impl Into<PathBuf>) -> Result<JsonDb> {
+        let path = path.into();
+        let db = NanoDB::open(&path)
+            .with_context(|| format!("Opening db file {} failed", path.display()))?;
+        return Ok(JsonDb {
+            db,
+            path,
+            writing: Arc::new(Mutex::new(())),
+        });
+    }
+
+    /// database kept in memory, changes are written to file by write
+    pub fn db(&self) -> NanoDB {
+        return self.db.clone();
+    }
+
+    /// file of the database
+    pub fn path(&self) -> &Path {
+        return &self.path;
+    }
+
+    /// write whole database to its file
+    pub async fn write(&self) -> Result<()> {
+        return self.write_to(&self.path).await;
+    }
+
+    /// write whole database to the file, used for snapshots, writes of the database wait until it is written
+    pub async fn write_to(&self, path: &Path) -> Result<()> {
+        let _writing = self.writing.lock().await;
+        let contents = serde_json::to_string_pretty(&self.db.data().await.inner())?;
+        return write_file_atomic(path, contents.as_bytes()).await;
+    }
+}
+
+/// NanoDB databases for messages, users and last seen times, whole file is written after every change
+#[derive(Clone)]
+pub struct NanoDbStorage {
+    chat: JsonDb,
+    users: JsonDb,
+    seen: JsonDb,
+}
+
+impl NanoDbStorage {
+    /// create storage from opened databases, databases can be the same
+    pub fn new(chat: JsonDb, users: JsonDb, seen: JsonDb) -> NanoDbStorage {
+        return NanoDbStorage { chat, users, seen };
+    }
+
+    /// open database files, files are created if they don't exist and messages in older format are converted
+    pub async fn open(chat_file: &str, users_file: &str, seen_file: &str) -> Result<NanoDbStorage> {
+        migrate_chat_file(Path::new(chat_file)).await?;
+        let chat = JsonDb::open(chat_file)?;
+        // new db gets version of its records with the first write
+        if chat.db.data().await.get(SCHEMA_KEY).is_err() {
+            chat.db().insert(SCHEMA_KEY, SCHEMA_VERSION).await?;
+        }
+        let users = JsonDb::open(users_file)?;
+        let seen = JsonDb::open(seen_file)?;
+        return Ok(NanoDbStorage::new(chat, users, seen));
+    }
+
+    /// read messages of chat db file without changing it, messages in older format are converted in memory only,
+    /// missing file has no messages
+    pub async fn read_messages(chat_file: &Path) -> Result<Vec<(String, ChatRecord)>> {
+        if !tokio::fs::try_exists(chat_file).await? {
+            return Ok(Vec::new());
+        }
+        let mut records = read_json_object(chat_file).await?;
+        migrate_records(&mut records)
+            .with_context(|| format!("Chat db {} cannot be migrated", chat_file.display()))?;
+        records.remove(SCHEMA_KEY);
+        let mut messages: Vec<(String, ChatRecord)> = parse_values(records, chat_file)?;
+        messages.sort_by(|(a, _), (b, _)| a.cmp(b));
+        return Ok(messages);
+    }
+
+    /// read database files strictly, every message has to be readable after migration to current version and users
+    /// and last seen times have to be maps of strings, used to check snapshot before it replaces live data, returns stored messages
+    pub async fn check(
+        chat_file: &Path,
+        users_file: &Path,
+        seen_file: &Path,
+    ) -> Result<Vec<(String, ChatRecord)>> {
+        let mut records = read_json_object(chat_file).await?;
+        migrate_records(&mut records)
+            .with_context(|| format!("Chat db {} cannot be migrated", chat_file.display()))?;
+        records.remove(SCHEMA_KEY);
+        let mut messages: Vec<(String, ChatRecord)> = parse_values(records, chat_file)?;
+        parse_values::<String>(read_json_object(users_file).await?, users_file)?;
+        parse_values::<String>(read_json_object(seen_file).await?, seen_file)?;
+        messages.sort_by(|(a, _), (b, _)| a.cmp(b));
+        return Ok(messages);
+    }
+}
+
+/// read json file with map
+async fn read_json_object(path: &Path) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let contents = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Reading file {} failed", path.display()))?;
+    let serde_json::Value::Object(values) = serde_json::from_str(&contents)
+        .with_context(|| format!("File {} is not valid json", path.display()))?
+    else {
+        bail!("File {} doesn't contain map", path.display());
+    };
+    return Ok(values);
+}
+
+/// read all values of the map read from the file as one type
+fn parse_values<T: serde::de::DeserializeOwned>(
+    values: serde_json::Map<String, serde_json::Value>,
+    path: &Path,
+) -> Result<Vec<(String, T)>> {
+    let mut map = Vec::new();
+    for (key, value) in values {
+        let value = serde_json::from_value(value)
+            .with_context(|| format!("Value {key} in file {} cannot be read", path.display()))?;
+        map.push((key, value));
+    }
+    return Ok(map);
+}
+
+/// insert value to db and write whole db to file, failed write is only logged, value stays in memory
+async fn insert_and_write<T: serde::Serialize>(db: &JsonDb, key: &str, value: T) -> Result<()> {
+    db.db().insert(key, value).await?;
+    if let Err(e) = db.write().await {
+        error!("Saving db to file failed with error {e}");
+    }
+    return Ok(());
+}
+
+/// get string value from db, None if key doesn't exist
+async fn get_string(db: &JsonDb, key: &str) -> Result<Option<String>> {
+    let value = db.db.data().await.get(key).and_then(|value| value.into());
+    return match value {
+        Ok(value) => Ok(Some(value)),
+        Err(NanoDBError::KeyNotFound(_)) => Ok(None),
+        Err(e) => Err(e.into()),
+    };
+}
+
+#[async_trait]
+impl Storage for NanoDbStorage {
+    async fn insert_message(&self, id: &str, record: ChatRecord) -> Result<()> {
+        return insert_and_write(&self.chat, id, record).await;
+    }
+
+    async fn insert_messages(&self, records: Vec<(String, ChatRecord)>) -> Result<()> {
+        let mut db = self.chat.db();
+        for (id, record) in records {
+            db.insert(&id, record).await?;
+        }
+        // whole file is written only once for all messages
+        return self.chat.write().await;
+    }
+
+    async fn delete_messages(&self, ids: &[String]) -> Result<usize> {
+        let mut db = self.chat.db();
+        let mut removed = 0;
+        for id in ids {
+            match db.remove(id).await {
+                Ok(()) => removed += 1,
+                Err(NanoDBError::KeyNotFound(_)) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if removed > 0 {
+            self.chat.write().await?;
+        }
+        return Ok(removed);
+    }
+
+    async fn messages(&self) -> Result<Vec<(String, ChatRecord)>> {
+        let serde_json::Value::Object(records) = self.chat.db.data().await.inner() else {
+            bail!("Chat db doesn't contain map of messages");
+        };
+        let mut messages = Vec::new();
+        for (key, value) in records {
+            if key == SCHEMA_KEY {
+                continue;
+            }
+            match serde_json::from_value(value) {
+                Ok(record) => messages.push((key, record)),
+                Err(e) => warn!("Message {key} in db cannot be read: {e}"),
+            }
+        }
+        messages.sort_by(|(a, _), (b, _)| a.cmp(b));
+        return Ok(messages);
+    }
+
+    async fn snapshot(&self, dir: &Path) -> Result<()> {
+        // files keep their names, so snapshot of default files can be restored by copying
+        for db in [&self.chat, &self.users, &self.seen] {
+            let Some(name) = db.path().file_name() else {
+                bail!("Db file {} has no name", db.path().display());
+            };
+            db.write_to(&dir.join(name)).await?;
+        }
+        return Ok(());
+    }
+
+    async fn get_password(&self, user: &str) -> Result<Option<String>> {
+        return get_string(&self.users, user).await;
+    }
+
+    async fn set_password(&self, user: &str, password: &str) -> Result<()> {
+        return insert_and_write(&self.users, user, password).await;
+    }
+
+    async fn last_seen(&self, user: &str) -> Result<Option<String>> {
+        return get_string(&self.seen, user).await;
+    }
+
+    async fn set_last_seen(&self, user: &str, timestamp: &str) -> Result<()> {
+        return insert_and_write(&self.seen, user, timestamp).await;
+    }
+}
@@ -0,0 +1,373 @@
+//! storage in embedded SQLite database, history is selected by sql query and every change is durable when it returns
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use tracing::info;
+
+use super::Storage;
+use crate::async_chat_msg::AsyncChatMsgDB;
+use crate::history::{remember_message_id, ChatRecord, HistoryEntry};
+use crate::rooms::DEFAULT_ROOM;
+use crate::schema::{parse_msg_v2, MigrationReport, SCHEMA_VERSION};
+
+/// tables are created when database is opened, room is null for private messages
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS messages (
+        id TEXT PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        from_user TEXT NOT NULL,
+        to_user TEXT,
+        room TEXT,
+        msg TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS messages_room ON messages (room, id);
+    CREATE TABLE IF NOT EXISTS users (
+        name TEXT PRIMARY KEY,
+        password TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS seen (
+        name TEXT PRIMARY KEY,
+        timestamp TEXT NOT NULL
+    );
+";
+
+/// connection to SQLite database file, queries run on blocking threads, so they don't block async workers
+#[derive(Clone)]
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+    path: PathBuf,
+}
+
+impl SqliteStorage {
+    /// open database file, file and tables are created if they don't exist
+    pub async fn open(path: &str) -> Result<SqliteStorage> {
+        let file = path.to_string();
+        let conn = tokio::task::spawn_blocking(move || -> Result<Connection> {
+            let conn = Connection::open(&file)?;
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            conn.execute_batch(SCHEMA)?;
+            let report = migrate_messages(&conn)?;
+            if !report.is_current() {
+                info!("{report}");
+            }
+            return Ok(conn);
+        })
+        .await??;
+        let storage = SqliteStorage {
+            conn: Arc::new(Mutex::new(conn)),
+            path: PathBuf::from(path),
+        };
+        // new messages get bigger ids than the stored ones
+        let last: Option<String> = storage
+            .call(|conn| conn.query_row("SELECT MAX(id) FROM messages", [], |row| row.get(0)))
+            .await?;
+        if let Some(last) = last {
+            remember_message_id(&last);
+        }
+        return Ok(storage);
+    }
+
+    /// open database file read only and check its integrity, every message has to be readable,
+    /// used to check snapshot before it replaces live data, returns stored messages
+    pub async fn check(path: &Path) -> Result<Vec<(String, ChatRecord)>> {
+        let path = path.to_path_buf();
+        return tokio::task::spawn_blocking(move || -> Result<Vec<(String, ChatRecord)>> {
+            let conn = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                .with_context(|| format!("Opening database {} failed", path.display()))?;
+            let integrity: String =
+                conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+            if integrity != "ok" {
+                bail!("Database {} is damaged: {integrity}", path.display());
+            }
+            return select_messages(&conn).map_err(anyhow::Error::from);
+        })
+        .await?;
+    }
+
+    /// read messages of database file read only without migrating it, messages in older format are converted in memory only,
+    /// missing file has no messages
+    pub async fn read_messages(path: &Path) -> Result<Vec<(String, ChatRecord)>> {
+        if !tokio::fs::try_exists(path).await? {
+            return Ok(Vec::new());
+        }
+        let path = path.to_path_buf();
+        return tokio::task::spawn_blocking(move || -> Result<Vec<(String, ChatRecord)>> {
+            let conn = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                .with_context(|| format!("Opening database {} failed", path.display()))?;
+            let from = stored_version(&conn)?;
+            if from >= 3 {
+                return select_messages(&conn).map_err(anyhow::Error::from);
+            }
+            let mut statement =
+                conn.prepare("SELECT id, timestamp, msg FROM messages ORDER BY id")?;
+            let rows: Vec<(String, String, String)> = statement
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                .collect::<rusqlite::Result<_>>()?;
+            let mut messages = Vec::new();
+            for (id, timestamp, msg) in rows {
+                let msg = parse_msg_v2(&msg)
+                    .with_context(|| format!("Message {id} in database cannot be converted"))?;
+                messages.push((id, ChatRecord { timestamp, msg }));
+            }
+            return Ok(messages);
+        })
+        .await?;
+    }
+
+    /// run the query on blocking thread
+    async fn call<T, F>(&self, query: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        return tokio::task::spawn_blocking(move || {
+            let conn = conn
+                .lock()
+                .map_err(|_| anyhow!("Connection to SQLite database is poisoned"))?;
+            return query(&conn).map_err(anyhow::Error::from);
+        })
+        .await?;
+    }
+}
+
+/// values of one row of messages table
+struct MessageRow {
+    id: String,
+    timestamp: String,
+    from: String,
+    to: Option<String>,
+    room: Option<String>,
+    msg: String,
+}
+
+impl MessageRow {
+    /// create row from the record, message is stored as json
+    fn new(id: String, record: ChatRecord) -> Result<MessageRow> {
+        let to = match &record.msg {
+            AsyncChatMsgDB::Direct { to, .. } => Some(to.clone()),
+            _ => None,
+        };
+        return Ok(MessageRow {
+            id,
+            from: record.msg.get_from().to_string(),
+            room: record.msg.get_room().map(|room| room.to_string()),
+            msg: serde_json::to_string(&record.msg)?,
+            timestamp: record.timestamp,
+            to,
+        });
+    }
+}
+
+/// read message record from row with timestamp and message in json
+fn read_record(timestamp: String, msg: String) -> rusqlite::Result<ChatRecord> {
+    let msg: AsyncChatMsgDB = serde_json::from_str(&msg).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e.into())
+    })?;
+    return Ok(ChatRecord { timestamp, msg });
+}
+
+/// upgrade messages to current version stored as user_version of the database, databases without version were created
+/// with messages of version 2, which are stored as tuple enum
+fn migrate_messages(conn: &Connection) -> Result<MigrationReport> {
+    let from = stored_version(conn)?;
+    let mut report = MigrationReport {
+        from,
+        to: SCHEMA_VERSION,
+        steps: Vec::new(),
+    };
+    let transaction = conn.unchecked_transaction()?;
+    if from < 3 {
+        let rows: Vec<(String, String)> = transaction
+            .prepare("SELECT id, msg FROM messages")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        for (id, msg) in &rows {
+            let msg = parse_msg_v2(msg)
+                .with_context(|| format!("Message {id} in database cannot be converted"))?;
+            let msg = serde_json::to_string(&msg)?;
+            transaction.execute(
+                "UPDATE messages SET msg = ?1 WHERE id = ?2",
+                params![msg, id],
+            )?;
+        }
+        report.steps.push((3, rows.len()));
+    }
+    transaction.pragma_update(None, "user_version", SCHEMA_VERSION as i64)?;
+    transaction.commit()?;
+    return Ok(report);
+}
+
+/// version of messages in the database, databases without version and without messages are new
+fn stored_version(conn: &Connection) -> Result<u64> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    let stored: i64 = conn.query_row("SELECT COUNT(*) FROM messages", [], |row| row.get(0))?;
+    let from = match (version, stored) {
+        // new database
+        (0, 0) => SCHEMA_VERSION,
+        (0, _) => 2,
+        (version, _) => version as u64,
+    };
+    if from > SCHEMA_VERSION {
+        bail!("Database has schema version {from}, this version of the server supports up to {SCHEMA_VERSION}");
+    }
+    return Ok(from);
+}
+
+/// all messages ordered by id
+fn select_messages(conn: &Connection) -> rusqlite::Result<Vec<(String, ChatRecord)>> {
+    let mut statement = conn.prepare("SELECT id, timestamp, msg FROM messages ORDER BY id")?;
+    let rows = statement.query_map([], |row| {
+        Ok((row.get(0)?, read_record(row.get(1)?, row.get(2)?)?))
+    })?;
+    return rows.collect();
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn insert_message(&self, id: &str, record: ChatRecord) -> Result<()> {
+        return self.insert_messages(vec![(id.to_string(), record)]).await;
+    }
+
+    async fn insert_messages(&self, records: Vec<(String, ChatRecord)>) -> Result<()> {
+        let rows = records
+            .into_iter()
+            .map(|(id, record)| MessageRow::new(id, record))
+            .collect::<Result<Vec<MessageRow>>>()?;
+        self.call(move |conn| {
+            // all messages are written in one transaction
+            let transaction = conn.unchecked_transaction()?;
+            for row in rows {
+                transaction.execute(
+                    "INSERT OR REPLACE INTO messages (id, timestamp, from_user, to_user, room, msg) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![row.id, row.timestamp, row.from, row.to, row.room, row.msg],
+                )?;
+            }
+            return transaction.commit();
+        })
+        .await?;
+        return Ok(());
+    }
+
+    async fn delete_messages(&self, ids: &[String]) -> Result<usize> {
+        let ids = ids.to_vec();
+        return self
+            .call(move |conn| {
+                let transaction = conn.unchecked_transaction()?;
+                let mut removed = 0;
+                for id in ids {
+                    removed +=
+                        transaction.execute("DELETE FROM messages WHERE id = ?1", params![id])?;
+                }
+                transaction.commit()?;
+                return Ok(removed);
+            })
+            .await;
+    }
+
+    async fn messages(&self) -> Result<Vec<(String, ChatRecord)>> {
+        return self.call(select_messages).await;
+    }
+
+    async fn snapshot(&self, dir: &Path) -> Result<()> {
+        let Some(name) = self.path.file_name() else {
+            bail!("Database file {} has no name", self.path.display());
+        };
+        let target = dir.join(name).to_string_lossy().to_string();
+        // copy is made by sqlite in one read transaction, so it is consistent while other connections write
+        self.call(move |conn| conn.execute("VACUUM INTO ?1", params![target]))
+            .await?;
+        return Ok(());
+    }
+
+    async fn history(
+        &self,
+        user: &str,
+        room: &str,
+        since: Option<&str>,
+        count: usize,
+    ) -> Result<Vec<HistoryEntry>> {
+        let user = user.to_string();
+        let room = room.to_string();
+        let since = since.map(|since| since.to_string());
+        let mut history: Vec<HistoryEntry> = self
+            .call(move |conn| {
+                // private messages of the user belong to history of the default room
+                let mut statement = conn.prepare(
+                    "SELECT timestamp, msg FROM messages
+                     WHERE (room = ?1 OR (room IS NULL AND ?1 = ?2 AND (from_user = ?3 OR to_user = ?3)))
+                       AND (?4 IS NULL OR timestamp >= ?4)
+                     ORDER BY id DESC LIMIT ?5",
+                )?;
+                let rows = statement.query_map(
+                    params![room, DEFAULT_ROOM, user, since, count as i64],
+                    |row| {
+                        let record = read_record(row.get(0)?, row.get(1)?)?;
+                        Ok((record.timestamp, record.msg))
+                    },
+                )?;
+                return rows.collect();
+            })
+            .await?;
+        history.reverse();
+        return Ok(history);
+    }
+
+    async fn get_password(&self, user: &str) -> Result<Option<String>> {
+        let user = user.to_string();
+        return self
+            .call(move |conn| {
+                conn.query_row(
+                    "SELECT password FROM users WHERE name = ?1",
+                    params![user],
+                    |row| row.get(0),
+                )
+                .optional()
+            })
+            .await;
+    }
+
+    async fn set_password(&self, user: &str, password: &str) -> Result<()> {
+        let user = user.to_string();
+        let password = password.to_string();
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO users (name, password) VALUES (?1, ?2)",
+                params![user, password],
+            )
+        })
+        .await?;
+        return Ok(());
+    }
+
+    async fn last_seen(&self, user: &str) -> Result<Option<String>> {
+        let user = user.to_string();
+        return self
+            .call(move |conn| {
+                conn.query_row(
+                    "SELECT timestamp FROM seen WHERE name = ?1",
+                    params![user],
+                    |row| row.get(0),
+                )
+                .optional()
+            })
+            .await;
+    }
+
+    async fn set_last_seen(&self, user: &str, timestamp: &str) -> Result<()> {
+        let user = user.to_string();
+        let timestamp = timestamp.to_string();
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO seen (name, timestamp) VALUES (?1, ?2)",
+                params![user, timestamp],
+            )
+        })
+        .await?;
+        return Ok(());
+    }
+}
@@ -0,0 +1,140 @@
+//! write-behind layer over other storage, messages wait in memory and are written in batches
+
+use std::path::Path;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+use tracing::error;
+
+use super::Storage;
+use crate::history::{ChatRecord, HistoryEntry};
+
+/// storage which queues new messages and writes them to inner storage after max_pending messages or on timer
+/// reads write waiting messages first, so they always see all messages, users and last seen times are written immediately
+pub struct WriteBehindStorage {
+    inner: Arc<dyn Storage>,
+    pending: Mutex<Vec<(String, ChatRecord)>>,
+    /// held while batch is written, so reads wait until the batch is in inner storage
+    flushing: Mutex<()>,
+    max_pending: usize,
+}
+
+impl WriteBehindStorage {
+    /// wrap the storage, waiting messages are written every interval by background task, which ends when storage is dropped
+    pub fn new(
+        inner: Arc<dyn Storage>,
+        max_pending: usize,
+        interval: Duration,
+    ) -> Arc<WriteBehindStorage> {
+        let storage = Arc::new(WriteBehindStorage {
+            inner,
+            pending: Mutex::new(Vec::new()),
+            flushing: Mutex::new(()),
+            max_pending: max_pending.max(1),
+        });
+        tokio::spawn(flush_periodically(Arc::downgrade(&storage), interval));
+        return storage;
+    }
+
+    /// count of messages waiting to be written
+    pub async fn pending(&self) -> usize {
+        return self.pending.lock().await.len();
+    }
+}
+
+/// write waiting messages every interval until the storage is dropped
+async fn flush_periodically(storage: Weak<WriteBehindStorage>, interval: Duration) {
+    let mut timer = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        timer.tick().await;
+        let Some(storage) = storage.upgrade() else {
+            break;
+        };
+        if let Err(e) = storage.flush().await {
+            error!("Writing messages to storage failed with error: {e}");
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for WriteBehindStorage {
+    async fn insert_message(&self, id: &str, record: ChatRecord) -> Result<()> {
+        let full = {
+            let mut pending = self.pending.lock().await;
+            pending.push((id.to_string(), record));
+            pending.len() >= self.max_pending
+        };
+        if full {
+            self.flush().await?;
+        }
+        return Ok(());
+    }
+
+    async fn insert_messages(&self, records: Vec<(String, ChatRecord)>) -> Result<()> {
+        self.pending.lock().await.extend(records);
+        return self.flush().await;
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let _flushing = self.flushing.lock().await;
+        let batch = std::mem::take(&mut *self.pending.lock().await);
+        if batch.is_empty() {
+            return self.inner.flush().await;
+        }
+        if let Err(e) = self.inner.insert_messages(batch.clone()).await {
+            // messages are kept for next attempt, newer messages stay after them
+            let mut pending = self.pending.lock().await;
+            let newer = std::mem::replace(&mut *pending, batch);
+            pending.extend(newer);
+            return Err(e);
+        }
+        return self.inner.flush().await;
+    }
+
+    async fn delete_messages(&self, ids: &[String]) -> Result<usize> {
+        // waiting messages are written first, so they can be removed too
+        self.flush().await?;
+        return self.inner.delete_messages(ids).await;
+    }
+
+    async fn snapshot(&self, dir: &Path) -> Result<()> {
+        // waiting messages are written first, so they are in the snapshot too
+        self.flush().await?;
+        return self.inner.snapshot(dir).await;
+    }
+
+    async fn messages(&self) -> Result<Vec<(String, ChatRecord)>> {
+        self.flush().await?;
+        return self.inner.messages().await;
+    }
+
+    async fn history(
+        &self,
+        user: &str,
+        room: &str,
+        since: Option<&str>,
+        count: usize,
+    ) -> Result<Vec<HistoryEntry>> {
+        self.flush().await?;
+        return self.inner.history(user, room, since, count).await;
+    }
+
+    async fn get_password(&self, user: &str) -> Result<Option<String>> {
+        return self.inner.get_password(user).await;
+    }
+
+    async fn set_password(&self, user: &str, password: &str) -> Result<()> {
+        return self.inner.set_password(user, password).await;
+    }
+
+    async fn last_seen(&self, user: &str) -> Result<Option<String>> {
+        return self.inner.last_seen(user).await;
+    }
+
+    async fn set_last_seen(&self, user: &str, timestamp: &str) -> Result<()> {
+        return self.inner.set_last_seen(user, timestamp).await;
+    }
+}
@@ -0,0 +1,705 @@
+//! chunked file transfer, file is sent as FileStart, sequence of FileChunk messages and FileEnd, so the whole file is never held in memory and chat messages can be sent between the chunks
+//! received data are written to temporary file, which is renamed once the transfer is complete, so interrupted transfer can continue from the last received offset
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tracing::{error, warn};
+
+use crate::async_chat_msg::{AsyncChatMsg, Destination, Envelope};
+use crate::{ensure_folder, get_file_name, sanitize_file_name, unique_file_path};
+
+/// size of the file data sent in one chunk
+pub const CHUNK_SIZE: usize = 32 * 1024;
+/// default maximum size of whole file or image
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+/// folder where server stores files received from clients, file is named by its transfer id, snapshots keep it under the same name
+pub const TRANSFERS_FOLDER: &str = "transfers";
+
+/// kind of transferred file, images are stored separately from other files
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    /// any file, stored in files folder of download directory
+    File,
+    /// image, stored in images folder of download directory
+    Image,
+}
+
+impl FileKind {
+    /// folder where received files of this kind are stored
+    pub fn folder(&self) -> &'static str {
+        return match self {
+            FileKind::File => "files",
+            FileKind::Image => "images",
+        };
+    }
+}
+
+/// create new id of the transfer, ids are unique, so chunks of concurrent transfers can be told apart
+pub fn new_transfer_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    // random state is seeded randomly, so ids from different clients don't collide
+    let random = RandomState::new().hash_one(COUNTER.fetch_add(1, Ordering::Relaxed));
+    return format!("{nanos:x}-{random:016x}");
+}
+
+/// check that transfer id received from the network can be safely used as a file name
+pub fn is_valid_transfer_id(id: &str) -> bool {
+    return !id.is_empty()
+        && id.len() <= 64
+        && id.chars().all(|c| c.is_ascii_hexdigit() || c == '-');
+}
+
+/// compute SHA-256 hash of the file as hex string, file is read chunk by chunk
+pub async fn file_sha256(path: &Path) -> Result<String> {
+    let mut file = File::open(path)
+        .await
+        .with_context(|| format!("Opening file {path:?} failed"))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    return Ok(format!("{:x}", hasher.finalize()));
+}
+
+/// errors of received transfer, transfer cannot continue after any of them
+#[derive(Debug, Error)]
+pub enum TransferError {
+    /// chunk doesn't follow already received data or doesn't fit into the file
+    #[error("Chunk of {len}B at offset {offset} doesn't fit after {written}B of {size}B file")]
+    UnexpectedChunk {
+        /// offset of the chunk
+        offset: u64,
+        /// size of the chunk
+        len: u64,
+        /// count of bytes already received
+        written: u64,
+        /// size of the whole file
+        size: u64,
+    },
+    /// transfer ended before all data were received
+    #[error("Transfer ended after {written}B of {size}B")]
+    Incomplete {
+        /// count of bytes received
+        written: u64,
+        /// size of the whole file
+        size: u64,
+    },
+    /// received data don't have the hash announced by the sender
+    #[error("Checksum of received file doesn't match, expected SHA-256 {expected}, got {actual}")]
+    ChecksumMismatch {
+        /// hash sent in FileStart message
+        expected: String,
+        /// hash of received data
+        actual: String,
+    },
+}
+
+/// read the file from the offset chunk by chunk and put chunks and end of the transfer to the queue, bounded queue keeps only few chunks in memory
+/// every message is put to envelope created by `envelope`, so the same transfer can be sent by the client to the room and by the server to one user
+pub async fn send_chunks(
+    path: &Path,
+    id: &str,
+    offset: u64,
+    size: u64,
+    envelope: impl Fn(AsyncChatMsg) -> Envelope,
+    queue: &mpsc::Sender<Envelope>,
+) -> Result<()> {
+    let closed = |_| anyhow!("Sending queue was closed");
+    let mut file = File::open(path)
+        .await
+        .with_context(|| format!("Opening file {path:?} failed"))?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let mut offset = offset;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        let chunk = AsyncChatMsg::FileChunk {
+            transfer: id.to_string(),
+            offset,
+            data: buffer[..read].to_vec(),
+        };
+        queue.send(envelope(chunk)).await.map_err(closed)?;
+        offset += read as u64;
+    }
+    if offset != size {
+        bail!("File {path:?} has changed during the transfer");
+    }
+    queue
+        .send(envelope(AsyncChatMsg::FileEnd {
+            transfer: id.to_string(),
+        }))
+        .await
+        .map_err(closed)?;
+    return Ok(());
+}
+
+/// file prepared for sending, data are read from disk chunk by chunk while sending
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileUpload {
+    /// id of the transfer
+    pub id: String,
+    /// user sending the file, only used to find uploads of the user in the journal, the server sets the sender itself
+    pub from: String,
+    /// room the file is sent to
+    pub room: String,
+    /// name of the file without path
+    pub filename: String,
+    /// size of the file in bytes
+    pub size: u64,
+    /// kind of the file
+    pub kind: FileKind,
+    /// SHA-256 hash of the file as hex string
+    pub sha256: String,
+    path: PathBuf,
+}
+
+impl FileUpload {
+    /// prepare upload of the file on the path, file is read once to compute its hash
+    pub async fn new(from: String, room: String, path: &str, kind: FileKind) -> Result<FileUpload> {
+        let path = fs::canonicalize(path.trim())
+            .await
+            .with_context(|| format!("File {} not found", path.trim()))?;
+        let metadata = fs::metadata(&path)
+            .await
+            .with_context(|| format!("Getting metadata of file {path:?} failed"))?;
+        if !metadata.is_file() {
+            bail!("Path {path:?} is not a file");
+        }
+        let sha256 = file_sha256(&path).await?;
+        return Ok(FileUpload {
+            id: new_transfer_id(),
+            from,
+            room,
+            filename: get_file_name(&path.to_string_lossy()),
+            size: metadata.len(),
+            kind,
+            sha256,
+            path,
+        });
+    }
+
+    /// message of the transfer for the room of the upload
+    fn envelope(&self, body: AsyncChatMsg) -> Envelope {
+        return Envelope::new(Destination::Room(self.room.clone()), body);
+    }
+
+    /// message announcing the transfer, it is sent before the first chunk
+    pub fn start_msg(&self) -> Envelope {
+        return self.envelope(AsyncChatMsg::FileStart {
+            transfer: self.id.clone(),
+            filename: self.filename.clone(),
+            size: self.size,
+            kind: self.kind,
+            sha256: self.sha256.clone(),
+        });
+    }
+
+    /// put start, chunks and end of the transfer to the queue
+    pub async fn send(&self, queue: &mpsc::Sender<Envelope>) -> Result<()> {
+        return self.send_from(0, queue).await;
+    }
+
+    /// continue the transfer from the offset, transfer starting from 0 is announced by FileStart message
+    pub async fn send_from(&self, offset: u64, queue: &mpsc::Sender<Envelope>) -> Result<()> {
+        if offset == 0 {
+            queue
+                .send(self.start_msg())
+                .await
+                .map_err(|_| anyhow!("Sending queue was closed"))?;
+        }
+        let envelope = |body| self.envelope(body);
+        return send_chunks(&self.path, &self.id, offset, self.size, envelope, queue).await;
+    }
+}
+
+/// file being received, data are written to temporary file next to the target, which is renamed when all data are received and hash matches
+#[derive(Debug)]
+pub struct PartialFile {
+    file: File,
+    temp: PathBuf,
+    path: PathBuf,
+    size: u64,
+    sha256: String,
+    written: u64,
+}
+
+impl PartialFile {
+    /// path of the temporary file for the transfer, it is in the same folder as the target, so it can be renamed
+    pub fn temp_path(path: &Path, id: &str) -> PathBuf {
+        return path.with_file_name(format!("{id}.part"));
+    }
+
+    /// start receiving of new file, data received before for the same transfer are discarded
+    pub async fn create(path: PathBuf, id: &str, size: u64, sha256: &str) -> Result<PartialFile> {
+        let temp = Self::temp_path(&path, id);
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&temp)
+            .await
+            .with_context(|| format!("Creating file {temp:?} failed"))?;
+        return Ok(PartialFile {
+            file,
+            temp,
+            path,
+            size,
+            sha256: sha256.to_string(),
+            written: 0,
+        });
+    }
+
+    /// continue receiving of the file after interrupted transfer, data already in temporary file are kept
+    pub async fn resume(path: PathBuf, id: &str, size: u64, sha256: &str) -> Result<PartialFile> {
+        let temp = Self::temp_path(&path, id);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&temp)
+            .await
+            .with_context(|| format!("Opening file {temp:?} failed"))?;
+        let written = file.metadata().await?.len();
+        if written > size {
+            bail!("File {temp:?} is bigger than the transferred file");
+        }
+        return Ok(PartialFile {
+            file,
+            temp,
+            path,
+            size,
+            sha256: sha256.to_string(),
+            written,
+        });
+    }
+
+    /// count of bytes already received, transfer continues from this offset
+    pub fn written(&self) -> u64 {
+        return self.written;
+    }
+
+    /// size of the whole file
+    pub fn size(&self) -> u64 {
+        return self.size;
+    }
+
+    /// append chunk to the file, chunk has to follow the data already written
+    pub async fn write_chunk(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let len = data.len() as u64;
+        if offset != self.written || self.written + len > self.size {
+            return Err(TransferError::UnexpectedChunk {
+                offset,
+                len,
+                written: self.written,
+                size: self.size,
+            }
+            .into());
+        }
+        self.file.write_all(data).await?;
+        self.written += data.len() as u64;
+        return Ok(());
+    }
+
+    /// check that whole file was received with expected hash and move it to the target path, incomplete or damaged file is removed
+    /// returns path where the file was stored, it differs from target path when file with the same name already exists
+    pub async fn finish(mut self) -> Result<PathBuf> {
+        if self.written != self.size {
+            let (written, size) = (self.written, self.size);
+            self.remove().await;
+            return Err(TransferError::Incomplete { written, size }.into());
+        }
+        self.file.flush().await?;
+        let actual = file_sha256(&self.temp).await?;
+        if !actual.eq_ignore_ascii_case(&self.sha256) {
+            let expected = self.sha256.clone();
+            self.remove().await;
+            return Err(TransferError::ChecksumMismatch { expected, actual }.into());
+        }
+        drop(self.file);
+        // existing file is never overwritten, received file gets numbered suffix instead
+        let path = unique_file_path(&self.path);
+        fs::rename(&self.temp, &path)
+            .await
+            .with_context(|| format!("Moving file {:?} to {path:?} failed", self.temp))?;
+        return Ok(path);
+    }
+
+    /// stop the transfer and remove the temporary file
+    pub async fn remove(self) {
+        drop(self.file);
+        _ = fs::remove_file(&self.temp).await;
+    }
+}
+
+/// information about incoming transfer, kept in journal, so transfer can be resumed after restart of the client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DownloadRecord {
+    filename: String,
+    size: u64,
+    kind: FileKind,
+    sha256: String,
+}
+
+/// file offered by other user, which waits for .accept or .reject command
+#[derive(Debug, Clone)]
+struct FileOffer {
+    number: u32,
+    record: DownloadRecord,
+    /// whole file was received by server, so it can be downloaded
+    finished: bool,
+    /// user accepted the file before it was received by server, it is downloaded once finished
+    accepted: bool,
+}
+
+/// incoming transfers by id, chunks are written to disk as they arrive
+pub struct FileDownloads {
+    active: HashMap<String, (DownloadRecord, PartialFile)>,
+    offers: HashMap<String, FileOffer>,
+    next_offer: u32,
+    max_file_size: u64,
+    root: PathBuf,
+    journal: Option<PathBuf>,
+}
+
+impl FileDownloads {
+    /// create empty set of transfers, files are stored in folders for their kind in root folder,
+    /// files bigger than max_file_size are refused
+    pub fn new(max_file_size: u64, root: &Path) -> FileDownloads {
+        return FileDownloads {
+            active: HashMap::new(),
+            offers: HashMap::new(),
+            next_offer: 1,
+            max_file_size,
+            root: root.to_path_buf(),
+            journal: None,
+        };
+    }
+
+    /// load unfinished transfers from the journal file, every change of transfers is saved to the journal
+    pub async fn load(max_file_size: u64, root: &Path, journal: &str) -> Result<FileDownloads> {
+        let mut downloads = FileDownloads::new(max_file_size, root);
+        downloads.journal = Some(PathBuf::from(journal));
+        let records: HashMap<String, DownloadRecord> = read_journal(journal).await?;
+        for (id, record) in records {
+            let path = downloads.folder(record.kind).join(&record.filename);
+            match PartialFile::resume(path, &id, record.size, &record.sha256).await {
+                Ok(partial) => {
+                    downloads.active.insert(id, (record, partial));
+                }
+                Err(e) => warn!("Transfer {id} cannot be resumed: {e}"),
+            }
+        }
+        return Ok(downloads);
+    }
+
+    /// unfinished transfers with count of bytes already received
+    pub fn pending(&self) -> Vec<(String, u64)> {
+        return self
+            .active
+            .iter()
+            .map(|(id, (_, partial))| (id.clone(), partial.written()))
+            .collect();
+    }
+
+    /// start receiving of the file, data are written to temporary file in the folder for its kind, folder is created if doesn't exist
+    pub async fn start(
+        &mut self,
+        id: &str,
+        filename: &str,
+        size: u64,
+        kind: FileKind,
+        sha256: &str,
+    ) -> Result<()> {
+        // transfer id comes from the network and is part of the name of the temporary file
+        if !is_valid_transfer_id(id) {
+            bail!("Transfer id {id:?} of file {filename} is not valid");
+        }
+        if size > self.max_file_size {
+            bail!(
+                "File {filename} has {size}B, which exceeds limit {}B",
+                self.max_file_size
+            );
+        }
+        let folder = self.folder(kind);
+        ensure_folder(&folder.to_string_lossy()).await?;
+        // file name comes from other user, so it cannot contain path
+        let filename = sanitize_file_name(filename);
+        let path = folder.join(&filename);
+        let partial = PartialFile::create(path, id, size, sha256).await?;
+        let record = DownloadRecord {
+            filename,
+            size,
+            kind,
+            sha256: sha256.to_string(),
+        };
+        self.active.insert(id.to_string(), (record, partial));
+        self.save().await;
+        return Ok(());
+    }
+
+    /// write chunk of the transfer, chunk has to follow the data already written, otherwise the transfer is cancelled
+    /// chunks of transfers which were not started or were cancelled are ignored
+    pub async fn write_chunk(&mut self, id: &str, offset: u64, data: &[u8]) -> Result<()> {
+        let Some((_, partial)) = self.active.get_mut(id) else {
+            return Ok(());
+        };
+        if let Err(e) = partial.write_chunk(offset, data).await {
+            self.cancel(id).await;
+            return Err(e);
+        }
+        return Ok(());
+    }
+
+    /// finish the transfer and return path to the received file, None if the transfer is not known
+    pub async fn finish(&mut self, id: &str) -> Result<Option<PathBuf>> {
+        let Some((_, partial)) = self.active.remove(id) else {
+            return Ok(None);
+        };
+        self.save().await;
+        let path = partial.finish().await?;
+        return Ok(Some(path));
+    }
+
+    /// remember file offered by other user, it is downloaded only after it is accepted,
+    /// returns number of the offer, which can be used instead of transfer id in .accept and .reject
+    pub fn offer(
+        &mut self,
+        id: &str,
+        filename: &str,
+        size: u64,
+        kind: FileKind,
+        sha256: &str,
+    ) -> u32 {
+        let number = self.next_offer;
+        self.next_offer += 1;
+        let record = DownloadRecord {
+            filename: filename.to_string(),
+            size,
+            kind,
+            sha256: sha256.to_string(),
+        };
+        let offer = FileOffer {
+            number,
+            record,
+            finished: false,
+            accepted: false,
+        };
+        self.offers.insert(id.to_string(), offer);
+        return number;
+    }
+
+    /// check if the transfer is offered and waits for decision or for the end of upload
+    pub fn is_offered(&self, id: &str) -> bool {
+        return self.offers.contains_key(id);
+    }
+
+    /// find transfer id of the offer by offer number or transfer id
+    fn offer_id(&self, key: &str) -> Option<String> {
+        let key = key.trim();
+        return self
+            .offers
+            .iter()
+            .find(|(id, offer)| *id == key || offer.number.to_string() == key)
+            .map(|(id, _)| id.clone());
+    }
+
+    /// accept the offered file, if it was already received by server, download is started
+    /// and request for the file is returned, otherwise it is requested when its upload is finished
+    pub async fn accept(&mut self, key: &str) -> Result<Option<AsyncChatMsg>> {
+        let Some(id) = self.offer_id(key) else {
+            bail!("Unknown file offer {key}");
+        };
+        let offer = self.offers.get_mut(&id).expect("offer was found");
+        if !offer.finished {
+            offer.accepted = true;
+            return Ok(None);
+        }
+        return self.download_offer(&id).await;
+    }
+
+    /// reject the offered file, name of the file is returned, None if the offer is not known
+    pub fn reject(&mut self, key: &str) -> Option<String> {
+        let id = self.offer_id(key)?;
+        return self.offers.remove(&id).map(|offer| offer.record.filename);
+    }
+
+    /// upload of the offered file was finished, request for the file is returned if it was accepted
+    pub async fn offer_finished(&mut self, id: &str) -> Result<Option<AsyncChatMsg>> {
+        let Some(offer) = self.offers.get_mut(id) else {
+            return Ok(None);
+        };
+        if !offer.accepted {
+            offer.finished = true;
+            return Ok(None);
+        }
+        return self.download_offer(id).await;
+    }
+
+    /// start download of the offered file from the beginning of the file stored on server
+    async fn download_offer(&mut self, id: &str) -> Result<Option<AsyncChatMsg>> {
+        let Some(offer) = self.offers.remove(id) else {
+            return Ok(None);
+        };
+        let record = offer.record;
+        self.start(
+            id,
+            &record.filename,
+            record.size,
+            record.kind,
+            &record.sha256,
+        )
+        .await?;
+        return Ok(Some(AsyncChatMsg::ResumeDownload {
+            transfer: id.to_string(),
+            offset: 0,
+        }));
+    }
+
+    /// stop the transfer and remove partially received file
+    pub async fn cancel(&mut self, id: &str) {
+        self.offers.remove(id);
+        if let Some((_, partial)) = self.active.remove(id) {
+            partial.remove().await;
+            self.save().await;
+        }
+    }
+
+    /// folder for received files of the kind
+    fn folder(&self, kind: FileKind) -> PathBuf {
+        return self.root.join(kind.folder());
+    }
+
+    /// save unfinished transfers to the journal, errors are only logged, transfer can continue without journal
+    async fn save(&self) {
+        let Some(journal) = &self.journal else {
+            return;
+        };
+        let records: HashMap<&String, &DownloadRecord> = self
+            .active
+            .iter()
+            .map(|(id, (record, _))| (id, record))
+            .collect();
+        if let Err(e) = write_journal(journal, &records).await {
+            error!("Saving transfer journal failed with error: {e}");
+        }
+    }
+}
+
+/// outgoing transfers by id, they are kept in journal until server confirms that whole file was received
+#[derive(Default)]
+pub struct FileUploads {
+    pending: HashMap<String, FileUpload>,
+    running: HashMap<String, AbortHandle>,
+    journal: Option<PathBuf>,
+}
+
+impl FileUploads {
+    /// create empty set of transfers without journal
+    pub fn new() -> FileUploads {
+        return FileUploads::default();
+    }
+
+    /// load unfinished transfers from the journal file, every change of transfers is saved to the journal
+    pub async fn load(journal: &str) -> Result<FileUploads> {
+        return Ok(FileUploads {
+            pending: read_journal(journal).await?,
+            running: HashMap::new(),
+            journal: Some(PathBuf::from(journal)),
+        });
+    }
+
+    /// unfinished transfer by id
+    pub fn get(&self, id: &str) -> Option<&FileUpload> {
+        return self.pending.get(id);
+    }
+
+    /// unfinished transfers of the user
+    pub fn pending_for(&self, user: &str) -> Vec<&FileUpload> {
+        return self.pending.values().filter(|u| u.from == user).collect();
+    }
+
+    /// send the file from the offset in background task, transfer is kept until it is finished or cancelled
+    pub async fn start(&mut self, upload: FileUpload, offset: u64, queue: &mpsc::Sender<Envelope>) {
+        let id = upload.id.clone();
+        if let Some(running) = self.running.remove(&id) {
+            running.abort();
+        }
+        self.pending.insert(id.clone(), upload.clone());
+        self.save().await;
+
+        let queue = queue.clone();
+        let task = tokio::spawn(async move {
+            if let Err(e) = upload.send_from(offset, &queue).await {
+                error!("Sending file {} failed with error: {e}", upload.filename);
+            }
+        });
+        self.running.insert(id, task.abort_handle());
+    }
+
+    /// remove the transfer when it is finished or cancelled, sending of its chunks is stopped
+    pub async fn finish(&mut self, id: &str) -> Option<FileUpload> {
+        if let Some(running) = self.running.remove(id) {
+            running.abort();
+        }
+        let upload = self.pending.remove(id);
+        if upload.is_some() {
+            self.save().await;
+        }
+        return upload;
+    }
+
+    /// save unfinished transfers to the journal, errors are only logged, transfer can continue without journal
+    async fn save(&self) {
+        let Some(journal) = &self.journal else {
+            return;
+        };
+        if let Err(e) = write_journal(journal, &self.pending).await {
+            error!("Saving transfer journal failed with error: {e}");
+        }
+    }
+}
+
+/// read journal of transfers, missing journal means no unfinished transfers
+async fn read_journal<T: serde::de::DeserializeOwned + Default>(journal: &str) -> Result<T> {
+    if !Path::new(journal).exists() {
+        return Ok(T::default());
+    }
+    let data = fs::read_to_string(journal)
+        .await
+        .with_context(|| format!("Reading journal {journal} failed"))?;
+    return serde_json::from_str(&data)
+        .with_context(|| format!("Parsing journal {journal} failed"));
+}
+
+/// write journal of transfers as json
+async fn write_journal<T: serde::Serialize>(journal: &Path, records: &T) -> Result<()> {
+    let data = serde_json::to_string_pretty(records)?;
+    fs::write(journal, data).await?;
+    return Ok(());
+}
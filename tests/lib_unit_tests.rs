@@ -1,14 +1,53 @@
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use nanodb::nanodb::NanoDB;
-use rust_15_async_chat::async_chat_msg::AsyncChatMsg;
+use chrono::Utc;
+use rust_15_async_chat::async_chat_msg::{
+    AsyncChatMsg, AsyncChatMsgDB, Destination, Envelope, ErrorCode, Password,
+};
+use rust_15_async_chat::config::{
+    AcceptDecision, AcceptPolicy, RegistrationPolicy, RetentionConfig, ServerConfig, StorageConfig,
+};
+use rust_15_async_chat::export::{export, ExportFilter, ExportFormat, ExportRecord};
+use rust_15_async_chat::frame::{FrameError, FrameKind, FrameLimits};
+use rust_15_async_chat::history::{
+    history_batches, last_seen, local_time, new_message_id, save_last_seen, utc_timestamp,
+    ChatRecord, HistoryEntry,
+};
+use rust_15_async_chat::import::{import_messages, parse_irc, parse_jsonl};
+use rust_15_async_chat::logging::{create_subscriber, REDACTED};
+use rust_15_async_chat::retention::{expired_messages, prune, PRIVATE_MESSAGES};
+use rust_15_async_chat::rooms::{normalize_room_name, RoomRegistry, DEFAULT_ROOM};
+use rust_15_async_chat::schema::{
+    migrate_chat_file, migrate_records, schema_version, SCHEMA_KEY, SCHEMA_VERSION,
+};
+use rust_15_async_chat::search::{search_results, SearchIndex, SearchQuery, SEARCH_PAGE_SIZE};
+use rust_15_async_chat::snapshot::{
+    check_snapshot, restore_snapshot, take_snapshot, MANIFEST_FILE,
+};
+use rust_15_async_chat::storage::nano::{JsonDb, CHAT_DB_FILE, SEEN_DB_FILE, USERS_DB_FILE};
+use rust_15_async_chat::storage::{
+    IndexedStorage, MemoryStorage, NanoDbStorage, SqliteStorage, Storage, WriteBehindStorage,
+};
+use rust_15_async_chat::transfer::{
+    file_sha256, is_valid_transfer_id, new_transfer_id, FileDownloads, FileKind, PartialFile,
+    TransferError, CHUNK_SIZE, DEFAULT_MAX_FILE_SIZE, TRANSFERS_FOLDER,
+};
 use rust_15_async_chat::*;
-use tokio::fs::{remove_dir, remove_file};
+use tokio::fs::{remove_dir, remove_file, write};
+use tokio::sync::mpsc;
+
+/// storage with messages, users and last seen times in one NanoDB file
+fn nano_storage(testfile: &str) -> NanoDbStorage {
+    let db = JsonDb::open(testfile).unwrap();
+    NanoDbStorage::new(db.clone(), db.clone(), db)
+}
 
 #[test]
 fn message_serialize_is_ok() {
     // prepare
-    let msg = AsyncChatMsg::Text("martin".into(), "hello".into());
+    let msg = Envelope::create_text("#general".into(), "hello".into()).stamped("martin");
     // act
     let serialized = serialize_msg(&msg);
     // assert
@@ -18,13 +57,132 @@ fn message_serialize_is_ok() {
 #[test]
 fn message_deserialize_is_ok() {
     // prepare
-    let msg = AsyncChatMsg::Text("martin".into(), "hello".into());
+    let msg = Envelope::create_text("#general".into(), "hello".into()).stamped("martin");
     // act
     let serialized = serialize_msg(&msg).unwrap();
     let deserialized = deserialize_msg(serialized);
     // assert
     assert!(deserialized.is_ok());
-    assert_eq!(deserialized.unwrap().get_text(), msg.get_text());
+    assert_eq!(deserialized.unwrap().body.get_text(), msg.body.get_text());
+}
+
+#[test]
+fn message_without_metadata_deserialize_gets_defaults() {
+    // prepare
+    let serialized = serde_cbor::to_vec(&serde_json::json!({ "body": "Who" })).unwrap();
+    // act
+    let deserialized = deserialize_msg(serialized).unwrap();
+    // assert
+    assert!(matches!(deserialized.body, AsyncChatMsg::Who));
+    assert_eq!(deserialized.to, Destination::Server);
+    assert!(deserialized.id.is_empty() && deserialized.from.is_empty());
+}
+
+#[test]
+fn error_message_deserialize_keeps_code() {
+    // prepare
+    let msg = Envelope::from_server(
+        "martin",
+        AsyncChatMsg::Error {
+            code: ErrorCode::AlreadyLoggedIn,
+            text: "martin is logged in".into(),
+        },
+    );
+    // act
+    let deserialized = deserialize_msg(serialize_msg(&msg).unwrap()).unwrap();
+    // assert
+    assert!(matches!(
+        deserialized.body,
+        AsyncChatMsg::Error {
+            code: ErrorCode::AlreadyLoggedIn,
+            ..
+        }
+    ));
+    assert_eq!(deserialized.to, Destination::User("martin".into()));
+    assert_eq!(deserialized.id, msg.id);
+    assert!(deserialized.from.is_empty());
+}
+
+#[test]
+fn history_batch_deserialize_keeps_named_fields() {
+    // prepare
+    let file = AsyncChatMsgDB::File {
+        from: "martin".into(),
+        room: DEFAULT_ROOM.into(),
+        filename: "a.txt".into(),
+        sha256: ABCDEF_SHA256.into(),
+        transfer: "t-1".into(),
+    };
+    let msg = Envelope::from_server(
+        "martin",
+        AsyncChatMsg::HistoryBatch {
+            room: DEFAULT_ROOM.into(),
+            messages: vec![("2024-07-13T17:21:29.000000Z".into(), file.clone())],
+        },
+    );
+    // act
+    let deserialized = deserialize_msg(serialize_msg(&msg).unwrap()).unwrap();
+    let json = serde_json::to_value(&file).unwrap();
+    // assert
+    let AsyncChatMsg::HistoryBatch { messages, .. } = deserialized.body else {
+        panic!("history batch expected");
+    };
+    assert_eq!(messages[0].1, file);
+    assert_eq!(json["type"], "file");
+    assert_eq!(json["filename"], "a.txt");
+}
+
+#[tokio::test]
+async fn stamped_envelope_replaces_sender_and_is_stored_under_its_id() {
+    // prepare
+    let db = MemoryStorage::new();
+    let mut msg = Envelope::create_text("#general".into(), "hello".into());
+    msg.from = "eva".into();
+    msg.id = "1".into();
+    // act
+    let received = deserialize_msg(serialize_msg(&msg).unwrap()).unwrap();
+    let stamped = received.stamped("martin");
+    stamped.save_to_db(&db).await.unwrap();
+    // assert
+    assert_eq!(stamped.from, "martin");
+    assert_eq!(stamped.id.len(), 20);
+    assert_eq!(stamped.to_string(), "[#general] martin: hello");
+    let stored = db.messages().await.unwrap();
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].0, stamped.id);
+    assert_eq!(stored[0].1.timestamp, stamped.timestamp);
+    assert_eq!(stored[0].1.msg.get_from(), "martin");
+}
+
+#[test]
+fn destination_of_envelope_decides_what_is_stored() {
+    // prepare
+    let direct = Envelope::create_direct("john".into(), "psst".into()).stamped("martin");
+    let request = Envelope::request(AsyncChatMsg::Text { text: "hi".into() }).stamped("martin");
+    let image = file_msg(
+        new_transfer_id(),
+        "#ops".into(),
+        "cat.png".into(),
+        6,
+        FileKind::Image,
+        ABCDEF_SHA256.into(),
+    );
+    // act
+    let end = image.follow_up(AsyncChatMsg::FileEnd {
+        transfer: "t".into(),
+    });
+    // assert
+    assert_eq!(direct.to_string(), "(private) martin -> john: psst");
+    assert!(direct.to_db_msg().unwrap().is_private());
+    assert!(request.to_db_msg().is_none());
+    assert_eq!(
+        image.to_string(),
+        "[#ops] martin: incomming image cat.png (6B)"
+    );
+    assert_eq!(image.to_db_msg().unwrap().get_room(), Some("#ops"));
+    assert_eq!((end.from.as_str(), end.room()), ("martin", Some("#ops")));
+    assert!(end.id > image.id);
+    assert!(end.to_db_msg().is_none());
 }
 
 #[tokio::test]
@@ -32,11 +190,11 @@ async fn ensure_folder_exists() {
     // prepare
     let folder_name = "testfolder";
     // act
-    let mut folder_result = ensure_folder(&folder_name).await;
+    let mut folder_result = ensure_folder(folder_name).await;
     // assert
     assert!(folder_result.is_ok());
     assert!(Path::new(folder_name).exists());
-    folder_result = ensure_folder(&folder_name).await;
+    folder_result = ensure_folder(folder_name).await;
     assert!(folder_result.is_ok());
     assert!(Path::new(folder_name).exists());
     // cleanup
@@ -48,10 +206,10 @@ async fn ensure_folder_exists() {
 async fn save_message_to_db_message_saved() {
     // prepare
     let testfile = "testdb.json";
-    let db = NanoDB::open(testfile).unwrap();
-    let msg = AsyncChatMsg::Text("martin".into(), "hello".into());
+    let db = nano_storage(testfile);
+    let msg = Envelope::create_text("#general".into(), "hello".into()).stamped("martin");
     // act
-    let dbres = msg.save_to_db(db).await;
+    let dbres = msg.save_to_db(&db).await;
     // assert
     assert!(dbres.is_ok());
     assert!(Path::new(testfile).exists());
@@ -63,14 +221,39 @@ async fn save_message_to_db_message_saved() {
 #[tokio::test]
 async fn store_message_file_file_stored() {
     let filename = "test.zip";
-    let msg = AsyncChatMsg::create_file("martin".into(), filename.into())
+    let upload = Envelope::create_file("martin".into(), "#general".into(), filename.into())
         .await
         .unwrap();
+    let (queue, mut received) = mpsc::channel(4);
+    let mut downloads = FileDownloads::new(DEFAULT_MAX_FILE_SIZE, Path::new("."));
     // act
-    let res = msg.store_file().await;
+    let sending = tokio::spawn(async move { upload.send(&queue).await });
+    let mut res = Ok(None);
+    let mut chunks = 0;
+    while let Some(msg) = received.recv().await {
+        if matches!(msg.body, AsyncChatMsg::FileChunk { .. }) {
+            chunks += 1;
+        }
+        res = msg.body.store_file(&mut downloads).await;
+    }
     // assert
-    assert!(res.is_ok());
-    assert!(Path::new(&format!("files/{filename}")).exists());
+    assert!(sending.await.unwrap().is_ok());
+    assert_eq!(
+        res.unwrap().unwrap(),
+        Path::new(".").join("files").join(filename).as_path()
+    );
+    let size = std::fs::metadata(filename).unwrap().len();
+    assert_eq!(chunks, size.div_ceil(CHUNK_SIZE as u64));
+    assert_eq!(
+        file_sha256(Path::new("files").join(filename).as_path())
+            .await
+            .unwrap(),
+        file_sha256(Path::new(filename)).await.unwrap()
+    );
+    assert_eq!(
+        std::fs::read(format!("files/{filename}")).unwrap(),
+        std::fs::read(filename).unwrap()
+    );
     // cleanup
     _ = remove_file(&format!("files/{filename}")).await;
     _ = remove_dir("files").await;
@@ -78,33 +261,58 @@ async fn store_message_file_file_stored() {
 }
 
 #[tokio::test]
-async fn validate_user_in_db_new_user_created() {
+async fn register_user_in_db_new_user_created() {
     // prepare
     let testfile = "testuserdb.json";
-    let db = NanoDB::open(testfile).unwrap();
+    let db = nano_storage(testfile);
     // act
-    let dbres = validate_user_in_db("martin", "password", db).await;
+    let dbres = register_user_in_db("martin", "password", &db).await;
+    let again = register_user_in_db("martin", "password2", &db).await;
     // assert
     assert!(dbres.is_ok());
     assert!(Path::new(testfile).exists());
-    assert_eq!(dbres.unwrap(), true);
+    assert_eq!(dbres.unwrap(), RegisterStatus::Registered);
+    assert_eq!(again.unwrap(), RegisterStatus::AlreadyExists);
+    assert_eq!(
+        validate_user_in_db("martin", "password", &db)
+            .await
+            .unwrap(),
+        LoginStatus::Valid
+    );
     // cleanup
     _ = remove_file(testfile).await;
     assert!(!Path::new(testfile).exists());
 }
 
+#[tokio::test]
+async fn validate_user_in_db_unknown_user_not_created() {
+    // prepare
+    let testfile = "testuser6db.json";
+    let db = nano_storage(testfile);
+    // act
+    let dbres = validate_user_in_db("martin", "password", &db).await;
+    // assert
+    assert_eq!(dbres.unwrap(), LoginStatus::UnknownUser);
+    assert!(get_password_for_user("martin", &db)
+        .await
+        .unwrap()
+        .is_none());
+    // cleanup
+    _ = remove_file(testfile).await;
+}
+
 #[tokio::test]
 async fn validate_user_in_db_existing_user_correct_password() {
     // prepare
     let testfile = "testuser2db.json";
-    let db = NanoDB::open(testfile).unwrap();
+    let db = nano_storage(testfile);
     // act
-    let _ = validate_user_in_db("martin", "password", db.clone()).await;
-    let dbres = validate_user_in_db("martin", "password", db).await;
+    let _ = register_user_in_db("martin", "password", &db).await;
+    let dbres = validate_user_in_db("martin", "password", &db).await;
     // assert
     assert!(dbres.is_ok());
     assert!(Path::new(testfile).exists());
-    assert_eq!(dbres.unwrap(), true);
+    assert_eq!(dbres.unwrap(), LoginStatus::Valid);
     // cleanup
     _ = remove_file(testfile).await;
     assert!(!Path::new(testfile).exists());
@@ -114,15 +322,1824 @@ async fn validate_user_in_db_existing_user_correct_password() {
 async fn validate_user_in_db_existing_user_incorrect_password() {
     // prepare
     let testfile = "testuser3db.json";
-    let db = NanoDB::open(testfile).unwrap();
+    let db = nano_storage(testfile);
     // act
-    let _ = validate_user_in_db("martin", "password", db.clone()).await;
-    let dbres = validate_user_in_db("martin", "password2", db).await;
+    let _ = register_user_in_db("martin", "password", &db).await;
+    let dbres = validate_user_in_db("martin", "password2", &db).await;
     // assert
     assert!(dbres.is_ok());
     assert!(Path::new(testfile).exists());
-    assert_eq!(dbres.unwrap(), false);
+    assert_eq!(dbres.unwrap(), LoginStatus::WrongPassword);
     // cleanup
     _ = remove_file(testfile).await;
     assert!(!Path::new(testfile).exists());
 }
+
+#[test]
+fn db_message_without_room_read_as_default_room() {
+    // prepare
+    let mut records: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(r#"{"2024-07-13 19:21:29|john": {"Text": ["john", "hi"]}}"#).unwrap();
+    // act
+    migrate_records(&mut records).unwrap();
+    records.remove(SCHEMA_KEY);
+    let record: ChatRecord =
+        serde_json::from_value(records.values().next().unwrap().clone()).unwrap();
+    // assert
+    assert!(matches!(record.msg, AsyncChatMsgDB::Text { room, .. } if room == DEFAULT_ROOM));
+}
+
+#[tokio::test]
+async fn room_registry_join_leave_broadcast() {
+    // prepare
+    let mut rooms = RoomRegistry::new();
+    let addr = "127.0.0.1:1".parse().unwrap();
+    let mut receiver = rooms.join("#ops", "martin");
+    let msg = Envelope::create_text("#ops".into(), "hello".into()).stamped("john");
+    // act
+    rooms.sender("#ops").unwrap().send((msg, addr)).unwrap();
+    let (received, _) = receiver.recv().await.unwrap();
+    // assert
+    assert_eq!(received.body.get_text(), "hello");
+    assert!(rooms.is_member("#ops", "martin"));
+    assert!(!rooms.is_member("#ops", "john"));
+    assert!(rooms.leave("#ops", "martin"));
+    assert!(rooms.sender("#ops").is_none());
+    assert_eq!(rooms.list(), vec![(DEFAULT_ROOM.to_string(), 0)]);
+}
+
+#[test]
+fn normalize_room_name_adds_hash_and_rejects_invalid() {
+    assert_eq!(normalize_room_name("ops").unwrap(), "#ops");
+    assert_eq!(normalize_room_name(" #ops ").unwrap(), "#ops");
+    assert!(normalize_room_name("#").is_err());
+    assert!(normalize_room_name("my room").is_err());
+}
+
+#[tokio::test]
+async fn save_direct_message_to_db_stored_as_private() {
+    // prepare
+    let testfile = "testdmdb.json";
+    let db = nano_storage(testfile);
+    let msg = Envelope::create_direct("john".into(), "psst".into()).stamped("martin");
+    // act
+    let dbres = msg.save_to_db(&db).await;
+    // assert
+    assert!(dbres.is_ok());
+    let stored: Vec<AsyncChatMsgDB> = db
+        .messages()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(_, record)| record.msg)
+        .collect();
+    assert_eq!(stored.len(), 1);
+    assert!(stored[0].is_private());
+    assert!(stored[0].is_visible_to("martin"));
+    assert!(stored[0].is_visible_to("john"));
+    assert!(!stored[0].is_visible_to("eve"));
+    // cleanup
+    _ = remove_file(testfile).await;
+}
+
+#[tokio::test]
+async fn validate_user_in_db_password_stored_as_hash() {
+    // prepare
+    let testfile = "testuser4db.json";
+    let db = nano_storage(testfile);
+    // act
+    let _ = register_user_in_db("martin", "password", &db).await;
+    let stored = get_password_for_user("martin", &db).await.unwrap().unwrap();
+    // assert
+    assert_ne!(stored, "password");
+    assert!(stored.starts_with("$argon2"));
+    assert!(verify_password("password", &stored).await.unwrap());
+    assert!(!verify_password("password2", &stored).await.unwrap());
+    // cleanup
+    _ = remove_file(testfile).await;
+}
+
+#[tokio::test]
+async fn validate_user_in_db_plain_text_password_upgraded() {
+    // prepare
+    let testfile = "testuser5db.json";
+    write(testfile, r#"{"martin": "password"}"#).await.unwrap();
+    let db = nano_storage(testfile);
+    // act
+    let wrong = validate_user_in_db("martin", "password2", &db).await;
+    let not_upgraded = get_password_for_user("martin", &db).await.unwrap().unwrap();
+    let correct = validate_user_in_db("martin", "password", &db).await;
+    let upgraded = get_password_for_user("martin", &db).await.unwrap().unwrap();
+    // assert
+    assert_eq!(wrong.unwrap(), LoginStatus::WrongPassword);
+    assert_eq!(not_upgraded, "password");
+    assert_eq!(correct.unwrap(), LoginStatus::Valid);
+    assert!(upgraded.starts_with("$argon2"));
+    assert_eq!(
+        validate_user_in_db("martin", "password", &db)
+            .await
+            .unwrap(),
+        LoginStatus::Valid
+    );
+    // cleanup
+    _ = remove_file(testfile).await;
+}
+
+/// writer collecting log output in memory
+struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn login_password_never_reaches_log() {
+    // prepare
+    let buffer = Arc::new(Mutex::new(Vec::new()));
+    let writer = {
+        let buffer = buffer.clone();
+        move || LogBuffer(buffer.clone())
+    };
+    let subscriber = create_subscriber("trace", writer);
+    let msg = AsyncChatMsg::Login {
+        login: "martin".into(),
+        password: Password::from("secret123".to_string()),
+    };
+    // act
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!("{msg}");
+        tracing::info!("{msg:?}");
+        tracing::info!(?msg, "login received");
+        tracing::warn!(password = "secret123", user = "martin", "login attempt");
+    });
+    // assert
+    let log = String::from_utf8(buffer.lock().unwrap().clone()).unwrap();
+    assert!(log.contains("martin"));
+    assert!(log.contains(REDACTED));
+    assert!(!log.contains("secret123"));
+}
+
+#[test]
+fn registration_allowed_by_policy() {
+    // prepare
+    let mut config = ServerConfig {
+        invite_codes: vec!["welcome".into()],
+        ..Default::default()
+    };
+    // act & assert
+    assert!(config.registration_allowed(None).is_ok());
+    config.registration = RegistrationPolicy::InviteOnly;
+    assert_eq!(
+        config.registration_allowed(None),
+        Err(ErrorCode::InvalidInvite)
+    );
+    assert_eq!(
+        config.registration_allowed(Some("wrong")),
+        Err(ErrorCode::InvalidInvite)
+    );
+    assert!(config.registration_allowed(Some("welcome")).is_ok());
+    config.registration = RegistrationPolicy::Closed;
+    assert_eq!(
+        config.registration_allowed(Some("welcome")),
+        Err(ErrorCode::RegistrationClosed)
+    );
+}
+
+#[tokio::test]
+async fn save_logout_to_db_not_saved() {
+    // prepare
+    let testfile = "testlogoutdb.json";
+    let db = nano_storage(testfile);
+    // act
+    let dbres = Envelope::request(AsyncChatMsg::Logout)
+        .stamped("martin")
+        .save_to_db(&db)
+        .await;
+    // assert
+    assert!(dbres.is_ok());
+    assert!(db.messages().await.unwrap().is_empty());
+    // cleanup
+    _ = remove_file(testfile).await;
+}
+
+#[tokio::test]
+async fn receive_huge_length_prefix_rejected_without_allocation() {
+    // prepare
+    let mut data: &[u8] = &[0xFF, 0xFF, 0xFF, 0xFF, FrameKind::Message as u8];
+    // act
+    let res = Envelope::receive(&mut data).await;
+    // assert
+    let err = res.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<FrameError>(),
+        Some(FrameError::Oversized {
+            kind: FrameKind::Message,
+            size: 0xFFFF_FFFF,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn receive_file_frame_over_limit_rejected() {
+    // prepare
+    let limits = FrameLimits {
+        max_message_size: 16,
+        max_chunk_size: 1024,
+    };
+    let mut data: &[u8] = &[0x00, 0x00, 0x04, 0x01, FrameKind::File as u8];
+    // act
+    let res = Envelope::receive_with_limits(&mut data, &limits).await;
+    // assert
+    assert!(matches!(
+        res.unwrap_err().downcast_ref::<FrameError>(),
+        Some(FrameError::Oversized {
+            kind: FrameKind::File,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn receive_unknown_frame_kind_rejected() {
+    // prepare
+    let mut data: &[u8] = &[0x00, 0x00, 0x00, 0x01, 0x07, 0x00];
+    // act
+    let res = Envelope::receive(&mut data).await;
+    // assert
+    assert!(matches!(
+        res.unwrap_err().downcast_ref::<FrameError>(),
+        Some(FrameError::UnknownKind(7))
+    ));
+}
+
+#[tokio::test]
+async fn receive_text_in_file_frame_rejected() {
+    // prepare
+    let msg = Envelope::create_text("#general".into(), "hello".into()).stamped("martin");
+    let serialized = serialize_msg(&msg).unwrap();
+    let mut frame = (serialized.len() as u32).to_be_bytes().to_vec();
+    frame.push(FrameKind::File as u8);
+    frame.extend(serialized);
+    // act
+    let res = Envelope::receive(&mut frame.as_slice()).await;
+    // assert
+    assert!(matches!(
+        res.unwrap_err().downcast_ref::<FrameError>(),
+        Some(FrameError::KindMismatch(FrameKind::File))
+    ));
+}
+
+#[tokio::test]
+async fn send_and_receive_within_limits_is_ok() {
+    // prepare
+    let limits = FrameLimits {
+        max_message_size: 1024,
+        max_chunk_size: 10,
+    };
+    let msg = Envelope::create_text("#general".into(), "hello".into()).stamped("martin");
+    let file = Envelope::new(
+        Destination::Room("#general".into()),
+        AsyncChatMsg::FileChunk {
+            transfer: "1".into(),
+            offset: 0,
+            data: vec![0; 100],
+        },
+    );
+    let mut buffer: Vec<u8> = Vec::new();
+    // act
+    let sent = msg.send_with_limits(&mut buffer, &limits).await;
+    let file_sent = file.send_with_limits(&mut buffer, &limits).await;
+    let received = Envelope::receive_with_limits(&mut buffer.as_slice(), &limits).await;
+    // assert
+    assert!(sent.is_ok());
+    assert!(matches!(
+        file_sent.unwrap_err().downcast_ref::<FrameError>(),
+        Some(FrameError::Oversized { .. })
+    ));
+    assert_eq!(received.unwrap().body.get_text(), "hello");
+}
+
+#[tokio::test]
+async fn stamped_message_close_to_limit_checked_before_forwarding() {
+    // prepare
+    let limits = FrameLimits::default();
+    let text = |len: usize| Envelope::create_text("#general".into(), "x".repeat(len));
+    // longest text the client is allowed to send
+    let mut len = limits.max_message_size as usize;
+    while text(len).check_limits(&limits).is_err() {
+        len -= 1;
+    }
+    let stamped_overhead = serialize_msg(&text(len).stamped("martin")).unwrap().len()
+        - serialize_msg(&text(len)).unwrap().len();
+    let mut buffer: Vec<u8> = Vec::new();
+    // act
+    let longest = text(len).stamped("martin").check_limits(&limits);
+    let forwarded = text(len - stamped_overhead).stamped("martin");
+    let checked = forwarded.check_limits(&limits);
+    let sent = forwarded.send_with_limits(&mut buffer, &limits).await;
+    let received = Envelope::receive_with_limits(&mut buffer.as_slice(), &limits).await;
+    // assert
+    assert!(matches!(
+        longest.unwrap_err().downcast_ref::<FrameError>(),
+        Some(FrameError::Oversized {
+            kind: FrameKind::Message,
+            ..
+        })
+    ));
+    assert!(checked.is_ok());
+    assert!(sent.is_ok());
+    assert_eq!(received.unwrap().id, forwarded.id);
+}
+
+#[tokio::test]
+async fn store_file_chunk_out_of_order_cancels_transfer() {
+    // prepare
+    let mut downloads = FileDownloads::new(DEFAULT_MAX_FILE_SIZE, Path::new("."));
+    let start = file_msg(
+        "a1".into(),
+        "#general".into(),
+        "ooo.bin".into(),
+        8,
+        FileKind::Image,
+        String::new(),
+    );
+    let first = AsyncChatMsg::FileChunk {
+        transfer: "a1".into(),
+        offset: 0,
+        data: vec![1; 4],
+    };
+    let skipped = AsyncChatMsg::FileChunk {
+        transfer: "a1".into(),
+        offset: 6,
+        data: vec![2; 2],
+    };
+    // act
+    start.body.store_file(&mut downloads).await.unwrap();
+    first.store_file(&mut downloads).await.unwrap();
+    let res = skipped.store_file(&mut downloads).await;
+    // assert
+    assert!(res.is_err());
+    assert!(!Path::new("images/ooo.bin").exists());
+    assert!(!Path::new("images/a1.part").exists());
+    assert!(AsyncChatMsg::FileEnd {
+        transfer: "a1".into()
+    }
+    .store_file(&mut downloads)
+    .await
+    .unwrap()
+    .is_none());
+    // cleanup
+    _ = remove_dir("images").await;
+}
+
+#[tokio::test]
+async fn store_file_over_limit_refused() {
+    // prepare
+    let mut downloads = FileDownloads::new(1024, Path::new("."));
+    let start = file_msg(
+        "a2".into(),
+        "#general".into(),
+        "big.bin".into(),
+        4096,
+        FileKind::File,
+        String::new(),
+    );
+    // act
+    let res = start.body.store_file(&mut downloads).await;
+    // assert
+    assert!(res.is_err());
+    assert!(!Path::new("files/big.bin").exists());
+}
+
+#[tokio::test]
+async fn store_file_invalid_transfer_id_refused() {
+    // prepare
+    let root = Path::new("testinvalidid");
+    let mut downloads = FileDownloads::new(DEFAULT_MAX_FILE_SIZE, root);
+    let start = file_msg(
+        "../../evil".into(),
+        "#general".into(),
+        "evil.bin".into(),
+        4,
+        FileKind::File,
+        String::new(),
+    );
+    // act
+    let res = start.body.store_file(&mut downloads).await;
+    // assert
+    assert!(res.is_err());
+    assert!(!Path::new("evil.part").exists());
+    assert!(!root.join("evil.part").exists());
+    assert!(downloads.pending().is_empty());
+    // cleanup
+    _ = tokio::fs::remove_dir_all(root).await;
+}
+
+const ABCDEF_SHA256: &str = "bef57ec7f53a6d40beb640a780a639c83bc29ac8a9816f1fc6c5c6dcd93c4721";
+
+#[tokio::test]
+async fn partial_file_resumed_and_renamed_when_complete() {
+    // prepare
+    let folder = "testresume";
+    ensure_folder(folder).await.unwrap();
+    let path = Path::new(folder).join("resumed.bin");
+    let mut partial = PartialFile::create(path.clone(), "r1", 6, ABCDEF_SHA256)
+        .await
+        .unwrap();
+    partial.write_chunk(0, b"abc").await.unwrap();
+    // connection dropped
+    drop(partial);
+    // act
+    let mut resumed = PartialFile::resume(path.clone(), "r1", 6, ABCDEF_SHA256)
+        .await
+        .unwrap();
+    let offset = resumed.written();
+    resumed.write_chunk(offset, b"def").await.unwrap();
+    let finished = resumed.finish().await;
+    // assert
+    assert_eq!(offset, 3);
+    assert_eq!(finished.unwrap(), path);
+    assert_eq!(std::fs::read(&path).unwrap(), b"abcdef");
+    assert!(!PartialFile::temp_path(&path, "r1").exists());
+    // cleanup
+    _ = remove_file(&path).await;
+    _ = remove_dir(folder).await;
+}
+
+#[tokio::test]
+async fn partial_file_incomplete_not_renamed() {
+    // prepare
+    let folder = "testincomplete";
+    ensure_folder(folder).await.unwrap();
+    let path = Path::new(folder).join("incomplete.bin");
+    let mut partial = PartialFile::create(path.clone(), "r2", 6, ABCDEF_SHA256)
+        .await
+        .unwrap();
+    partial.write_chunk(0, b"abc").await.unwrap();
+    // act
+    let finished = partial.finish().await;
+    // assert
+    assert!(finished.is_err());
+    assert!(!path.exists());
+    assert!(!PartialFile::temp_path(&path, "r2").exists());
+    // cleanup
+    _ = remove_dir(folder).await;
+}
+
+#[test]
+fn transfer_id_validated() {
+    assert!(is_valid_transfer_id(&new_transfer_id()));
+    assert_ne!(new_transfer_id(), new_transfer_id());
+    assert!(!is_valid_transfer_id(""));
+    assert!(!is_valid_transfer_id("../../etc/passwd"));
+    assert!(!is_valid_transfer_id("/tmp/x"));
+}
+
+#[tokio::test]
+async fn full_chunk_fits_default_frame_limit() {
+    // prepare
+    let chunk = Envelope::from_server(
+        "martin",
+        AsyncChatMsg::FileChunk {
+            transfer: new_transfer_id(),
+            offset: 0,
+            data: vec![0xFF; CHUNK_SIZE],
+        },
+    );
+    let mut buffer: Vec<u8> = Vec::new();
+    // act
+    let sent = chunk
+        .send_with_limits(&mut buffer, &FrameLimits::default())
+        .await;
+    // assert
+    assert!(sent.is_ok());
+    assert!(buffer.len() < CHUNK_SIZE + 256);
+}
+
+#[tokio::test]
+async fn partial_file_checksum_mismatch_not_renamed() {
+    // prepare
+    let folder = "testchecksum";
+    ensure_folder(folder).await.unwrap();
+    let path = Path::new(folder).join("damaged.bin");
+    let mut partial = PartialFile::create(path.clone(), "r3", 6, ABCDEF_SHA256)
+        .await
+        .unwrap();
+    partial.write_chunk(0, b"abcdeX").await.unwrap();
+    // act
+    let finished = partial.finish().await;
+    // assert
+    assert!(matches!(
+        finished.unwrap_err().downcast_ref::<TransferError>(),
+        Some(TransferError::ChecksumMismatch { expected, .. }) if expected == ABCDEF_SHA256
+    ));
+    assert!(!path.exists());
+    assert!(!PartialFile::temp_path(&path, "r3").exists());
+    // cleanup
+    _ = remove_dir(folder).await;
+}
+
+#[tokio::test]
+async fn save_file_to_db_hash_stored() {
+    // prepare
+    let testfile = "testhashdb.json";
+    let db = nano_storage(testfile);
+    let id = new_transfer_id();
+    let start = file_msg(
+        id.clone(),
+        "#general".into(),
+        "abc.txt".into(),
+        6,
+        FileKind::File,
+        ABCDEF_SHA256.into(),
+    );
+    // act
+    start.save_to_db(&db).await.unwrap();
+    // assert
+    let msgs = db.messages().await.unwrap();
+    assert!(matches!(
+        msgs.first().map(|(_, record)| &record.msg),
+        Some(AsyncChatMsgDB::File { sha256, transfer, .. }) if sha256 == ABCDEF_SHA256 && *transfer == id
+    ));
+    // cleanup
+    _ = remove_file(testfile).await;
+}
+
+#[test]
+fn sanitize_file_name_removes_hostile_parts() {
+    assert_eq!(sanitize_file_name("../../.bashrc"), "bashrc");
+    assert_eq!(sanitize_file_name("/etc/passwd"), "passwd");
+    assert_eq!(
+        sanitize_file_name("..\\..\\Windows\\system.ini"),
+        "system.ini"
+    );
+    assert_eq!(sanitize_file_name("C:\\Users\\me\\photo.jpg"), "photo.jpg");
+    assert_eq!(sanitize_file_name("images/"), "images");
+    assert_eq!(sanitize_file_name(".."), "file");
+    assert_eq!(sanitize_file_name(""), "file");
+    assert_eq!(sanitize_file_name("a\0b\nc.txt"), "a_b_c.txt");
+    assert_eq!(sanitize_file_name("what?:*.txt"), "what___.txt");
+    assert_eq!(sanitize_file_name("CON"), "_CON");
+    assert_eq!(sanitize_file_name("nul.txt"), "_nul.txt");
+    assert_eq!(sanitize_file_name("console.txt"), "console.txt");
+    assert_eq!(sanitize_file_name(&"x".repeat(1000)).len(), 200);
+}
+
+#[tokio::test]
+async fn unique_file_path_adds_numbered_suffix() {
+    // prepare
+    let folder = Path::new("testunique");
+    ensure_folder("testunique").await.unwrap();
+    write(folder.join("photo.jpg"), b"1").await.unwrap();
+    write(folder.join("photo (1).jpg"), b"2").await.unwrap();
+    write(folder.join("README"), b"3").await.unwrap();
+    // act
+    let free = unique_file_path(&folder.join("other.jpg"));
+    let photo = unique_file_path(&folder.join("photo.jpg"));
+    let readme = unique_file_path(&folder.join("README"));
+    // assert
+    assert_eq!(free, folder.join("other.jpg"));
+    assert_eq!(photo, folder.join("photo (2).jpg"));
+    assert_eq!(readme, folder.join("README (1)"));
+    // cleanup
+    _ = tokio::fs::remove_dir_all(folder).await;
+}
+
+#[tokio::test]
+async fn partial_file_existing_file_not_overwritten() {
+    // prepare
+    let folder = Path::new("testcollision");
+    ensure_folder("testcollision").await.unwrap();
+    let path = folder.join("abc.txt");
+    write(&path, b"older and longer content").await.unwrap();
+    let mut partial = PartialFile::create(path.clone(), "r4", 6, ABCDEF_SHA256)
+        .await
+        .unwrap();
+    partial.write_chunk(0, b"abcdef").await.unwrap();
+    // act
+    let stored = partial.finish().await.unwrap();
+    // assert
+    assert_eq!(stored, folder.join("abc (1).txt"));
+    assert_eq!(std::fs::read(&stored).unwrap(), b"abcdef");
+    assert_eq!(std::fs::read(&path).unwrap(), b"older and longer content");
+    // cleanup
+    _ = tokio::fs::remove_dir_all(folder).await;
+}
+
+#[test]
+fn accept_policy_decides_by_size_and_extension() {
+    // prepare
+    let small = AcceptPolicy::SmallerThan(1024);
+    let images = AcceptPolicy::Extensions(vec![".png".into(), "jpg".into()]);
+    // act
+    // assert
+    assert_eq!(
+        AcceptPolicy::Always.decide("a.exe", 1 << 30),
+        AcceptDecision::Accept
+    );
+    assert_eq!(
+        AcceptPolicy::Never.decide("a.png", 1),
+        AcceptDecision::Ignore
+    );
+    assert_eq!(AcceptPolicy::Ask.decide("a.png", 1), AcceptDecision::Ask);
+    assert_eq!(small.decide("a.bin", 1000), AcceptDecision::Accept);
+    assert_eq!(small.decide("a.bin", 1024), AcceptDecision::Ask);
+    assert_eq!(images.decide("photo.JPG", 1 << 30), AcceptDecision::Accept);
+    assert_eq!(images.decide("photo.png.exe", 1), AcceptDecision::Ask);
+    assert_eq!(images.decide("png", 1), AcceptDecision::Ask);
+}
+
+#[tokio::test]
+async fn offered_file_downloaded_to_root_after_accept() {
+    // prepare
+    let root = Path::new("testdownloads");
+    let mut downloads = FileDownloads::new(DEFAULT_MAX_FILE_SIZE, root);
+    let offer = downloads.offer("c1", "offered.txt", 6, FileKind::File, ABCDEF_SHA256);
+    let rejected = downloads.offer("c2", "rejected.txt", 6, FileKind::File, ABCDEF_SHA256);
+    // relayed chunks of not accepted file are ignored
+    AsyncChatMsg::FileChunk {
+        transfer: "c1".into(),
+        offset: 0,
+        data: b"abc".to_vec(),
+    }
+    .store_file(&mut downloads)
+    .await
+    .unwrap();
+    // act
+    let early = downloads.accept(&offer.to_string()).await.unwrap();
+    let request = downloads.offer_finished("c1").await.unwrap();
+    AsyncChatMsg::FileChunk {
+        transfer: "c1".into(),
+        offset: 0,
+        data: b"abcdef".to_vec(),
+    }
+    .store_file(&mut downloads)
+    .await
+    .unwrap();
+    let path = AsyncChatMsg::FileEnd {
+        transfer: "c1".into(),
+    }
+    .store_file(&mut downloads)
+    .await
+    .unwrap();
+    let rejected = downloads.reject(&rejected.to_string());
+    // assert
+    assert!(early.is_none());
+    assert!(matches!(
+        request,
+        Some(AsyncChatMsg::ResumeDownload { transfer, offset: 0 }) if transfer == "c1"
+    ));
+    assert_eq!(path, Some(root.join("files").join("offered.txt")));
+    assert_eq!(
+        tokio::fs::read(root.join("files/offered.txt"))
+            .await
+            .unwrap(),
+        b"abcdef"
+    );
+    assert_eq!(rejected.as_deref(), Some("rejected.txt"));
+    assert!(!downloads.is_offered("c2"));
+    assert!(downloads.accept("c2").await.is_err());
+    // cleanup
+    _ = tokio::fs::remove_dir_all(root).await;
+}
+
+#[tokio::test]
+async fn load_history_filters_room_private_and_since() {
+    // prepare
+    let testfile = "testhistorydb.json";
+    write(
+        testfile,
+        r##"{
+            "00000000000000000001": {"timestamp": "2024-07-13T17:21:29.000000Z", "msg": {"Text": ["john", "hi"]}},
+            "00000000000000000002": {"timestamp": "2024-07-13T17:21:30.000000Z", "msg": {"Text": ["john", "ops", "#ops"]}},
+            "00000000000000000003": {"timestamp": "2024-07-13T17:21:31.000000Z", "msg": {"Direct": ["john", "martin", "secret"]}},
+            "00000000000000000004": {"timestamp": "2024-07-13T17:21:32.000000Z", "msg": {"Direct": ["john", "eva", "other secret"]}},
+            "00000000000000000005": {"timestamp": "2024-07-13T17:21:32.000000Z", "msg": {"File": ["martin", "a.txt", "#general", ""]}}
+        }"##,
+    )
+    .await
+    .unwrap();
+    migrate_chat_file(Path::new(testfile)).await.unwrap();
+    let db = nano_storage(testfile);
+    // act
+    let general = db.history("martin", DEFAULT_ROOM, None, 10).await.unwrap();
+    let ops = db.history("martin", "#ops", None, 10).await.unwrap();
+    let since = db
+        .history(
+            "martin",
+            DEFAULT_ROOM,
+            Some("2024-07-13T17:21:32.000000Z"),
+            10,
+        )
+        .await
+        .unwrap();
+    let last = db.history("martin", DEFAULT_ROOM, None, 1).await.unwrap();
+    // assert
+    let timestamps: Vec<&str> = general.iter().map(|(t, _)| t.as_str()).collect();
+    assert_eq!(
+        timestamps,
+        vec![
+            "2024-07-13T17:21:29.000000Z",
+            "2024-07-13T17:21:31.000000Z",
+            "2024-07-13T17:21:32.000000Z"
+        ]
+    );
+    assert_eq!(ops.len(), 1);
+    assert_eq!(since.len(), 1);
+    assert!(matches!(&last[0].1, AsyncChatMsgDB::File { filename, .. } if filename == "a.txt"));
+    // cleanup
+    _ = remove_file(testfile).await;
+    _ = remove_file(format!("{testfile}.v2")).await;
+}
+
+#[test]
+fn history_batches_fit_to_limit() {
+    // prepare
+    let history: Vec<HistoryEntry> = (0..100)
+        .map(|i| {
+            let text = AsyncChatMsgDB::Text {
+                from: "john".into(),
+                room: "#general".into(),
+                text: "x".repeat(1000),
+            };
+            (format!("2024-07-13 19:21:{i:02}"), text)
+        })
+        .collect();
+    let too_big = AsyncChatMsgDB::Text {
+        from: "john".into(),
+        room: "#general".into(),
+        text: "x".repeat(20_000),
+    };
+    let mut with_big = history.clone();
+    with_big.push(("2024-07-13 19:22:00".into(), too_big));
+    // act
+    let batches = history_batches("martin", DEFAULT_ROOM, with_big, 16 * 1024);
+    // assert
+    assert!(batches.len() > 1);
+    let mut count = 0;
+    for batch in &batches {
+        assert!(serialize_msg(batch).unwrap().len() <= 16 * 1024);
+        if let AsyncChatMsg::HistoryBatch { room, messages } = &batch.body {
+            assert_eq!(room, DEFAULT_ROOM);
+            count += messages.len();
+        }
+    }
+    assert_eq!(count, history.len());
+}
+
+#[tokio::test]
+async fn last_seen_saved_and_loaded() {
+    // prepare
+    let testfile = "testseendb.json";
+    let db = nano_storage(testfile);
+    // act
+    let before = last_seen(&db, "martin").await.unwrap();
+    save_last_seen(&db, "martin").await.unwrap();
+    let after = last_seen(&db, "martin").await.unwrap();
+    // assert
+    assert!(before.is_none());
+    assert_eq!(after.unwrap().len(), "2024-07-13T17:21:29.000000Z".len());
+    // cleanup
+    _ = remove_file(testfile).await;
+}
+
+#[test]
+fn message_ids_unique_and_increasing() {
+    // prepare
+    // act
+    let ids: Vec<String> = (0..1000).map(|_| new_message_id()).collect();
+    // assert
+    let mut sorted = ids.clone();
+    sorted.sort();
+    sorted.dedup();
+    assert_eq!(sorted, ids);
+}
+
+#[tokio::test]
+async fn save_messages_in_same_second_both_stored() {
+    // prepare
+    let testfile = "testsamesecond.json";
+    let db = nano_storage(testfile);
+    let first = Envelope::create_text("#general".into(), "one".into()).stamped("martin");
+    let second = Envelope::create_text("#general".into(), "two".into()).stamped("martin");
+    // act
+    first.save_to_db(&db).await.unwrap();
+    second.save_to_db(&db).await.unwrap();
+    let history = db.history("martin", DEFAULT_ROOM, None, 10).await.unwrap();
+    // assert
+    let texts: Vec<String> = history.iter().map(|(_, msg)| msg.to_string()).collect();
+    assert_eq!(
+        texts,
+        vec!["[#general] martin: one", "[#general] martin: two"]
+    );
+    assert!(history[0].0 < history[1].0);
+    // cleanup
+    _ = remove_file(testfile).await;
+}
+
+#[tokio::test]
+async fn migrate_chat_file_upgrades_checked_in_chatdb() {
+    // prepare
+    let testfile = "testmigratedb.json";
+    std::fs::copy("chatdb.json", testfile).unwrap();
+    // act
+    let converted = migrate_chat_file(Path::new(testfile)).await.unwrap();
+    let again = migrate_chat_file(Path::new(testfile)).await.unwrap();
+    let db = JsonDb::open(testfile).unwrap();
+    let history = NanoDbStorage::new(db.clone(), db.clone(), db.clone())
+        .history("martin", DEFAULT_ROOM, None, 100)
+        .await
+        .unwrap();
+    let migrated: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(testfile).unwrap()).unwrap();
+    let kept = std::fs::read_to_string(format!("{testfile}.v1")).unwrap();
+    // assert
+    assert_eq!((converted.from, converted.to), (1, SCHEMA_VERSION));
+    assert_eq!(converted.steps, vec![(2, 6), (3, 6)]);
+    assert_eq!(
+        converted.to_string(),
+        "Chat db migrated from version 1 to 3 (version 2: 6 records, version 3: 6 records)"
+    );
+    assert!(again.is_current());
+    assert_eq!(history.len(), 6);
+    assert_eq!(history[0].1.to_string(), "[#general] john: hi");
+    assert_eq!(history[5].1.to_string(), "[#general] john: .quit");
+    assert!(history.windows(2).all(|w| w[0].0 <= w[1].0));
+    let migrated = migrated.as_object().unwrap();
+    assert_eq!(migrated[SCHEMA_KEY], SCHEMA_VERSION);
+    assert!(migrated.get("2024-07-13 19:21:29|john").is_none());
+    let first = migrated
+        .values()
+        .find(|record| record.get("type").is_some())
+        .unwrap();
+    assert_eq!(first["type"], "text");
+    assert_eq!(first["room"], DEFAULT_ROOM);
+    assert!(first.get("msg").is_none());
+    assert_eq!(kept, std::fs::read_to_string("chatdb.json").unwrap());
+    assert!(new_message_id() > format!("{:020}", 0));
+    // cleanup
+    _ = remove_file(testfile).await;
+    _ = remove_file(format!("{testfile}.v1")).await;
+}
+
+#[test]
+fn migrate_records_upgrades_version_2_and_rejects_newer() {
+    // prepare
+    let mut records: serde_json::Map<String, serde_json::Value> = serde_json::from_str(
+        r##"{
+            "00000000000000000001": {"timestamp": "2024-07-13T17:21:29.000000Z", "msg": {"Text": ["john", "hi"]}},
+            "00000000000000000002": {"timestamp": "2024-07-13T17:21:30.000000Z", "msg": {"Image": ["john", "a.png", "#ops", "abc", "t-1"]}},
+            "00000000000000000003": {"timestamp": "2024-07-13T17:21:31.000000Z", "msg": {"Direct": ["john", "martin", "secret"]}}
+        }"##,
+    )
+    .unwrap();
+    let mut newer: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(r#"{"schema_version": 99}"#).unwrap();
+    // act
+    let version = schema_version(&records).unwrap();
+    let report = migrate_records(&mut records).unwrap();
+    let again = migrate_records(&mut records).unwrap();
+    let rejected = migrate_records(&mut newer);
+    // assert
+    assert_eq!(version, 2);
+    assert_eq!(report.steps, vec![(3, 3)]);
+    assert!(again.is_current());
+    assert_eq!(
+        records["00000000000000000002"],
+        serde_json::json!({"timestamp": "2024-07-13T17:21:30.000000Z", "type": "image", "from": "john", "room": "#ops", "filename": "a.png", "sha256": "abc", "transfer": "t-1"})
+    );
+    let image: ChatRecord =
+        serde_json::from_value(records["00000000000000000002"].clone()).unwrap();
+    assert_eq!(image.msg.get_transfer_id(), Some("t-1"));
+    let text: ChatRecord = serde_json::from_value(records["00000000000000000001"].clone()).unwrap();
+    assert_eq!(text.msg.get_room(), Some(DEFAULT_ROOM));
+    assert!(rejected
+        .unwrap_err()
+        .to_string()
+        .contains("schema version 99"));
+}
+
+#[tokio::test]
+async fn sqlite_storage_upgrades_messages_of_older_version() {
+    // prepare
+    let folder = "testsqlitemigration";
+    ensure_folder(folder).await.unwrap();
+    let path = format!("{folder}/chat.sqlite");
+    {
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(
+            r##"CREATE TABLE messages (id TEXT PRIMARY KEY, timestamp TEXT NOT NULL, from_user TEXT NOT NULL, to_user TEXT, room TEXT, msg TEXT NOT NULL);
+            INSERT INTO messages VALUES ('00000000000000000001', '2024-07-13T17:21:29.000000Z', 'john', NULL, '#general', '{"Text":["john","hi","#general"]}');"##,
+        )
+        .unwrap();
+    }
+    // act
+    let storage = SqliteStorage::open(&path).await.unwrap();
+    let messages = storage.messages().await.unwrap();
+    drop(storage);
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    let version: i64 = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .unwrap();
+    let msg: String = conn
+        .query_row("SELECT msg FROM messages", [], |row| row.get(0))
+        .unwrap();
+    // assert
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].1.msg.to_string(), "[#general] john: hi");
+    assert_eq!(version, SCHEMA_VERSION as i64);
+    assert!(msg.contains(r#""type":"text""#));
+    // cleanup
+    drop(conn);
+    _ = tokio::fs::remove_dir_all(folder).await;
+}
+
+#[tokio::test]
+async fn read_messages_leaves_older_storage_unchanged() {
+    // prepare
+    let folder = "testreadmessages";
+    ensure_folder(folder).await.unwrap();
+    let chat_file = format!("{folder}/chatdb.json");
+    std::fs::copy("chatdb.json", &chat_file).unwrap();
+    let path = format!("{folder}/chat.sqlite");
+    {
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(
+            r##"CREATE TABLE messages (id TEXT PRIMARY KEY, timestamp TEXT NOT NULL, from_user TEXT NOT NULL, to_user TEXT, room TEXT, msg TEXT NOT NULL);
+            INSERT INTO messages VALUES ('00000000000000000001', '2024-07-13T17:21:29.000000Z', 'john', NULL, '#general', '{"Text":["john","hi","#general"]}');"##,
+        )
+        .unwrap();
+    }
+    let missing = format!("{folder}/missing.sqlite");
+    // act
+    let nano = NanoDbStorage::read_messages(Path::new(&chat_file))
+        .await
+        .unwrap();
+    let sqlite = SqliteStorage::read_messages(Path::new(&path))
+        .await
+        .unwrap();
+    let none = SqliteStorage::read_messages(Path::new(&missing))
+        .await
+        .unwrap();
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    let version: i64 = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .unwrap();
+    let msg: String = conn
+        .query_row("SELECT msg FROM messages", [], |row| row.get(0))
+        .unwrap();
+    // assert
+    assert_eq!(nano.len(), 6);
+    assert_eq!(nano[0].1.msg.to_string(), "[#general] john: hi");
+    assert_eq!(
+        std::fs::read_to_string(&chat_file).unwrap(),
+        std::fs::read_to_string("chatdb.json").unwrap()
+    );
+    assert!(!Path::new(&format!("{chat_file}.v1")).exists());
+    assert_eq!(sqlite.len(), 1);
+    assert_eq!(sqlite[0].1.msg.to_string(), "[#general] john: hi");
+    assert_eq!(version, 0);
+    assert_eq!(msg, r##"{"Text":["john","hi","#general"]}"##);
+    assert!(none.is_empty());
+    assert!(!Path::new(&missing).exists());
+    // cleanup
+    drop(conn);
+    _ = tokio::fs::remove_dir_all(folder).await;
+}
+
+/// same checks for every storage backend
+async fn storage_stores_messages_and_users(storage: &dyn Storage) {
+    // prepare
+    let messages = [
+        Envelope::create_text("#general".into(), "hi".into()).stamped("john"),
+        Envelope::create_text("#ops".into(), "ops".into()).stamped("john"),
+        Envelope::create_direct("martin".into(), "secret".into()).stamped("john"),
+        Envelope::create_direct("eva".into(), "other secret".into()).stamped("john"),
+        Envelope::create_text("#general".into(), "hello".into()).stamped("martin"),
+    ];
+    // act
+    for msg in &messages {
+        msg.save_to_db(storage).await.unwrap();
+    }
+    register_user_in_db("martin", "password", storage)
+        .await
+        .unwrap();
+    save_last_seen(storage, "martin").await.unwrap();
+    let all = storage.messages().await.unwrap();
+    let general = storage
+        .history("martin", DEFAULT_ROOM, None, 10)
+        .await
+        .unwrap();
+    let last = storage
+        .history("martin", DEFAULT_ROOM, None, 2)
+        .await
+        .unwrap();
+    let since = storage
+        .history("martin", DEFAULT_ROOM, Some(&all[4].1.timestamp), 10)
+        .await
+        .unwrap();
+    let found = storage
+        .search(&SearchQuery::parse("HELLO").unwrap())
+        .await
+        .unwrap();
+    let secret = storage
+        .search(&SearchQuery::parse("secret").unwrap())
+        .await
+        .unwrap();
+    // assert
+    assert_eq!(all.len(), 5);
+    assert!(all.windows(2).all(|w| w[0].0 < w[1].0));
+    let texts: Vec<String> = general.iter().map(|(_, msg)| msg.to_string()).collect();
+    assert_eq!(
+        texts,
+        vec![
+            "[#general] john: hi",
+            "(private) john -> martin: secret",
+            "[#general] martin: hello"
+        ]
+    );
+    assert_eq!(last.len(), 2);
+    assert_eq!(last[1].1.to_string(), "[#general] martin: hello");
+    assert_eq!(since.len(), 1);
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].1.to_string(), "[#general] martin: hello");
+    assert!(secret.is_empty());
+    assert_eq!(
+        validate_user_in_db("martin", "password", storage)
+            .await
+            .unwrap(),
+        LoginStatus::Valid
+    );
+    assert!(storage.get_password("john").await.unwrap().is_none());
+    assert!(last_seen(storage, "martin").await.unwrap().is_some());
+    assert!(last_seen(storage, "john").await.unwrap().is_none());
+    // act
+    let removed = storage
+        .delete_messages(&[all[0].0.clone(), "unknown".to_string()])
+        .await
+        .unwrap();
+    // assert
+    assert_eq!(removed, 1);
+    assert_eq!(storage.messages().await.unwrap().len(), 4);
+    assert!(storage
+        .search(&SearchQuery::parse("hi").unwrap())
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn memory_storage_stores_messages_and_users() {
+    storage_stores_messages_and_users(&MemoryStorage::new()).await;
+}
+
+#[tokio::test]
+async fn nanodb_storage_stores_messages_and_users() {
+    // prepare
+    let folder = "testnanostorage";
+    ensure_folder(folder).await.unwrap();
+    let storage = NanoDbStorage::open(
+        &format!("{folder}/chat.json"),
+        &format!("{folder}/users.json"),
+        &format!("{folder}/seen.json"),
+    )
+    .await
+    .unwrap();
+    // act
+    // assert
+    storage_stores_messages_and_users(&storage).await;
+    // cleanup
+    _ = tokio::fs::remove_dir_all(folder).await;
+}
+
+#[tokio::test]
+async fn sqlite_storage_stores_messages_and_users() {
+    // prepare
+    let folder = "testsqlitestorage";
+    ensure_folder(folder).await.unwrap();
+    let path = format!("{folder}/chat.sqlite");
+    let storage = SqliteStorage::open(&path).await.unwrap();
+    // act
+    // assert
+    storage_stores_messages_and_users(&storage).await;
+    drop(storage);
+    let reopened = SqliteStorage::open(&path).await.unwrap();
+    assert_eq!(reopened.messages().await.unwrap().len(), 4);
+    // cleanup
+    _ = tokio::fs::remove_dir_all(folder).await;
+}
+
+#[tokio::test]
+async fn write_behind_storage_stores_messages_and_users() {
+    let storage =
+        WriteBehindStorage::new(Arc::new(MemoryStorage::new()), 2, Duration::from_secs(3600));
+    storage_stores_messages_and_users(storage.as_ref()).await;
+}
+
+#[tokio::test]
+async fn write_behind_storage_writes_file_after_max_pending_or_flush() {
+    // prepare
+    let testfile = "testwritebehinddb.json";
+    let storage = WriteBehindStorage::new(
+        Arc::new(nano_storage(testfile)),
+        3,
+        Duration::from_secs(3600),
+    );
+    let msg = || AsyncChatMsgDB::Text {
+        from: "martin".into(),
+        room: DEFAULT_ROOM.into(),
+        text: "hello".into(),
+    };
+    let stored = || async { nano_storage(testfile).messages().await.unwrap().len() };
+    // act
+    save_msg_to_db(msg(), storage.as_ref()).await.unwrap();
+    save_msg_to_db(msg(), storage.as_ref()).await.unwrap();
+    // assert
+    assert_eq!(storage.pending().await, 2);
+    assert_eq!(stored().await, 0);
+    // act
+    save_msg_to_db(msg(), storage.as_ref()).await.unwrap();
+    // assert
+    assert_eq!(storage.pending().await, 0);
+    assert_eq!(stored().await, 3);
+    // act
+    save_msg_to_db(msg(), storage.as_ref()).await.unwrap();
+    assert_eq!(stored().await, 3);
+    storage.flush().await.unwrap();
+    // assert
+    assert_eq!(stored().await, 4);
+    assert_eq!(storage.messages().await.unwrap().len(), 4);
+    // cleanup
+    _ = remove_file(testfile).await;
+}
+
+#[tokio::test]
+async fn write_behind_storage_writes_file_on_timer() {
+    // prepare
+    let testfile = "testwritebehindtimerdb.json";
+    let storage = WriteBehindStorage::new(
+        Arc::new(nano_storage(testfile)),
+        100,
+        Duration::from_millis(50),
+    );
+    let msg = AsyncChatMsgDB::Text {
+        from: "martin".into(),
+        room: DEFAULT_ROOM.into(),
+        text: "hello".into(),
+    };
+    // act
+    save_msg_to_db(msg, storage.as_ref()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    // assert
+    assert_eq!(storage.pending().await, 0);
+    assert_eq!(nano_storage(testfile).messages().await.unwrap().len(), 1);
+    // cleanup
+    _ = remove_file(testfile).await;
+}
+
+#[tokio::test]
+async fn indexed_storage_stores_messages_and_users() {
+    let storage = IndexedStorage::open(Arc::new(MemoryStorage::new()))
+        .await
+        .unwrap();
+    storage_stores_messages_and_users(&storage).await;
+    assert_eq!(storage.indexed().await, 2);
+}
+
+#[test]
+fn search_query_parses_terms_and_filters() {
+    // prepare
+    // act
+    let query = SearchQuery::parse("Deploy, failed! from:martin since:2024-05-01 page:2").unwrap();
+    // assert
+    assert_eq!(query.terms, vec!["deploy", "failed"]);
+    assert_eq!(query.from.as_deref(), Some("martin"));
+    assert!(query
+        .since_timestamp
+        .as_ref()
+        .unwrap()
+        .starts_with("2024-0"));
+    assert_eq!(query.page, 2);
+    assert_eq!(
+        query.to_string(),
+        "deploy failed from:martin since:2024-05-01"
+    );
+    assert!(SearchQuery::parse("from:martin").is_err());
+    assert!(SearchQuery::parse("deploy page:0").is_err());
+    assert!(SearchQuery::parse("deploy since:yesterday").is_err());
+}
+
+#[test]
+fn search_index_finds_text_and_file_names() {
+    // prepare
+    let records = [
+        ChatRecord::new(AsyncChatMsgDB::Text {
+            from: "martin".into(),
+            room: "#ops".into(),
+            text: "Deploy failed again".into(),
+        }),
+        ChatRecord::new(AsyncChatMsgDB::File {
+            from: "john".into(),
+            room: "#ops".into(),
+            filename: "deploy-report.pdf".into(),
+            sha256: "".into(),
+            transfer: "".into(),
+        }),
+        ChatRecord::new(AsyncChatMsgDB::Direct {
+            from: "martin".into(),
+            to: "john".into(),
+            text: "deploy secret".into(),
+        }),
+        ChatRecord::new(AsyncChatMsgDB::Text {
+            from: "john".into(),
+            room: DEFAULT_ROOM.into(),
+            text: "nothing here".into(),
+        }),
+    ];
+    let mut index = SearchIndex::new();
+    let ids: Vec<String> = records.iter().map(|_| new_message_id()).collect();
+    for (id, record) in ids.iter().zip(&records) {
+        index.add(id, record);
+    }
+    // act
+    let deploy = index.search(&SearchQuery::parse("deploy").unwrap());
+    let report = index.search(&SearchQuery::parse("deploy report").unwrap());
+    let from_martin = index.search(&SearchQuery::parse("deploy from:martin").unwrap());
+    index.remove(&ids[1]);
+    let removed = index.search(&SearchQuery::parse("report").unwrap());
+    // assert
+    let texts: Vec<String> = deploy.iter().map(|(_, msg)| msg.to_string()).collect();
+    assert_eq!(
+        texts,
+        vec![
+            "[#ops] john: file deploy-report.pdf",
+            "[#ops] martin: Deploy failed again"
+        ]
+    );
+    assert_eq!(report.len(), 1);
+    assert_eq!(from_martin.len(), 1);
+    assert!(removed.is_empty());
+    assert_eq!(index.len(), 2);
+}
+
+#[test]
+fn search_results_huge_page_is_empty() {
+    // prepare
+    let hits: Vec<HistoryEntry> = vec![(
+        "2024-07-13T17:21:29.000000Z".into(),
+        AsyncChatMsgDB::Text {
+            from: "martin".into(),
+            room: DEFAULT_ROOM.into(),
+            text: "match".into(),
+        },
+    )];
+    let query = SearchQuery::parse(&format!("match page:{}", usize::MAX)).unwrap();
+    // act
+    let results = search_results(&query, hits);
+    // assert
+    assert!(results.to_string().contains("end of search results"));
+    let AsyncChatMsg::SearchResults {
+        page,
+        total,
+        results,
+        ..
+    } = results
+    else {
+        panic!("search results expected");
+    };
+    assert_eq!((page, total), (usize::MAX, 1));
+    assert!(results.is_empty());
+}
+
+#[test]
+fn search_results_paginated_and_shortened() {
+    // prepare
+    let hits: Vec<HistoryEntry> = (0..25)
+        .map(|i| {
+            let text = format!("match {i} {}", "x".repeat(500));
+            let record = ChatRecord::new(AsyncChatMsgDB::Text {
+                from: "martin".into(),
+                room: DEFAULT_ROOM.into(),
+                text,
+            });
+            (record.timestamp, record.msg)
+        })
+        .collect();
+    let query = SearchQuery::parse("match page:3").unwrap();
+    // act
+    let results = search_results(&query, hits.clone());
+    let first = search_results(&SearchQuery::parse("match").unwrap(), hits);
+    // assert
+    let AsyncChatMsg::SearchResults {
+        query: text,
+        page,
+        total,
+        results,
+    } = results
+    else {
+        panic!("search results expected");
+    };
+    assert_eq!((text.as_str(), page, total), ("match", 3, 25));
+    assert_eq!(results.len(), 5);
+    assert!(results[0].1.to_string().ends_with("..."));
+    assert!(first
+        .to_string()
+        .contains("use .search match page:2 for more"));
+    let AsyncChatMsg::SearchResults { results: first, .. } = first else {
+        panic!("search results expected");
+    };
+    assert_eq!(first.len(), SEARCH_PAGE_SIZE);
+    let reply = AsyncChatMsg::SearchResults {
+        query: text,
+        page: 1,
+        total: 25,
+        results: first,
+    };
+    assert!(
+        serialize_msg(&Envelope::from_server("martin", reply))
+            .unwrap()
+            .len()
+            < 65536
+    );
+}
+
+#[test]
+fn expired_messages_by_age_count_and_room_policy() {
+    // prepare
+    let config: RetentionConfig = serde_json::from_str(
+        r##"{ "default": { "max_age_days": 30 }, "rooms": { "#ops": { "max_messages": 2 } } }"##,
+    )
+    .unwrap();
+    let now = Utc::now();
+    let record = |days: i64, msg: AsyncChatMsgDB| {
+        let record = ChatRecord {
+            timestamp: utc_timestamp(now - chrono::Duration::days(days)),
+            msg,
+        };
+        (new_message_id(), record)
+    };
+    let text = |room: &str, text: &str| AsyncChatMsgDB::Text {
+        from: "martin".into(),
+        room: room.into(),
+        text: text.into(),
+    };
+    let records = vec![
+        record(40, text(DEFAULT_ROOM, "old")),
+        record(100, text("#ops", "ops 1")),
+        record(100, text("#ops", "ops 2")),
+        record(100, text("#ops", "ops 3")),
+        record(
+            40,
+            AsyncChatMsgDB::Direct {
+                from: "martin".into(),
+                to: "john".into(),
+                text: "old secret".into(),
+            },
+        ),
+        record(1, text(DEFAULT_ROOM, "new")),
+        record(1, text("#ops", "ops 4")),
+    ];
+    // act
+    let expired = expired_messages(records, &config, now);
+    // assert
+    let texts: Vec<String> = expired
+        .iter()
+        .map(|(_, record)| record.msg.to_string())
+        .collect();
+    assert_eq!(
+        texts,
+        vec![
+            "[#general] martin: old",
+            "[#ops] martin: ops 1",
+            "[#ops] martin: ops 2",
+            "(private) martin -> john: old secret",
+        ]
+    );
+    assert_eq!(config.prune_interval_secs, 3600);
+}
+
+#[tokio::test]
+async fn prune_removes_messages_and_attachments() {
+    // prepare
+    let folder = "testprunetransfers";
+    ensure_folder(folder).await.unwrap();
+    let storage = MemoryStorage::new();
+    let id = new_transfer_id();
+    write(Path::new(folder).join(&id), b"abcdef").await.unwrap();
+    let start = file_msg(
+        id.clone(),
+        DEFAULT_ROOM.into(),
+        "abc.txt".into(),
+        6,
+        FileKind::File,
+        ABCDEF_SHA256.into(),
+    );
+    start.save_to_db(&storage).await.unwrap();
+    Envelope::create_text(DEFAULT_ROOM.into(), "kept".into())
+        .stamped("martin")
+        .save_to_db(&storage)
+        .await
+        .unwrap();
+    Envelope::create_direct("john".into(), "secret".into())
+        .stamped("martin")
+        .save_to_db(&storage)
+        .await
+        .unwrap();
+    let config: RetentionConfig =
+        serde_json::from_str(r#"{ "default": { "max_messages": 1 } }"#).unwrap();
+    // act
+    let report = prune(&storage, &config, Path::new(folder)).await.unwrap();
+    let again = prune(&storage, &config, Path::new(folder)).await.unwrap();
+    // assert
+    assert_eq!(report.messages, 1);
+    assert_eq!(report.rooms.get(DEFAULT_ROOM), Some(&1));
+    assert_eq!(report.rooms.get(PRIVATE_MESSAGES), None);
+    assert_eq!((report.attachments, report.attachment_bytes), (1, 6));
+    assert!(!Path::new(folder).join(&id).exists());
+    assert_eq!(storage.messages().await.unwrap().len(), 2);
+    assert!(again.is_empty());
+    assert_eq!(again.to_string(), "Nothing to prune");
+    // cleanup
+    _ = tokio::fs::remove_dir_all(folder).await;
+}
+
+/// text with separator and quotes, stored file and private message with html, stored one second after each other
+fn export_fixture(transfer: &str) -> Vec<(String, ChatRecord)> {
+    [
+        AsyncChatMsgDB::Text {
+            from: "martin".into(),
+            room: DEFAULT_ROOM.into(),
+            text: "hello, \"world\"".into(),
+        },
+        AsyncChatMsgDB::File {
+            from: "martin".into(),
+            room: DEFAULT_ROOM.into(),
+            filename: "a.txt".into(),
+            sha256: ABCDEF_SHA256.into(),
+            transfer: transfer.into(),
+        },
+        AsyncChatMsgDB::Direct {
+            from: "martin".into(),
+            to: "john".into(),
+            text: "<b>secret</b>".into(),
+        },
+    ]
+    .into_iter()
+    .enumerate()
+    .map(|(i, msg)| {
+        let timestamp = utc_timestamp(Utc::now() + chrono::Duration::seconds(i as i64));
+        (new_message_id(), ChatRecord { timestamp, msg })
+    })
+    .collect()
+}
+
+/// export records to string
+fn export_to_string(format: ExportFormat, records: &[(String, ChatRecord)]) -> String {
+    let mut out = Vec::new();
+    export(&mut out, format, records, "transfers/", "History <all>").unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn export_formats_contain_messages_and_attachment_links() {
+    // prepare
+    let transfer = new_transfer_id();
+    let records = export_fixture(&transfer);
+    let link = format!("transfers/{transfer}");
+    // act
+    let jsonl = export_to_string(ExportFormat::Jsonl, &records);
+    let csv = export_to_string(ExportFormat::Csv, &records);
+    let html = export_to_string(ExportFormat::Html, &records);
+    let irc = export_to_string(ExportFormat::Irc, &records);
+    // assert
+    let parsed: Vec<ExportRecord> = jsonl
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(parsed.len(), 3);
+    assert_eq!(parsed[1].attachment.as_deref(), Some(link.as_str()));
+    assert_eq!(parsed[2].to.as_deref(), Some("john"));
+    assert!(parsed[2].room.is_none());
+    let csv: Vec<&str> = csv.lines().collect();
+    assert_eq!(csv.len(), 4);
+    assert!(csv[1].ends_with(",martin,,#general,\"hello, \"\"world\"\"\",,"));
+    assert!(csv[2].ends_with(&format!(",a.txt,{ABCDEF_SHA256},{link}")));
+    assert!(html.contains(&format!("<a href=\"{link}\">a.txt</a>")));
+    assert!(html.contains("&lt;b&gt;secret&lt;/b&gt;"));
+    assert!(html.contains("<title>History &lt;all&gt;</title>"));
+    assert!(!html.contains("<b>"));
+    let irc: Vec<&str> = irc.lines().collect();
+    assert!(irc[0].ends_with("] #general <martin> hello, \"world\""));
+    assert!(irc[1].ends_with(&format!("] #general * martin sends file a.txt ({link})")));
+    assert!(irc[2].ends_with("] john <martin> <b>secret</b>"));
+    assert!("pdf".parse::<ExportFormat>().is_err());
+}
+
+#[test]
+fn export_filter_selects_room_and_time_range() {
+    // prepare
+    let records = export_fixture(&new_transfer_id());
+    let room = ExportFilter {
+        room: Some(DEFAULT_ROOM.into()),
+        ..ExportFilter::default()
+    };
+    let range = ExportFilter {
+        since: Some(records[1].1.timestamp.clone()),
+        until: Some(records[2].1.timestamp.clone()),
+        ..ExportFilter::default()
+    };
+    // act
+    let in_room = records.iter().filter(|(_, r)| room.matches(r)).count();
+    let in_range: Vec<&String> = records
+        .iter()
+        .filter(|(_, r)| range.matches(r))
+        .map(|(id, _)| id)
+        .collect();
+    // assert
+    assert_eq!(in_room, 2);
+    assert_eq!(in_range, vec![&records[1].0]);
+}
+
+#[test]
+fn parse_irc_log_reads_messages_files_and_private() {
+    // prepare
+    let log = "--- Log opened
+[2024-05-01 10:00:00] #ops <@martin> deploy done
+[2024-05-01 10:00:05] <john> thanks
+[2024-05-01 10:01:00] #ops * martin sends file report.pdf (transfers/abc)
+[2024-05-01 10:02:00] john <martin> secret
+[10:03] <eva> time only
+*** john has quit";
+    // act
+    let parsed = parse_irc(log, "#general", None);
+    let dated = parse_irc(log, "#general", "2024-05-01".parse().ok());
+    // assert
+    let texts: Vec<String> = parsed
+        .messages
+        .iter()
+        .map(|(_, msg)| msg.to_string())
+        .collect();
+    assert_eq!(
+        texts,
+        vec![
+            "[#ops] martin: deploy done",
+            "[#general] john: thanks",
+            "[#ops] martin: file report.pdf",
+            "(private) martin -> john: secret",
+        ]
+    );
+    let lines: Vec<usize> = parsed.skipped.iter().map(|(line, _)| *line).collect();
+    assert_eq!(lines, vec![1, 6, 7]);
+    assert_eq!(dated.messages.len(), 5);
+    assert_eq!(
+        local_time(&utc_timestamp(dated.messages[4].0)),
+        "2024-05-01 10:03:00"
+    );
+}
+
+#[tokio::test]
+async fn import_keeps_time_and_skips_duplicates() {
+    // prepare
+    let records = export_fixture(&new_transfer_id());
+    let jsonl = export_to_string(ExportFormat::Jsonl, &records);
+    let irc = export_to_string(ExportFormat::Irc, &records);
+    let storage = MemoryStorage::new();
+    // act
+    let dry = import_messages(parse_jsonl(&jsonl), &storage, true)
+        .await
+        .unwrap();
+    let stored_after_dry_run = storage.messages().await.unwrap().len();
+    let imported = import_messages(parse_jsonl(&jsonl), &storage, false)
+        .await
+        .unwrap();
+    let again = import_messages(parse_jsonl(&jsonl), &storage, false)
+        .await
+        .unwrap();
+    let from_log = import_messages(parse_irc(&irc, DEFAULT_ROOM, None), &storage, false)
+        .await
+        .unwrap();
+    // assert
+    assert_eq!(dry.imported.len(), 3);
+    assert_eq!(stored_after_dry_run, 0);
+    assert_eq!(imported.imported.len(), 3);
+    assert_eq!(
+        imported.to_string(),
+        "Imported 3 messages, 0 duplicates and 0 other lines skipped"
+    );
+    assert_eq!((again.imported.len(), again.duplicates), (0, 3));
+    assert_eq!((from_log.imported.len(), from_log.duplicates), (0, 3));
+    let stored = storage.messages().await.unwrap();
+    let original: Vec<(&String, String)> = records
+        .iter()
+        .map(|(_, record)| (&record.timestamp, record.msg.get_from().to_string()))
+        .collect();
+    let stored: Vec<(&String, String)> = stored
+        .iter()
+        .map(|(_, record)| (&record.timestamp, record.msg.get_from().to_string()))
+        .collect();
+    assert_eq!(stored, original);
+}
+
+#[tokio::test]
+async fn nanodb_storage_writes_files_atomically() {
+    // prepare
+    let folder = Path::new("testatomicwrite");
+    ensure_folder(&folder.to_string_lossy()).await.unwrap();
+    let chat = folder.join(CHAT_DB_FILE);
+    let storage = NanoDbStorage::open(
+        &chat.to_string_lossy(),
+        &folder.join(USERS_DB_FILE).to_string_lossy(),
+        &folder.join(SEEN_DB_FILE).to_string_lossy(),
+    )
+    .await
+    .unwrap();
+    // act
+    Envelope::create_text(DEFAULT_ROOM.into(), "hello".into())
+        .stamped("martin")
+        .save_to_db(&storage)
+        .await
+        .unwrap();
+    storage.set_password("martin", "hash").await.unwrap();
+    write_file_atomic(&folder.join("other.txt"), b"old")
+        .await
+        .unwrap();
+    write_file_atomic(&folder.join("other.txt"), b"new")
+        .await
+        .unwrap();
+    // assert
+    let mut names = Vec::new();
+    let mut entries = tokio::fs::read_dir(folder).await.unwrap();
+    while let Some(entry) = entries.next_entry().await.unwrap() {
+        names.push(entry.file_name().to_string_lossy().to_string());
+    }
+    names.sort();
+    assert_eq!(names, vec![CHAT_DB_FILE, "other.txt", USERS_DB_FILE]);
+    let reopened: serde_json::Value =
+        serde_json::from_str(&tokio::fs::read_to_string(&chat).await.unwrap()).unwrap();
+    assert_eq!(reopened.as_object().unwrap().len(), 2);
+    assert_eq!(reopened[SCHEMA_KEY], SCHEMA_VERSION);
+    assert_eq!(
+        tokio::fs::read(folder.join("other.txt")).await.unwrap(),
+        b"new"
+    );
+    // cleanup
+    _ = tokio::fs::remove_dir_all(folder).await;
+}
+
+/// file message of martin to the room as stamped by the server
+fn file_msg(
+    id: String,
+    room: String,
+    filename: String,
+    size: u64,
+    kind: FileKind,
+    sha256: String,
+) -> Envelope {
+    let body = AsyncChatMsg::FileStart {
+        transfer: id,
+        filename,
+        size,
+        kind,
+        sha256,
+    };
+    Envelope::new(Destination::Room(room), body).stamped("martin")
+}
+
+/// store file message with its attachment in the transfers folder, attachment is written only if `stored` is set
+async fn store_attachment(storage: &dyn Storage, transfers: &Path, stored: bool) -> String {
+    let id = new_transfer_id();
+    if stored {
+        write(transfers.join(&id), b"abcdef").await.unwrap();
+    }
+    let start = file_msg(
+        id.clone(),
+        DEFAULT_ROOM.into(),
+        "abc.txt".into(),
+        6,
+        FileKind::File,
+        ABCDEF_SHA256.into(),
+    );
+    start.save_to_db(storage).await.unwrap();
+    id
+}
+
+#[tokio::test]
+async fn snapshot_restores_sqlite_storage_and_attachments() {
+    // prepare
+    let folder = Path::new("testsnapshotrestore");
+    let transfers = folder.join(TRANSFERS_FOLDER);
+    let snapshots = folder.join("snapshots");
+    ensure_folder(&transfers.to_string_lossy()).await.unwrap();
+    let path = folder.join("chat.sqlite").to_string_lossy().to_string();
+    let config = StorageConfig::Sqlite(path.clone());
+    let storage = SqliteStorage::open(&path).await.unwrap();
+    let id = store_attachment(&storage, &transfers, true).await;
+    Envelope::create_text(DEFAULT_ROOM.into(), "before".into())
+        .stamped("martin")
+        .save_to_db(&storage)
+        .await
+        .unwrap();
+    write(transfers.join("upload.part"), b"abc").await.unwrap();
+    // act
+    let report = take_snapshot(&storage, &transfers, &snapshots)
+        .await
+        .unwrap();
+    Envelope::create_text(DEFAULT_ROOM.into(), "after".into())
+        .stamped("martin")
+        .save_to_db(&storage)
+        .await
+        .unwrap();
+    drop(storage);
+    let restored = restore_snapshot(&report.dir, &config, &transfers, &snapshots)
+        .await
+        .unwrap();
+    let reopened = SqliteStorage::open(&path).await.unwrap();
+    // assert
+    assert_eq!(report.manifest.databases.len(), 1);
+    assert_eq!(report.manifest.attachments.len(), 1);
+    assert_eq!(report.manifest.attachments[0].sha256, ABCDEF_SHA256);
+    assert_eq!(restored.check.messages, 2);
+    let texts: Vec<String> = reopened
+        .messages()
+        .await
+        .unwrap()
+        .iter()
+        .map(|(_, record)| record.msg.to_string())
+        .collect();
+    assert_eq!(texts.len(), 2);
+    assert_eq!(texts[1], "[#general] martin: before");
+    assert!(transfers.join(&id).exists());
+    assert!(!transfers.join("upload.part").exists());
+    assert!(restored.backup.join("chat.sqlite").exists());
+    assert!(restored
+        .backup
+        .join(TRANSFERS_FOLDER)
+        .join("upload.part")
+        .exists());
+    // cleanup
+    drop(reopened);
+    _ = tokio::fs::remove_dir_all(folder).await;
+}
+
+#[tokio::test]
+async fn check_snapshot_rejects_changed_or_incomplete_snapshot() {
+    // prepare
+    let folder = Path::new("testsnapshotcheck");
+    let transfers = folder.join(TRANSFERS_FOLDER);
+    let snapshots = folder.join("snapshots");
+    ensure_folder(&transfers.to_string_lossy()).await.unwrap();
+    let storage = NanoDbStorage::open(
+        &folder.join(CHAT_DB_FILE).to_string_lossy(),
+        &folder.join(USERS_DB_FILE).to_string_lossy(),
+        &folder.join(SEEN_DB_FILE).to_string_lossy(),
+    )
+    .await
+    .unwrap();
+    store_attachment(&storage, &transfers, true).await;
+    let valid = take_snapshot(&storage, &transfers, &snapshots)
+        .await
+        .unwrap();
+    store_attachment(&storage, &transfers, false).await;
+    let incomplete = take_snapshot(&storage, &transfers, &snapshots)
+        .await
+        .unwrap();
+    // act
+    let checked = check_snapshot(&valid.dir, &StorageConfig::NanoDB)
+        .await
+        .unwrap();
+    let other_backend = check_snapshot(&valid.dir, &StorageConfig::Sqlite("chat.sqlite".into()))
+        .await
+        .unwrap_err();
+    let missing = check_snapshot(&incomplete.dir, &StorageConfig::NanoDB)
+        .await
+        .unwrap_err();
+    write(valid.dir.join(CHAT_DB_FILE), "{}").await.unwrap();
+    let changed = check_snapshot(&valid.dir, &StorageConfig::NanoDB)
+        .await
+        .unwrap_err();
+    remove_file(valid.dir.join(MANIFEST_FILE)).await.unwrap();
+    let unfinished = check_snapshot(&valid.dir, &StorageConfig::NanoDB)
+        .await
+        .unwrap_err();
+    // assert
+    assert_eq!(checked.messages, 1);
+    assert_eq!(checked.manifest.databases.len(), 3);
+    assert!(other_backend
+        .to_string()
+        .contains("doesn't contain database file chat.sqlite"));
+    assert!(missing.to_string().contains("missing 1 attachments"));
+    assert!(changed.to_string().contains("changed or damaged"));
+    assert!(unfinished
+        .to_string()
+        .contains("is not a complete snapshot"));
+    // cleanup
+    _ = tokio::fs::remove_dir_all(folder).await;
+}